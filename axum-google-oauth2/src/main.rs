@@ -1,324 +1,54 @@
-//! Example OAuth (Discord) implementation.
-//!
-//! 1) Create a new application at <https://discord.com/developers/applications>
-//! 2) Visit the OAuth2 tab to get your CLIENT_ID and CLIENT_SECRET
-//! 3) Add a new redirect URI (for this example: `http://127.0.0.1:3000/auth/authorized`)
-//! 4) Run with the following (replacing values appropriately):
-//! ```not_rust
-//! CLIENT_ID=REPLACE_ME CLIENT_SECRET=REPLACE_ME cargo run -p example-oauth
-//! ```
+//! Google login over plain HTTP, meant to run behind a TLS terminating proxy such as
+//! `ngrok http 3000`. The auth flow comes from `axum-oauth2-auth`; token requests go
+//! through the `oauth2` crate's client.
 
 use anyhow::{Context, Result};
-use async_session::{MemoryStore, Session, SessionStore};
-use axum::{
-    async_trait,
-    extract::{FromRef, FromRequestParts, Query, State},
-    http::{header::SET_COOKIE, HeaderMap},
-    response::{IntoResponse, Redirect, Response},
-    routing::get,
-    RequestPartsExt, Router,
-};
-use axum_extra::{headers, typed_header::TypedHeaderRejectionReason, TypedHeader};
-use http::{header, request::Parts, StatusCode};
-use oauth2::{
-    basic::BasicClient, reqwest::async_http_client, AuthUrl, AuthorizationCode, ClientId,
-    ClientSecret, CsrfToken, RedirectUrl, Scope, TokenResponse, TokenUrl,
-};
-use serde::{Deserialize, Serialize};
-use std::env;
+use axum_oauth2_auth::{app_state_init, backend::Backend, create_router, domains, session_store};
+use std::net::SocketAddr;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-static COOKIE_NAME: &str = "SESSION";
-
 #[tokio::main]
 async fn main() {
     tracing_subscriber::registry()
         .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "example_oauth=debug".into()),
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+                format!(
+                    "{}=debug,axum_oauth2_auth=debug,audit=info",
+                    env!("CARGO_CRATE_NAME")
+                )
+                .into()
+            }),
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    // `MemoryStore` is just used as an example. Don't use this in production.
-    let store = MemoryStore::new();
-    let oauth_client = oauth_client().unwrap();
-    let app_state = AppState {
-        store,
-        oauth_client,
-    };
+    if let Err(e) = run().await {
+        tracing::error!("{:#}", e);
+        std::process::exit(1);
+    }
+}
 
-    let app = Router::new()
-        .route("/", get(index))
-        .route("/auth/google", get(google_auth))
-        .route("/auth/authorized", get(login_authorized))
-        .route("/protected", get(protected))
-        .route("/logout", get(logout))
-        .with_state(app_state);
+async fn run() -> Result<()> {
+    let app_state = app_state_init(Backend::oauth2).await?;
+    session_store::spawn_cleanup_task(app_state.store.clone());
+    domains::spawn_reload_task(app_state.domains.clone());
+    let app = create_router(app_state);
 
     let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
         .await
-        .context("failed to bind TcpListener")
-        .unwrap();
+        .context("failed to bind TcpListener")?;
 
     tracing::debug!(
         "listening on {}",
         listener
             .local_addr()
-            .context("failed to return local address")
-            .unwrap()
+            .context("failed to return local address")?
     );
 
-    axum::serve(listener, app).await.unwrap();
-}
-
-#[derive(Clone)]
-struct AppState {
-    store: MemoryStore,
-    oauth_client: BasicClient,
-}
-
-impl FromRef<AppState> for MemoryStore {
-    fn from_ref(state: &AppState) -> Self {
-        state.store.clone()
-    }
-}
-
-impl FromRef<AppState> for BasicClient {
-    fn from_ref(state: &AppState) -> Self {
-        state.oauth_client.clone()
-    }
-}
-
-fn oauth_client() -> Result<BasicClient, AppError> {
-    let client_id = env::var("CLIENT_ID").context("Missing CLIENT_ID!")?;
-    let client_secret = env::var("CLIENT_SECRET").context("Missing CLIENT_SECRET!")?;
-    let origin = env::var("ORIGIN").context("Missing ORIGIN!")?;
-    let redirect_url = format!("{}/auth/authorized", origin);
-
-    let auth_url = env::var("AUTH_URL").unwrap_or_else(|_| {
-        "https://accounts.google.com/o/oauth2/v2/auth".to_string()
-    });
-
-    let token_url = env::var("TOKEN_URL")
-        .unwrap_or_else(|_| "https://oauth2.googleapis.com/token".to_string());
-
-    Ok(BasicClient::new(
-        ClientId::new(client_id),
-        Some(ClientSecret::new(client_secret)),
-        AuthUrl::new(auth_url).context("failed to create new authorization server URL")?,
-        Some(TokenUrl::new(token_url).context("failed to create new token endpoint URL")?),
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
     )
-    .set_redirect_uri(
-        RedirectUrl::new(redirect_url).context("failed to create new redirection URL")?,
-    ))
-}
-
-// The user data we'll get back from Discord.
-// https://discord.com/developers/docs/resources/user#user-object-user-structure
-#[derive(Debug, Serialize, Deserialize)]
-struct User {
-    family_name: String,
-    name: String,
-    picture: String,
-    email: String,
-    given_name: String,
-    id: String,
-    hd: String,
-    verified_email: bool,
-}
-
-// Session is optional
-async fn index(user: Option<User>) -> impl IntoResponse {
-    match user {
-        Some(u) => format!(
-            "Hey {}! You're logged in!\nYou may now access `/protected`.\nLog out with `/logout`.",
-            u.name
-        ),
-        None => "You're not logged in.\nVisit `/auth/google` to do so.".to_string(),
-    }
-}
-
-async fn google_auth(State(client): State<BasicClient>) -> impl IntoResponse {
-    let (auth_url, csrf_token) = client
-        .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("openid".to_string()))
-        .add_scope(Scope::new("email".to_string()))
-        .add_scope(Scope::new("profile".to_string()))
-        .add_extra_param("prompt", "consent")
-        .add_extra_param("access_type", "online")
-        .url();
-
-    println!("Auth URL query: {:#?}", auth_url.query());
-    println!("CSRF Token: {:#?}", csrf_token.secret());
-
-    // Redirect to Discord's oauth service
-    Redirect::to(auth_url.as_ref())
-}
-
-// Valid user session required. If there is none, redirect to the auth page
-async fn protected(user: User) -> impl IntoResponse {
-    format!("Welcome to the protected area :)\nHere's your info:\n{user:?}")
-}
-
-async fn logout(
-    State(store): State<MemoryStore>,
-    TypedHeader(cookies): TypedHeader<headers::Cookie>,
-) -> Result<impl IntoResponse, AppError> {
-    let cookie = cookies
-        .get(COOKIE_NAME)
-        .context("unexpected error getting cookie name")?;
-
-    let session = match store
-        .load_session(cookie.to_string())
-        .await
-        .context("failed to load session")?
-    {
-        Some(s) => s,
-        // No session active, just redirect
-        None => return Ok(Redirect::to("/")),
-    };
-
-    store
-        .destroy_session(session)
-        .await
-        .context("failed to destroy session")?;
-
-    Ok(Redirect::to("/"))
-}
-
-#[derive(Debug, Deserialize)]
-#[allow(dead_code)]
-struct AuthRequest {
-    code: String,
-    state: String,
-}
-
-async fn login_authorized(
-    Query(query): Query<AuthRequest>,
-    State(store): State<MemoryStore>,
-    State(oauth_client): State<BasicClient>,
-) -> Result<impl IntoResponse, AppError> {
-    println!("Query: {:#?}", query);
-    println!("code: {:#?}", query.code);
-    // Get an auth token
-    let token = oauth_client
-        .exchange_code(AuthorizationCode::new(query.code.clone()))
-        .request_async(async_http_client)
-        .await
-        .context("failed in sending request request to authorization server")?;
-
-    println!("Token: {:#?}", token);
-    println!("access_token: {:#?}", token.access_token().secret());
-
-        // Fetch user data from discord
-    let client = reqwest::Client::new();
-    let user_data: User = client
-        .get("https://www.googleapis.com/userinfo/v2/me")
-        .bearer_auth(token.access_token().secret())
-        .send()
-        .await
-        .context("failed in sending request to target Url")?
-        .json::<User>()
-        .await
-        .context("failed to deserialize response as JSON")?;
-
-    // Create a new session filled with user data
-    let mut session = Session::new();
-    session
-        .insert("user", &user_data)
-        .context("failed in inserting serialized value into session")?;
-
-    println!("User data: {:#?}", user_data);
-    println!("Session: {:#?}", session);
-
-        // Store session and get corresponding cookie
-    let cookie = store
-        .store_session(session)
-        .await
-        .context("failed to store session")?
-        .context("unexpected error retrieving cookie value")?;
-
-    // Build the cookie
-    let cookie = format!("{COOKIE_NAME}={cookie}; SameSite=Lax; Path=/");
-
-    println!("Cookie: {:#?}", cookie);
-
-    // Set cookie
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        SET_COOKIE,
-        cookie.parse().context("failed to parse cookie")?,
-    );
-
-    Ok((headers, Redirect::to("/")))
-}
-
-struct AuthRedirect;
-
-impl IntoResponse for AuthRedirect {
-    fn into_response(self) -> Response {
-        Redirect::temporary("/auth/google").into_response()
-    }
-}
-
-#[async_trait]
-impl<S> FromRequestParts<S> for User
-where
-    MemoryStore: FromRef<S>,
-    S: Send + Sync,
-{
-    // If anything goes wrong or no session is found, redirect to the auth page
-    type Rejection = AuthRedirect;
-
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let store = MemoryStore::from_ref(state);
-
-        let cookies = parts
-            .extract::<TypedHeader<headers::Cookie>>()
-            .await
-            .map_err(|e| match *e.name() {
-                header::COOKIE => match e.reason() {
-                    TypedHeaderRejectionReason::Missing => AuthRedirect,
-                    _ => panic!("unexpected error getting Cookie header(s): {e}"),
-                },
-                _ => panic!("unexpected error getting cookies: {e}"),
-            })?;
-        let session_cookie = cookies.get(COOKIE_NAME).ok_or(AuthRedirect)?;
-
-        let session = store
-            .load_session(session_cookie.to_string())
-            .await
-            .unwrap()
-            .ok_or(AuthRedirect)?;
-
-        let user = session.get::<User>("user").ok_or(AuthRedirect)?;
-
-        Ok(user)
-    }
-}
-
-// Use anyhow, define error and enable '?'
-// For a simplified example of using anyhow in axum check /examples/anyhow-error-response
-#[derive(Debug)]
-struct AppError(anyhow::Error);
-
-// Tell axum how to convert `AppError` into a response.
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        tracing::error!("Application error: {:#}", self.0);
-
-        (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong").into_response()
-    }
-}
-
-// This enables using `?` on functions that return `Result<_, anyhow::Error>` to turn them into
-// `Result<_, AppError>`. That way you don't need to do that manually.
-impl<E> From<E> for AppError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
-    }
+    .await
+    .context("server error")
 }
@@ -0,0 +1,224 @@
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+
+use crate::users::UserStore;
+
+// `RUST_LOG=audit=info` selects just these events.
+pub const AUDIT_TARGET: &str = "audit";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthEventKind {
+    LoginSuccess,
+    LoginFailure,
+    CsrfFailure,
+    Logout,
+    SessionRevoked,
+}
+
+impl AuthEventKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            AuthEventKind::LoginSuccess => "login_success",
+            AuthEventKind::LoginFailure => "login_failure",
+            AuthEventKind::CsrfFailure => "csrf_failure",
+            AuthEventKind::Logout => "logout",
+            AuthEventKind::SessionRevoked => "session_revoked",
+        }
+    }
+}
+
+// One audit record. Nothing secret goes in here: session ids only as `redact`ed digests.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct AuthEvent {
+    pub kind: String,
+    pub user_id: Option<i64>,
+    pub provider: Option<String>,
+    pub session: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub reason: Option<String>,
+    pub created_at: i64,
+}
+
+impl AuthEvent {
+    pub fn new(kind: AuthEventKind) -> Self {
+        Self {
+            kind: kind.as_str().to_string(),
+            user_id: None,
+            provider: None,
+            session: None,
+            ip: None,
+            user_agent: None,
+            reason: None,
+            created_at: Utc::now().timestamp(),
+        }
+    }
+
+    pub fn user_id(mut self, user_id: i64) -> Self {
+        self.user_id = Some(user_id);
+        self
+    }
+
+    pub fn provider(mut self, provider: &str) -> Self {
+        self.provider = Some(provider.to_string());
+        self
+    }
+
+    // The store's session id, which is enough to look the session up; only a digest is kept.
+    pub fn session(mut self, session_id: &str) -> Self {
+        self.session = Some(redact(session_id));
+        self
+    }
+
+    pub fn client(mut self, ip: Option<String>, user_agent: Option<String>) -> Self {
+        self.ip = ip;
+        self.user_agent = user_agent;
+        self
+    }
+
+    pub fn reason(mut self, reason: impl ToString) -> Self {
+        self.reason = Some(reason.to_string());
+        self
+    }
+}
+
+// A short digest that tells values apart without revealing them.
+pub fn redact(secret: &str) -> String {
+    let digest = Sha256::digest(secret.as_bytes());
+    format!(
+        "sha256:{}",
+        digest[..6]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    )
+}
+
+// Writes events to `tracing` under `AUDIT_TARGET` and, with AUDIT_PERSIST=true, to the
+// `auth_events` table in the user database as well.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    store: Option<UserStore>,
+}
+
+impl AuditLog {
+    pub fn new(store: Option<UserStore>) -> Self {
+        Self { store }
+    }
+
+    pub fn from_env(users: &UserStore) -> Self {
+        let persist = env::var("AUDIT_PERSIST").is_ok_and(|v| v == "true" || v == "1");
+        Self::new(persist.then(|| users.clone()))
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.store.is_some()
+    }
+
+    // Never fails the request that caused the event; a lost record is logged instead.
+    pub async fn record(&self, event: AuthEvent) {
+        tracing::info!(
+            target: AUDIT_TARGET,
+            kind = %event.kind,
+            user_id = ?event.user_id,
+            provider = ?event.provider,
+            session = ?event.session,
+            ip = ?event.ip,
+            user_agent = ?event.user_agent,
+            reason = ?event.reason,
+            "{}",
+            event.kind
+        );
+        if let Some(store) = &self.store {
+            if let Err(e) = store.insert_auth_event(&event).await {
+                tracing::error!("Failed to persist audit event: {:#}", e);
+            }
+        }
+    }
+
+    // Most recent first. Empty when events aren't persisted.
+    pub async fn recent(&self, filter: &AuthEventFilter) -> Result<Vec<AuthEvent>> {
+        match &self.store {
+            Some(store) => store.auth_events(filter).await,
+            None => Ok(vec![]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthEventFilter {
+    pub kind: Option<AuthEventKind>,
+    pub user_id: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+static DEFAULT_EVENT_LIMIT: i64 = 100;
+static MAX_EVENT_LIMIT: i64 = 1000;
+
+impl UserStore {
+    pub(crate) async fn migrate_audit(&self) -> sqlx::Result<()> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS auth_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                kind TEXT NOT NULL,
+                user_id INTEGER NULL,
+                provider TEXT NULL,
+                session TEXT NULL,
+                ip TEXT NULL,
+                user_agent TEXT NULL,
+                reason TEXT NULL,
+                created_at INTEGER NOT NULL
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS auth_events_created_at ON auth_events (created_at)",
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn insert_auth_event(&self, event: &AuthEvent) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO auth_events
+             (kind, user_id, provider, session, ip, user_agent, reason, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&event.kind)
+        .bind(event.user_id)
+        .bind(&event.provider)
+        .bind(&event.session)
+        .bind(&event.ip)
+        .bind(&event.user_agent)
+        .bind(&event.reason)
+        .bind(event.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn auth_events(&self, filter: &AuthEventFilter) -> Result<Vec<AuthEvent>> {
+        let limit = filter
+            .limit
+            .unwrap_or(DEFAULT_EVENT_LIMIT)
+            .clamp(1, MAX_EVENT_LIMIT);
+        let events = sqlx::query_as(
+            "SELECT kind, user_id, provider, session, ip, user_agent, reason, created_at
+             FROM auth_events
+             WHERE (?1 IS NULL OR kind = ?1) AND (?2 IS NULL OR user_id = ?2)
+             ORDER BY id DESC LIMIT ?3",
+        )
+        .bind(filter.kind.map(|kind| kind.as_str().to_string()))
+        .bind(filter.user_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(events)
+    }
+}
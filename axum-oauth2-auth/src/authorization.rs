@@ -0,0 +1,218 @@
+use anyhow::{Context, Result};
+use url::Url;
+
+use crate::{AccessType, OAuth2Params, Prompt, ResponseMode, ResponseType};
+
+// Set by the builder itself; extra provider parameters can't override them.
+static RESERVED_PARAMS: [&str; 13] = [
+    "client_id",
+    "redirect_uri",
+    "response_type",
+    "scope",
+    "state",
+    "nonce",
+    "code_challenge",
+    "code_challenge_method",
+    "response_mode",
+    "prompt",
+    "access_type",
+    "login_hint",
+    "hd",
+];
+
+// The URL that sends the browser to the provider (RFC 6749 4.1.1, OIDC Core 3.1.2.1).
+// Every value is form-urlencoded and unset optional parameters are left out.
+#[derive(Debug, Clone)]
+pub struct AuthorizationRequest {
+    auth_url: String,
+    client_id: String,
+    redirect_uri: String,
+    response_type: ResponseType,
+    scope: Option<String>,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    response_mode: Option<ResponseMode>,
+    prompt: Option<Prompt>,
+    access_type: Option<AccessType>,
+    login_hint: Option<String>,
+    hd: Option<String>,
+    extra_params: Vec<(String, String)>,
+}
+
+impl AuthorizationRequest {
+    pub fn new(auth_url: &str, client_id: &str, redirect_uri: &str) -> Self {
+        Self {
+            auth_url: auth_url.to_string(),
+            client_id: client_id.to_string(),
+            redirect_uri: redirect_uri.to_string(),
+            response_type: ResponseType::Code,
+            scope: None,
+            state: None,
+            nonce: None,
+            code_challenge: None,
+            response_mode: None,
+            prompt: None,
+            access_type: None,
+            login_hint: None,
+            hd: None,
+            extra_params: vec![],
+        }
+    }
+
+    // Everything the provider and `provider_auth` put in `params`; PKCE comes on top.
+    pub fn from_params(params: &OAuth2Params) -> Result<Self> {
+        let mut request = Self::new(&params.auth_url, &params.client_id, &params.redirect_uri)
+            .response_type(ResponseType::parse(&params.response_type)?)
+            .scope(&params.scope);
+        request.state = params.state.clone();
+        request.nonce = params.nonce.clone();
+        request.response_mode = params.response_mode.clone();
+        request.prompt = params.prompt.clone();
+        request.access_type = params.access_type.clone();
+        request.login_hint = params.login_hint.clone();
+        request.hd = params.hd.clone();
+        request.extra_params = params.extra_params.clone();
+        Ok(request)
+    }
+
+    pub fn response_type(mut self, response_type: ResponseType) -> Self {
+        self.response_type = response_type;
+        self
+    }
+
+    pub fn scope(mut self, scope: &str) -> Self {
+        self.scope = Some(scope.to_string());
+        self
+    }
+
+    pub fn state(mut self, state: &str) -> Self {
+        self.state = Some(state.to_string());
+        self
+    }
+
+    pub fn nonce(mut self, nonce: &str) -> Self {
+        self.nonce = Some(nonce.to_string());
+        self
+    }
+
+    // An S256 PKCE challenge (RFC 7636); plain challenges aren't supported.
+    pub fn pkce_s256(mut self, code_challenge: &str) -> Self {
+        self.code_challenge = Some(code_challenge.to_string());
+        self
+    }
+
+    pub fn response_mode(mut self, response_mode: ResponseMode) -> Self {
+        self.response_mode = Some(response_mode);
+        self
+    }
+
+    pub fn prompt(mut self, prompt: Prompt) -> Self {
+        self.prompt = Some(prompt);
+        self
+    }
+
+    pub fn access_type(mut self, access_type: AccessType) -> Self {
+        self.access_type = Some(access_type);
+        self
+    }
+
+    pub fn login_hint(mut self, login_hint: &str) -> Self {
+        self.login_hint = Some(login_hint.to_string());
+        self
+    }
+
+    // Google's hosted domain hint.
+    pub fn hd(mut self, hd: &str) -> Self {
+        self.hd = Some(hd.to_string());
+        self
+    }
+
+    // A provider specific parameter, sent as is after the standard ones.
+    pub fn extra_param(mut self, name: &str, value: &str) -> Self {
+        self.extra_params
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn build(&self) -> Result<Url> {
+        let mut url = Url::parse(&self.auth_url)
+            .with_context(|| format!("invalid authorization endpoint {}", self.auth_url))?;
+        if !matches!(url.scheme(), "https" | "http") || url.fragment().is_some() {
+            return Err(anyhow::anyhow!(
+                "invalid authorization endpoint {}",
+                self.auth_url
+            ));
+        }
+        if self.client_id.is_empty() {
+            return Err(anyhow::anyhow!("client_id is required"));
+        }
+        Url::parse(&self.redirect_uri)
+            .with_context(|| format!("invalid redirect_uri {}", self.redirect_uri))?;
+        // Tokens must not be put in the query string (OAuth 2.0 Multiple Response Types).
+        if matches!(self.response_mode, Some(ResponseMode::Query))
+            && (self.response_type.has_token() || self.response_type.has_id_token())
+        {
+            return Err(anyhow::anyhow!(
+                "response_type {} can't use response_mode query",
+                self.response_type.as_str()
+            ));
+        }
+        // OIDC Core 3.2.2.1: required whenever an ID token comes back from this request.
+        if self.response_type.has_id_token() && self.nonce.is_none() {
+            return Err(anyhow::anyhow!(
+                "response_type {} requires a nonce",
+                self.response_type.as_str()
+            ));
+        }
+        if let Some((name, _)) = self
+            .extra_params
+            .iter()
+            .find(|(name, _)| RESERVED_PARAMS.contains(&name.as_str()))
+        {
+            return Err(anyhow::anyhow!(
+                "{} can't be set as an extra parameter",
+                name
+            ));
+        }
+
+        // Appended to whatever query the endpoint already has.
+        {
+            let mut query = url.query_pairs_mut();
+            query
+                .append_pair("client_id", &self.client_id)
+                .append_pair("redirect_uri", &self.redirect_uri)
+                .append_pair("response_type", self.response_type.as_str());
+            let optional = [
+                ("scope", self.scope.as_deref()),
+                ("state", self.state.as_deref()),
+                ("nonce", self.nonce.as_deref()),
+                ("code_challenge", self.code_challenge.as_deref()),
+                (
+                    "code_challenge_method",
+                    self.code_challenge.as_ref().map(|_| "S256"),
+                ),
+                (
+                    "response_mode",
+                    self.response_mode.as_ref().map(|mode| mode.as_str()),
+                ),
+                ("prompt", self.prompt.as_ref().map(|prompt| prompt.as_str())),
+                (
+                    "access_type",
+                    self.access_type.as_ref().map(|access| access.as_str()),
+                ),
+                ("login_hint", self.login_hint.as_deref()),
+                ("hd", self.hd.as_deref()),
+            ];
+            for (name, value) in optional {
+                if let Some(value) = value {
+                    query.append_pair(name, value);
+                }
+            }
+            for (name, value) in &self.extra_params {
+                query.append_pair(name, value);
+            }
+        }
+        Ok(url)
+    }
+}
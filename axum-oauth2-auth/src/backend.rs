@@ -0,0 +1,365 @@
+use anyhow::{Context, Result};
+use axum::async_trait;
+use oauth2::{
+    basic::{
+        BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
+        BasicTokenType,
+    },
+    AccessToken, AuthType, AuthUrl, AuthorizationCode, ClientId, ClientSecret, ExtraTokenFields,
+    HttpRequest, HttpResponse, PkceCodeVerifier, RedirectUrl, RefreshToken, RevocationUrl,
+    StandardRevocableToken, StandardTokenResponse, TokenUrl,
+};
+use serde::{Deserialize, Serialize};
+use std::{env, io, sync::Arc, time::Duration};
+
+use crate::OAuth2Params;
+
+static CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+static READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The client for every request to the providers: discovery, JWKS, and the token and
+// revocation endpoints. Following redirects would open it up to SSRF, as the `oauth2`
+// crate notes.
+pub fn http_client() -> Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .read_timeout(READ_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .context("failed to build HTTP client")
+}
+
+// The token endpoint response; `id_token` is there for OpenID Connect providers.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OidcTokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    // Only recommended by RFC 6749, so some providers leave it out.
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    pub refresh_token: Option<String>,
+    #[serde(default)]
+    pub scope: Option<String>,
+    pub id_token: Option<String>,
+}
+
+// A token to revoke (RFC 7009), which also gives the `token_type_hint`.
+#[derive(Debug, Clone)]
+pub enum RevocableToken {
+    AccessToken(String),
+    RefreshToken(String),
+}
+
+impl RevocableToken {
+    pub fn secret(&self) -> &str {
+        match self {
+            RevocableToken::AccessToken(token) | RevocableToken::RefreshToken(token) => token,
+        }
+    }
+
+    pub fn type_hint(&self) -> &str {
+        match self {
+            RevocableToken::AccessToken(_) => "access_token",
+            RevocableToken::RefreshToken(_) => "refresh_token",
+        }
+    }
+}
+
+// How we talk to a provider's token and revocation endpoints. Everything else (the authorization request,
+// ID token verification, sessions) is the same whichever backend is used.
+#[async_trait]
+pub trait OAuth2Backend: Send + Sync {
+    async fn exchange_code(
+        &self,
+        params: &OAuth2Params,
+        code: String,
+        pkce_verifier: String,
+    ) -> Result<OidcTokenResponse>;
+
+    async fn refresh(
+        &self,
+        params: &OAuth2Params,
+        refresh_token: String,
+    ) -> Result<OidcTokenResponse>;
+
+    async fn revoke(&self, params: &OAuth2Params, token: RevocableToken) -> Result<()>;
+}
+
+#[derive(Clone)]
+pub struct Backend(Arc<dyn OAuth2Backend>);
+
+impl Backend {
+    pub fn new(backend: impl OAuth2Backend + 'static) -> Self {
+        Self(Arc::new(backend))
+    }
+
+    // Plain form posts with reqwest.
+    pub fn http(client: reqwest::Client) -> Self {
+        Self::new(HttpBackend { client })
+    }
+
+    // The `oauth2` crate's client.
+    pub fn oauth2(client: reqwest::Client) -> Self {
+        Self::new(Oauth2CrateBackend { client })
+    }
+
+    // OAUTH2_BACKEND=http|oauth2, or `default`.
+    pub fn from_env(
+        default: fn(reqwest::Client) -> Backend,
+        client: reqwest::Client,
+    ) -> Result<Self> {
+        match env::var("OAUTH2_BACKEND").as_deref() {
+            Ok("http") => Ok(Self::http(client)),
+            Ok("oauth2") => Ok(Self::oauth2(client)),
+            Ok(other) => Err(anyhow::anyhow!("Unknown OAUTH2_BACKEND {}", other)),
+            Err(_) => Ok(default(client)),
+        }
+    }
+
+    pub async fn exchange_code(
+        &self,
+        params: &OAuth2Params,
+        code: String,
+        pkce_verifier: String,
+    ) -> Result<OidcTokenResponse> {
+        self.0.exchange_code(params, code, pkce_verifier).await
+    }
+
+    pub async fn refresh(
+        &self,
+        params: &OAuth2Params,
+        refresh_token: String,
+    ) -> Result<OidcTokenResponse> {
+        self.0.refresh(params, refresh_token).await
+    }
+
+    pub async fn revoke(&self, params: &OAuth2Params, token: RevocableToken) -> Result<()> {
+        self.0.revoke(params, token).await
+    }
+}
+
+pub struct HttpBackend {
+    client: reqwest::Client,
+}
+
+#[async_trait]
+impl OAuth2Backend for HttpBackend {
+    async fn exchange_code(
+        &self,
+        params: &OAuth2Params,
+        code: String,
+        pkce_verifier: String,
+    ) -> Result<OidcTokenResponse> {
+        let form = vec![
+            ("code", code),
+            ("client_id", params.client_id.clone()),
+            ("redirect_uri", params.redirect_uri.clone()),
+            ("grant_type", "authorization_code".to_string()),
+            ("code_verifier", pkce_verifier),
+        ];
+        let response_body = post_token_request(&self.client, params, form)
+            .await
+            .context("failed in sending request request to authorization server")?;
+        serde_json::from_str(&response_body).context("failed to deserialize response body")
+    }
+
+    async fn refresh(
+        &self,
+        params: &OAuth2Params,
+        refresh_token: String,
+    ) -> Result<OidcTokenResponse> {
+        let form = vec![
+            ("client_id", params.client_id.clone()),
+            ("grant_type", "refresh_token".to_string()),
+            ("refresh_token", refresh_token),
+        ];
+        let response_body = post_token_request(&self.client, params, form)
+            .await
+            .context("failed in sending refresh request to authorization server")?;
+        serde_json::from_str(&response_body).context("failed to deserialize response body")
+    }
+
+    async fn revoke(&self, params: &OAuth2Params, token: RevocableToken) -> Result<()> {
+        let revocation_url = params
+            .revocation_url
+            .as_deref()
+            .context("provider has no revocation endpoint")?;
+        let form = vec![
+            ("token", token.secret().to_string()),
+            ("token_type_hint", token.type_hint().to_string()),
+            ("client_id", params.client_id.clone()),
+        ];
+        post_form(&self.client, params, revocation_url, form)
+            .await
+            .context("failed in sending revocation request to authorization server")?;
+        Ok(())
+    }
+}
+
+async fn post_token_request(
+    client: &reqwest::Client,
+    params: &OAuth2Params,
+    form: Vec<(&str, String)>,
+) -> Result<String> {
+    post_form(client, params, &params.token_url, form).await
+}
+
+// The client secret, if any, goes in the body (client_secret_post).
+async fn post_form(
+    client: &reqwest::Client,
+    params: &OAuth2Params,
+    url: &str,
+    mut form: Vec<(&str, String)>,
+) -> Result<String> {
+    if let Some(client_secret) = params.client_secret.clone() {
+        form.push(("client_secret", client_secret));
+    }
+    let response = client
+        .post(url)
+        .form(&form)
+        .send()
+        .await?
+        .error_for_status()
+        .context("request rejected")?;
+    response.text().await.context("failed to get response body")
+}
+
+pub struct Oauth2CrateBackend {
+    client: reqwest::Client,
+}
+
+// `BasicClient`, plus the `id_token` OpenID Connect adds to the token response.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct IdTokenFields {
+    id_token: Option<String>,
+}
+
+impl ExtraTokenFields for IdTokenFields {}
+
+type OidcClient = oauth2::Client<
+    BasicErrorResponse,
+    StandardTokenResponse<IdTokenFields, BasicTokenType>,
+    BasicTokenType,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+>;
+
+impl Oauth2CrateBackend {
+    fn client(params: &OAuth2Params) -> Result<OidcClient> {
+        Ok(OidcClient::new(
+            ClientId::new(params.client_id.clone()),
+            params.client_secret.clone().map(ClientSecret::new),
+            AuthUrl::new(params.auth_url.clone())
+                .context("failed to create new authorization server URL")?,
+            Some(
+                TokenUrl::new(params.token_url.clone())
+                    .context("failed to create new token endpoint URL")?,
+            ),
+        )
+        // Same as `HttpBackend`, and what public clients need anyway.
+        .set_auth_type(AuthType::RequestBody)
+        .set_redirect_uri(
+            RedirectUrl::new(params.redirect_uri.clone())
+                .context("failed to create new redirection URL")?,
+        ))
+    }
+
+    // The standard response serializes to the same JSON the token endpoint sent.
+    fn convert(
+        response: StandardTokenResponse<IdTokenFields, BasicTokenType>,
+    ) -> Result<OidcTokenResponse> {
+        serde_json::from_value(serde_json::to_value(response)?)
+            .context("failed to deserialize response body")
+    }
+
+    // The crate's `async_http_client` builds a client per request, without timeouts, and
+    // on the crate's own `http` version.
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, io::Error> {
+        let method = reqwest::Method::from_bytes(request.method.as_str().as_bytes())
+            .map_err(io::Error::other)?;
+        let mut request_builder = self
+            .client
+            .request(method, request.url.as_str())
+            .body(request.body);
+        for (name, value) in &request.headers {
+            request_builder = request_builder.header(name.as_str(), value.as_bytes());
+        }
+        let response = request_builder.send().await.map_err(io::Error::other)?;
+
+        let status_code = oauth2::http::StatusCode::from_u16(response.status().as_u16())
+            .map_err(io::Error::other)?;
+        let mut headers = oauth2::http::HeaderMap::new();
+        for (name, value) in response.headers() {
+            headers.append(
+                oauth2::http::HeaderName::from_bytes(name.as_str().as_bytes())
+                    .map_err(io::Error::other)?,
+                oauth2::http::HeaderValue::from_bytes(value.as_bytes())
+                    .map_err(io::Error::other)?,
+            );
+        }
+        let body = response.bytes().await.map_err(io::Error::other)?;
+        Ok(HttpResponse {
+            status_code,
+            headers,
+            body: body.to_vec(),
+        })
+    }
+}
+
+#[async_trait]
+impl OAuth2Backend for Oauth2CrateBackend {
+    async fn exchange_code(
+        &self,
+        params: &OAuth2Params,
+        code: String,
+        pkce_verifier: String,
+    ) -> Result<OidcTokenResponse> {
+        let response = Self::client(params)?
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier))
+            .request_async(|request| self.send(request))
+            .await
+            .context("failed in sending request request to authorization server")?;
+        Self::convert(response)
+    }
+
+    async fn refresh(
+        &self,
+        params: &OAuth2Params,
+        refresh_token: String,
+    ) -> Result<OidcTokenResponse> {
+        let response = Self::client(params)?
+            .exchange_refresh_token(&RefreshToken::new(refresh_token))
+            .request_async(|request| self.send(request))
+            .await
+            .context("failed in sending refresh request to authorization server")?;
+        Self::convert(response)
+    }
+
+    // The crate only revokes over https.
+    async fn revoke(&self, params: &OAuth2Params, token: RevocableToken) -> Result<()> {
+        let revocation_url = params
+            .revocation_url
+            .clone()
+            .context("provider has no revocation endpoint")?;
+        let token = match token {
+            RevocableToken::AccessToken(token) => {
+                StandardRevocableToken::AccessToken(AccessToken::new(token))
+            }
+            RevocableToken::RefreshToken(token) => {
+                StandardRevocableToken::RefreshToken(RefreshToken::new(token))
+            }
+        };
+        Self::client(params)?
+            .set_revocation_uri(
+                RevocationUrl::new(revocation_url)
+                    .context("failed to create new revocation endpoint URL")?,
+            )
+            .revoke_token(token)?
+            .request_async(|request| self.send(request))
+            .await
+            .context("failed in sending revocation request to authorization server")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{Context, Result};
+use axum_extra::headers;
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine as _,
+};
+use chrono::{DateTime, Duration, Utc};
+use http::{header::SET_COOKIE, HeaderMap, HeaderValue};
+use std::{env, fmt, sync::Arc};
+
+// Keys for private (encrypted and authenticated) cookies, from COOKIE_KEYS: comma separated,
+// base64, 32 bytes each. The first key seals new cookies; all of them open existing ones,
+// so a key can be rotated by putting a new one in front and dropping the old one later.
+#[derive(Clone)]
+pub struct CookieKeys(Arc<Vec<Aes256Gcm>>);
+
+impl CookieKeys {
+    pub fn new(keys: &[[u8; 32]]) -> Result<Self> {
+        if keys.is_empty() {
+            return Err(anyhow::anyhow!("at least one cookie key is required"));
+        }
+        let ciphers = keys
+            .iter()
+            .map(|key| Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)))
+            .collect();
+        Ok(Self(Arc::new(ciphers)))
+    }
+
+    pub fn random() -> Self {
+        Self(Arc::new(vec![Aes256Gcm::new(&Aes256Gcm::generate_key(
+            OsRng,
+        ))]))
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let keys = s
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(|key| {
+                STANDARD
+                    .decode(key)
+                    .context("cookie key is not valid base64")?
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("cookie key must be 32 bytes"))
+            })
+            .collect::<Result<Vec<[u8; 32]>>>()?;
+        Self::new(&keys)
+    }
+
+    pub fn from_env() -> Result<Self> {
+        match env::var("COOKIE_KEYS") {
+            Ok(keys) => Self::parse(&keys).context("invalid COOKIE_KEYS"),
+            Err(_) => {
+                tracing::warn!("COOKIE_KEYS not set, using a random key");
+                Ok(Self::random())
+            }
+        }
+    }
+
+    // base64url(nonce || ciphertext). The cookie name is authenticated too, so a value
+    // can't be moved from one cookie to another.
+    pub fn seal(&self, name: &str, value: &str) -> Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: value.as_bytes(),
+            aad: name.as_bytes(),
+        };
+        let ciphertext = self.0[0]
+            .encrypt(&nonce, payload)
+            .map_err(|_| anyhow::anyhow!("failed to encrypt cookie"))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(URL_SAFE_NO_PAD.encode(sealed))
+    }
+
+    pub fn open(&self, name: &str, sealed: &str) -> Result<String> {
+        let sealed = URL_SAFE_NO_PAD.decode(sealed).context("malformed cookie")?;
+        if sealed.len() < 12 {
+            return Err(anyhow::anyhow!("malformed cookie"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(12);
+        let plaintext = self
+            .0
+            .iter()
+            .find_map(|cipher| {
+                let payload = Payload {
+                    msg: ciphertext,
+                    aad: name.as_bytes(),
+                };
+                cipher.decrypt(Nonce::from_slice(nonce), payload).ok()
+            })
+            .ok_or_else(|| anyhow::anyhow!("cookie {} failed authentication", name))?;
+        String::from_utf8(plaintext).context("cookie is not UTF-8")
+    }
+
+    // The decrypted value of private cookie `name`. Tampered cookies are treated as absent.
+    pub fn get(&self, cookies: &headers::Cookie, name: &str) -> Option<String> {
+        let sealed = cookies.get(name)?;
+        match self.open(name, sealed) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                tracing::warn!("Ignoring cookie: {:#}", e);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(&self) -> &str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+// A Set-Cookie header. Always `Secure` and `HttpOnly`; both `Expires` and `Max-Age` are
+// sent, for clients that only understand the former.
+#[derive(Debug, Clone)]
+pub struct SetCookie {
+    name: String,
+    value: String,
+    max_age: i64,
+    expires_at: DateTime<Utc>,
+    same_site: SameSite,
+    path: String,
+    domain: Option<String>,
+}
+
+impl SetCookie {
+    pub fn new(name: &str, value: String, max_age: i64) -> Self {
+        Self {
+            name: name.to_string(),
+            value,
+            max_age,
+            expires_at: Utc::now() + Duration::seconds(max_age),
+            same_site: SameSite::Lax,
+            path: "/".to_string(),
+            domain: None,
+        }
+    }
+
+    // Tells the browser to drop the cookie right away.
+    pub fn removal(name: &str) -> Self {
+        Self {
+            max_age: 0,
+            expires_at: DateTime::UNIX_EPOCH,
+            ..Self::new(name, String::new(), 0)
+        }
+    }
+
+    pub fn private(keys: &CookieKeys, name: &str, value: &str, max_age: i64) -> Result<Self> {
+        Ok(Self::new(name, keys.seal(name, value)?, max_age))
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = same_site;
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = path.to_string();
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    // Browsers silently drop `__Host-` cookies with a `Domain` or a `Path` other than "/";
+    // refuse to build them instead.
+    pub fn header_value(&self) -> Result<HeaderValue> {
+        if self.name.starts_with("__Host-") && (self.domain.is_some() || self.path != "/") {
+            return Err(anyhow::anyhow!(
+                "{} must have Path=/ and no Domain",
+                self.name
+            ));
+        }
+        HeaderValue::from_str(&self.to_string()).context("failed to build Set-Cookie header")
+    }
+
+    pub fn append_to(&self, headers: &mut HeaderMap) -> Result<()> {
+        headers.append(SET_COOKIE, self.header_value()?);
+        Ok(())
+    }
+}
+
+impl fmt::Display for SetCookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}={}; SameSite={}; Secure; HttpOnly; Path={}",
+            self.name,
+            self.value,
+            self.same_site.as_str(),
+            self.path
+        )?;
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        write!(
+            f,
+            "; Max-Age={}; Expires={}",
+            self.max_age,
+            self.expires_at.format("%a, %d %b %Y %H:%M:%S GMT")
+        )
+    }
+}
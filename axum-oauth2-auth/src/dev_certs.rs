@@ -0,0 +1,135 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+static CA_COMMON_NAME: &str = "axum-google-oauth2 development CA";
+static LEAF_NAMES: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+// A local CA and a localhost certificate issued by it, for development. Trust `ca_path`
+// (in the browser, or in tests) instead of clicking through warnings for every new leaf.
+#[derive(Debug, Clone)]
+pub struct DevCerts {
+    pub ca_path: PathBuf,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    // SHA-256 of the CA certificate (DER), as colon separated hex.
+    pub ca_fingerprint: String,
+}
+
+// Generates whatever is missing in `dir`: the CA on first run, the leaf certificate
+// whenever it's gone (e.g. deleted to renew it). Existing files are left alone.
+pub fn ensure_dev_certs(dir: &Path) -> Result<DevCerts> {
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let ca_path = dir.join("ca.pem");
+    let ca_key_path = dir.join("ca-key.pem");
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+
+    let ca_key = if ca_path.exists() && ca_key_path.exists() {
+        KeyPair::from_pem(&read(&ca_key_path)?).context("invalid development CA key")?
+    } else {
+        let ca_key = KeyPair::generate()?;
+        let ca = ca_params()?.self_signed(&ca_key)?;
+        write_private(&ca_key_path, &ca_key.serialize_pem())?;
+        write(&ca_path, &ca.pem())?;
+        // A new CA can't have issued the old leaf.
+        let _ = fs::remove_file(&cert_path);
+        tracing::info!("Generated development CA {}", ca_path.display());
+        ca_key
+    };
+
+    if !(cert_path.exists() && key_path.exists()) {
+        // Same subject and key as the stored CA, so what this signs chains up to it.
+        let ca = ca_params()?.self_signed(&ca_key)?;
+        let key = KeyPair::generate()?;
+        let cert = leaf_params()?.signed_by(&key, &ca, &ca_key)?;
+        write_private(&key_path, &key.serialize_pem())?;
+        write(&cert_path, &cert.pem())?;
+        tracing::info!("Generated development certificate {}", cert_path.display());
+    }
+
+    let ca_fingerprint = fingerprint(&read(&ca_path)?)?;
+    Ok(DevCerts {
+        ca_path,
+        cert_path,
+        key_path,
+        ca_fingerprint,
+    })
+}
+
+fn ca_params() -> Result<CertificateParams> {
+    let mut params = CertificateParams::new(Vec::<String>::new())?;
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, CA_COMMON_NAME);
+    params.distinguished_name = name;
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    Ok(params)
+}
+
+fn leaf_params() -> Result<CertificateParams> {
+    let mut params = CertificateParams::new(
+        LEAF_NAMES
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>(),
+    )?;
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, "localhost");
+    params.distinguished_name = name;
+    params.key_usages = vec![
+        KeyUsagePurpose::DigitalSignature,
+        KeyUsagePurpose::KeyEncipherment,
+    ];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    Ok(params)
+}
+
+// SHA-256 fingerprint of the first certificate in a PEM file.
+pub fn fingerprint(pem: &str) -> Result<String> {
+    let body: String = pem
+        .lines()
+        .skip_while(|line| !line.starts_with("-----BEGIN CERTIFICATE-----"))
+        .skip(1)
+        .take_while(|line| !line.starts_with("-----END"))
+        .collect();
+    let der = STANDARD.decode(body).context("invalid certificate PEM")?;
+    Ok(Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":"))
+}
+
+fn read(path: &Path) -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+fn write(path: &Path, contents: &str) -> Result<()> {
+    fs::write(path, contents).with_context(|| format!("failed to write {}", path.display()))
+}
+
+// Private keys are only readable by the owner.
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    std::io::Write::write_all(&mut file, contents.as_bytes())
+        .with_context(|| format!("failed to write {}", path.display()))
+}
@@ -0,0 +1,275 @@
+use anyhow::{Context, Result};
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::task::JoinHandle;
+
+use crate::id_token::IdTokenClaims;
+use crate::provider::ProviderKind;
+use crate::User;
+
+static DOMAIN_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+// Who may log in, as a file with one rule per line:
+//
+//     # Google Workspace domains, matched against the ID token's `hd` claim
+//     hd=example.com
+//     # verified email addresses; `*` matches any run of characters
+//     email=*@example.org
+//     email=alice@gmail.com
+//
+// A login passes if either kind of rule matches. No rules at all lets everyone in. `hd` is
+// Google's claim; other providers' logins only pass on their email.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DomainRules {
+    hosted_domains: Vec<String>,
+    email_patterns: Vec<String>,
+}
+
+impl DomainRules {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut rules = Self::default();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (kind, value) = line
+                .split_once('=')
+                .map(|(kind, value)| (kind.trim(), value.trim().to_ascii_lowercase()))
+                .filter(|(_, value)| !value.is_empty())
+                .ok_or_else(|| anyhow::anyhow!("Invalid rule on line {}: {}", n + 1, line))?;
+            match kind {
+                "hd" => rules.hosted_domains.push(value),
+                "email" if value.contains('@') => rules.email_patterns.push(value),
+                _ => return Err(anyhow::anyhow!("Invalid rule on line {}: {}", n + 1, line)),
+            }
+        }
+        Ok(rules)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosted_domains.is_empty() && self.email_patterns.is_empty()
+    }
+
+    pub fn hosted_domains(&self) -> &[String] {
+        &self.hosted_domains
+    }
+
+    // Checked against the verified ID token, so neither claim can be made up by the user.
+    // Only a verified email counts.
+    pub fn check(&self, kind: ProviderKind, claims: &IdTokenClaims) -> Result<(), DomainRejected> {
+        self.check_account(
+            kind,
+            claims.hd.as_deref(),
+            claims.email.as_deref(),
+            claims.email_verified,
+        )
+    }
+
+    // The same for a logged in user, whose session keeps those claims. Rules change while
+    // sessions last.
+    pub fn check_user(&self, kind: ProviderKind, user: &User) -> Result<(), DomainRejected> {
+        self.check_account(
+            kind,
+            user.hd.as_deref(),
+            user.email.as_deref(),
+            user.verified_email,
+        )
+    }
+
+    fn check_account(
+        &self,
+        kind: ProviderKind,
+        hd: Option<&str>,
+        email: Option<&str>,
+        email_verified: bool,
+    ) -> Result<(), DomainRejected> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let hd = hd
+            .filter(|_| kind == ProviderKind::Google)
+            .map(str::to_ascii_lowercase);
+        if hd
+            .as_ref()
+            .is_some_and(|hd| self.hosted_domains.contains(hd))
+        {
+            return Ok(());
+        }
+        let verified_email = email
+            .filter(|_| email_verified)
+            .map(str::to_ascii_lowercase);
+        if verified_email.as_ref().is_some_and(|email| {
+            self.email_patterns
+                .iter()
+                .any(|pattern| wildcard_match(pattern, email))
+        }) {
+            return Ok(());
+        }
+        Err(DomainRejected {
+            email: email.map(str::to_string),
+            email_verified,
+            hd,
+            allowed_domains: self.hosted_domains.clone(),
+        })
+    }
+
+    // The `hd` authorization request parameter, which makes Google's account chooser offer
+    // only matching accounts: the one domain, or "*" for any Workspace account. Left out when
+    // email rules may let in accounts from elsewhere.
+    pub fn hd_param(&self) -> Option<String> {
+        if !self.email_patterns.is_empty() {
+            return None;
+        }
+        match self.hosted_domains.as_slice() {
+            [] => None,
+            [domain] => Some(domain.clone()),
+            _ => Some("*".to_string()),
+        }
+    }
+}
+
+// `*` matches any run of characters, everything else itself.
+fn wildcard_match(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = s.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+// Why a login was turned away, for the page that explains it.
+#[derive(Debug, Clone)]
+pub struct DomainRejected {
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub hd: Option<String>,
+    pub allowed_domains: Vec<String>,
+}
+
+impl fmt::Display for DomainRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "account {} (hosted domain {}) is not allowed",
+            self.email.as_deref().unwrap_or("without email"),
+            self.hd.as_deref().unwrap_or("none")
+        )
+    }
+}
+
+impl std::error::Error for DomainRejected {}
+
+// The rules in effect, shared by every request. Read from DOMAIN_ALLOWLIST_FILE, if set;
+// `spawn_reload_task` picks up changes to the file without a restart.
+#[derive(Debug, Clone, Default)]
+pub struct DomainAllowlist {
+    path: Option<PathBuf>,
+    rules: Arc<RwLock<DomainRules>>,
+}
+
+impl DomainAllowlist {
+    // Fixed rules, for configuration in code.
+    pub fn new(rules: DomainRules) -> Self {
+        Self {
+            path: None,
+            rules: Arc::new(RwLock::new(rules)),
+        }
+    }
+
+    pub fn from_file(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let rules = read_rules(&path)?;
+        Ok(Self {
+            path: Some(path),
+            rules: Arc::new(RwLock::new(rules)),
+        })
+    }
+
+    pub fn from_env() -> Result<Self> {
+        match env::var("DOMAIN_ALLOWLIST_FILE") {
+            Ok(path) => Self::from_file(path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn rules(&self) -> DomainRules {
+        self.rules.read().unwrap().clone()
+    }
+
+    // Rereads the file. Invalid rules are an error and leave the current ones in place.
+    pub fn reload(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let rules = read_rules(path)?;
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+}
+
+fn read_rules(path: &Path) -> Result<DomainRules> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read domain allowlist {}", path.display()))?;
+    DomainRules::parse(&contents)
+        .with_context(|| format!("invalid domain allowlist {}", path.display()))
+}
+
+// Reloads the allowlist when its file changes, or on SIGHUP. Nothing to do without a file.
+pub fn spawn_reload_task(allowlist: DomainAllowlist) -> Option<JoinHandle<()>> {
+    let path = allowlist.path()?.to_path_buf();
+    Some(tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("failed to install SIGHUP handler");
+        let mut interval = tokio::time::interval(DOMAIN_RELOAD_INTERVAL);
+        let mut modified = modified_time(&path);
+        loop {
+            #[cfg(unix)]
+            let reason = tokio::select! {
+                _ = interval.tick() => None,
+                _ = hangup.recv() => Some("SIGHUP"),
+            };
+            #[cfg(not(unix))]
+            let reason = {
+                interval.tick().await;
+                None
+            };
+
+            let current = modified_time(&path);
+            let reason = match reason {
+                Some(reason) => reason,
+                None if current != modified => "file changed",
+                None => continue,
+            };
+            modified = current;
+            match allowlist.reload() {
+                Ok(()) => tracing::info!("Reloaded domain allowlist ({})", reason),
+                Err(e) => tracing::error!("Failed to reload domain allowlist: {:#}", e),
+            }
+        }
+    }))
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
@@ -0,0 +1,61 @@
+use anyhow::Result;
+use axum_extra::headers;
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+use crate::cookies::{CookieKeys, SetCookie};
+
+pub static FLASH_COOKIE_NAME: &str = "__Host-Flash";
+// Only has to survive the redirect to the page that shows it.
+static FLASH_COOKIE_MAX_AGE: i64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlashLevel {
+    Info,
+    Error,
+}
+
+impl FlashLevel {
+    pub fn as_str(&self) -> &str {
+        match self {
+            FlashLevel::Info => "info",
+            FlashLevel::Error => "error",
+        }
+    }
+}
+
+// A one-off message for the next page, e.g. why a login failed. It goes across the redirect
+// in a private cookie, and `Layout::render` removes the cookie once the message is shown.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Flash {
+    pub level: FlashLevel,
+    pub message: String,
+}
+
+impl Flash {
+    pub fn info(message: impl Into<String>) -> Self {
+        Self {
+            level: FlashLevel::Info,
+            message: message.into(),
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            level: FlashLevel::Error,
+            message: message.into(),
+        }
+    }
+
+    pub fn from_cookies(keys: &CookieKeys, cookies: &headers::Cookie) -> Option<Self> {
+        let value = keys.get(cookies, FLASH_COOKIE_NAME)?;
+        serde_json::from_str(&value).ok()
+    }
+
+    pub fn append_to(&self, keys: &CookieKeys, headers: &mut HeaderMap) -> Result<()> {
+        let value = serde_json::to_string(self)?;
+        SetCookie::private(keys, FLASH_COOKIE_NAME, &value, FLASH_COOKIE_MAX_AGE)?
+            .append_to(headers)
+    }
+}
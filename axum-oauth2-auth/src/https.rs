@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::State,
+    middleware,
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use http::{header, HeaderMap, HeaderValue, StatusCode, Uri};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::task::JoinHandle;
+
+use crate::dev_certs;
+
+static DEFAULT_HTTP_PORT: u16 = 3001;
+static DEFAULT_HTTPS_PORT: u16 = 3443;
+// Short, since HSTS for a host covers all its ports, e.g. every other dev server on
+// localhost. A longer one has to be asked for with HSTS_MAX_AGE.
+static DEFAULT_HSTS_MAX_AGE: u64 = 300; // 5 minutes
+static TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+// Ports and certificate files, from HTTP_PORT, HTTPS_PORT, TLS_CERT_PATH, TLS_KEY_PATH and
+// HSTS_MAX_AGE. DEV_CERTS=true instead of TLS_CERT_PATH and TLS_KEY_PATH is dev mode: a
+// development CA and certificate are generated in DEV_CERTS_DIR, by default `dev_certs` in
+// the working directory (see `dev_certs`), and there's no HSTS unless HSTS_MAX_AGE is set.
+// Dev mode is never the fallback for a missing certificate.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub http_port: u16,
+    pub https_port: u16,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub hsts_max_age: Option<u64>,
+    pub dev_certs_dir: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Result<Self> {
+        let dev_certs = parse_var("DEV_CERTS", false)?;
+        let (cert_path, key_path, dev_certs_dir) = match (
+            env::var("TLS_CERT_PATH"),
+            env::var("TLS_KEY_PATH"),
+            dev_certs,
+        ) {
+            (Ok(_), _, true) | (_, Ok(_), true) => {
+                return Err(anyhow::anyhow!(
+                    "DEV_CERTS can't be combined with TLS_CERT_PATH and TLS_KEY_PATH"
+                ))
+            }
+            (Ok(cert_path), Ok(key_path), false) => (cert_path.into(), key_path.into(), None),
+            (Err(_), Err(_), true) => {
+                let dir = env::var("DEV_CERTS_DIR")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|_| PathBuf::from("dev_certs"));
+                (dir.join("cert.pem"), dir.join("key.pem"), Some(dir))
+            }
+            (Err(_), Err(_), false) => {
+                return Err(anyhow::anyhow!(
+                    "No TLS certificate: set TLS_CERT_PATH and TLS_KEY_PATH, or DEV_CERTS=true for a development certificate"
+                ))
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "TLS_CERT_PATH and TLS_KEY_PATH must be set together"
+                ))
+            }
+        };
+        Ok(Self {
+            http_port: parse_var("HTTP_PORT", DEFAULT_HTTP_PORT)?,
+            https_port: parse_var("HTTPS_PORT", DEFAULT_HTTPS_PORT)?,
+            cert_path,
+            key_path,
+            hsts_max_age: if dev_certs_dir.is_some() && env::var("HSTS_MAX_AGE").is_err() {
+                None
+            } else {
+                Some(parse_var("HSTS_MAX_AGE", DEFAULT_HSTS_MAX_AGE)?)
+            },
+            dev_certs_dir,
+        })
+    }
+}
+
+fn parse_var<T: std::str::FromStr>(name: &str, default: T) -> Result<T> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid {}: {}", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+// All the plain HTTP listener does: `__Host-` cookies are Secure and would never come back
+// over HTTP anyway. 308 keeps the method and body, unlike 301.
+pub fn redirect_router(https_port: u16) -> Router {
+    Router::new()
+        .fallback(redirect_to_https)
+        .with_state(https_port)
+}
+
+async fn redirect_to_https(
+    State(https_port): State<u16>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    let Some(host) = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<http::uri::Authority>().ok())
+    else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+    let authority = match https_port {
+        443 => host.host().to_string(),
+        port => format!("{}:{}", host.host(), port),
+    };
+    let path_and_query = uri.path_and_query().map_or("/", |pq| pq.as_str());
+    Redirect::permanent(&format!("https://{}{}", authority, path_and_query)).into_response()
+}
+
+// Adds Strict-Transport-Security to every response, unless `max_age` is None; only meant
+// for the HTTPS listener.
+pub fn with_hsts(app: Router, max_age: Option<u64>) -> Router {
+    match max_age {
+        Some(max_age) => app.layer(middleware::map_response_with_state(max_age, hsts)),
+        None => app,
+    }
+}
+
+async fn hsts(State(max_age): State<u64>, mut response: Response) -> Response {
+    if let Ok(value) = HeaderValue::from_str(&format!("max-age={}", max_age)) {
+        response
+            .headers_mut()
+            .insert(header::STRICT_TRANSPORT_SECURITY, value);
+    }
+    response
+}
+
+// In dev mode, generates the certificate first if need be.
+pub async fn load_tls_config(config: &ServerConfig) -> Result<RustlsConfig> {
+    if let Some(dir) = &config.dev_certs_dir {
+        let dev_certs = dev_certs::ensure_dev_certs(dir)?;
+        tracing::info!(
+            "Development CA: {} (SHA-256 {})",
+            dev_certs.ca_path.display(),
+            dev_certs.ca_fingerprint
+        );
+    }
+    RustlsConfig::from_pem_file(&config.cert_path, &config.key_path)
+        .await
+        .with_context(|| {
+            format!(
+                "failed to load TLS certificate {} and key {}",
+                config.cert_path.display(),
+                config.key_path.display()
+            )
+        })
+}
+
+// Reloads the certificate when either PEM file changes, or on SIGHUP. A failed reload
+// keeps the certificate in use.
+pub fn spawn_tls_reload_task(tls_config: RustlsConfig, config: ServerConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("failed to install SIGHUP handler");
+        let mut interval = tokio::time::interval(TLS_RELOAD_INTERVAL);
+        let mut modified = modified_times(&config);
+        loop {
+            #[cfg(unix)]
+            let reason = tokio::select! {
+                _ = interval.tick() => None,
+                _ = hangup.recv() => Some("SIGHUP"),
+            };
+            #[cfg(not(unix))]
+            let reason = {
+                interval.tick().await;
+                None
+            };
+
+            let current = modified_times(&config);
+            let reason = match reason {
+                Some(reason) => reason,
+                None if current != modified => "certificate files changed",
+                None => continue,
+            };
+            modified = current;
+            match tls_config
+                .reload_from_pem_file(&config.cert_path, &config.key_path)
+                .await
+            {
+                Ok(()) => tracing::info!("Reloaded TLS certificate ({})", reason),
+                Err(e) => tracing::error!("Failed to reload TLS certificate: {:#}", e),
+            }
+        }
+    })
+}
+
+fn modified_times(config: &ServerConfig) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+    (modified(&config.cert_path), modified(&config.key_path))
+}
@@ -0,0 +1,247 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::Utc;
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+
+static JWKS_CACHE_TTL: Duration = Duration::from_secs(3600);
+// Anyone can send a token with an unknown kid (back-channel logout needs no login), so
+// those refetch the keys at most this often.
+static JWKS_MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+static LEEWAY_SECS: u64 = 60;
+// Logout tokens are acted on right away; older ones are rejected.
+static LOGOUT_TOKEN_MAX_AGE_SECS: i64 = 300;
+static BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+// The claims we use from an ID token. Everything beyond the required claims is optional,
+// `hd` is Google specific and `sid` comes with back-channel logout support.
+// https://openid.net/specs/openid-connect-core-1_0.html#IDToken
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: Audience,
+    pub exp: i64,
+    pub iat: i64,
+    pub nonce: Option<String>,
+    pub c_hash: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub name: Option<String>,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub picture: Option<String>,
+    pub preferred_username: Option<String>,
+    pub hd: Option<String>,
+    pub sid: Option<String>,
+}
+
+// A back-channel logout token names the user (`sub`), the provider session (`sid`) or both.
+// https://openid.net/specs/openid-connect-backchannel-1_0.html#LogoutToken
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogoutTokenClaims {
+    pub iss: String,
+    pub aud: Audience,
+    pub iat: i64,
+    pub jti: String,
+    pub sub: Option<String>,
+    pub sid: Option<String>,
+    pub events: HashMap<String, serde_json::Value>,
+    // Never allowed, so a logout token can't pass for an ID token.
+    pub nonce: Option<String>,
+}
+
+// `aud` is either a single client id or an array of them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+// A provider's signing keys, refetched when they are older than `JWKS_CACHE_TTL`
+// or when a token names a key we haven't seen (keys are rotated regularly), but not
+// within `JWKS_MIN_REFETCH_INTERVAL` of the last fetch.
+#[derive(Clone)]
+pub struct JwksCache {
+    url: String,
+    client: reqwest::Client,
+    cached: Arc<RwLock<Option<(JwkSet, Instant)>>>,
+    last_fetch: Arc<Mutex<Option<Instant>>>,
+}
+
+impl JwksCache {
+    pub fn new(url: &str, client: reqwest::Client) -> Self {
+        Self {
+            url: url.to_string(),
+            client,
+            cached: Arc::new(RwLock::new(None)),
+            last_fetch: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    async fn decoding_key(&self, kid: &str) -> Result<DecodingKey> {
+        let cached = self
+            .cached
+            .read()
+            .await
+            .as_ref()
+            .and_then(|(jwks, fetched_at)| {
+                let fresh = fetched_at.elapsed() < JWKS_CACHE_TTL;
+                jwks.find(kid).map(|jwk| (jwk.clone(), fresh))
+            });
+        match cached {
+            Some((jwk, true)) => return DecodingKey::from_jwk(&jwk).context("invalid JWK"),
+            // A stale key still beats no key while we may not refetch.
+            Some((jwk, false)) if !self.may_fetch() => {
+                return DecodingKey::from_jwk(&jwk).context("invalid JWK");
+            }
+            None if !self.may_fetch() => {
+                return Err(anyhow::anyhow!("No JWK found for kid {}", kid));
+            }
+            _ => {}
+        }
+
+        let jwks = self.fetch().await?;
+        let key = jwks
+            .find(kid)
+            .map(DecodingKey::from_jwk)
+            .transpose()
+            .context("invalid JWK");
+        *self.cached.write().await = Some((jwks, Instant::now()));
+        key?.ok_or_else(|| anyhow::anyhow!("No JWK found for kid {}", kid))
+    }
+
+    // Claims the next fetch, unless the last one was too recent.
+    fn may_fetch(&self) -> bool {
+        let mut last_fetch = self.last_fetch.lock().unwrap();
+        if last_fetch.is_some_and(|at| at.elapsed() < JWKS_MIN_REFETCH_INTERVAL) {
+            return false;
+        }
+        *last_fetch = Some(Instant::now());
+        true
+    }
+
+    async fn fetch(&self) -> Result<JwkSet> {
+        let response_body = self
+            .client
+            .get(&self.url)
+            .send()
+            .await
+            .context("failed to fetch JWKS")?
+            .error_for_status()
+            .context("failed to fetch JWKS")?
+            .text()
+            .await
+            .context("failed to get JWKS response body")?;
+        serde_json::from_str(&response_body).context("failed to deserialize JWKS")
+    }
+}
+
+// Verifies the RS256 signature and the iss, aud, exp, iat and nonce claims.
+pub async fn verify_id_token(
+    id_token: &str,
+    jwks: &JwksCache,
+    issuers: &[String],
+    client_id: &str,
+    expected_nonce: &str,
+) -> Result<IdTokenClaims> {
+    let key = signing_key(id_token, jwks, "ID token").await?;
+
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_issuer(issuers);
+    validation.set_audience(&[client_id]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    validation.leeway = LEEWAY_SECS;
+
+    let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+        .context("ID token validation failed")?
+        .claims;
+
+    if claims.iat > Utc::now().timestamp() + LEEWAY_SECS as i64 {
+        return Err(anyhow::anyhow!("ID token issued in the future"));
+    }
+    if claims.nonce.as_deref() != Some(expected_nonce) {
+        return Err(anyhow::anyhow!("ID token nonce mismatch"));
+    }
+    Ok(claims)
+}
+
+// Verifies a back-channel logout token: the RS256 signature, iss, aud, iat and the
+// logout event, as OpenID Connect Back-Channel Logout 1.0 section 2.6 asks.
+pub async fn verify_logout_token(
+    logout_token: &str,
+    jwks: &JwksCache,
+    issuers: &[String],
+    client_id: &str,
+) -> Result<LogoutTokenClaims> {
+    let key = signing_key(logout_token, jwks, "logout token").await?;
+
+    // `exp` is optional here, and still checked when present.
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_issuer(issuers);
+    validation.set_audience(&[client_id]);
+    validation.set_required_spec_claims(&["iss", "aud"]);
+    validation.leeway = LEEWAY_SECS;
+
+    let claims = decode::<LogoutTokenClaims>(logout_token, &key, &validation)
+        .context("logout token validation failed")?
+        .claims;
+
+    let now = Utc::now().timestamp();
+    if claims.iat > now + LEEWAY_SECS as i64 {
+        return Err(anyhow::anyhow!("logout token issued in the future"));
+    }
+    if claims.iat < now - LOGOUT_TOKEN_MAX_AGE_SECS {
+        return Err(anyhow::anyhow!("logout token is too old"));
+    }
+    if !claims.events.contains_key(BACKCHANNEL_LOGOUT_EVENT) {
+        return Err(anyhow::anyhow!(
+            "logout token has no back-channel logout event"
+        ));
+    }
+    if claims.sub.is_none() && claims.sid.is_none() {
+        return Err(anyhow::anyhow!("logout token has neither sub nor sid"));
+    }
+    if claims.nonce.is_some() {
+        return Err(anyhow::anyhow!("logout token must not have a nonce"));
+    }
+    Ok(claims)
+}
+
+// The provider key a token was signed with, by the `kid` in its header.
+async fn signing_key(token: &str, jwks: &JwksCache, what: &str) -> Result<DecodingKey> {
+    let header = decode_header(token).with_context(|| format!("malformed {} header", what))?;
+    if header.alg != Algorithm::RS256 {
+        return Err(anyhow::anyhow!(
+            "Unexpected {} algorithm {:?}",
+            what,
+            header.alg
+        ));
+    }
+    let kid = header
+        .kid
+        .ok_or_else(|| anyhow::anyhow!("{} has no kid", what))?;
+    jwks.decoding_key(&kid).await
+}
+
+// `c_hash` binds a front-channel ID token to the code delivered with it: the base64url
+// encoded left half of the SHA-256 hash of the code, for RS256.
+// https://openid.net/specs/openid-connect-core-1_0.html#HybridIDToken
+pub fn verify_c_hash(claims: &IdTokenClaims, code: &str) -> Result<()> {
+    let digest = Sha256::digest(code.as_bytes());
+    let expected = URL_SAFE_NO_PAD.encode(&digest[..digest.len() / 2]);
+    match claims.c_hash.as_deref() {
+        Some(c_hash) if c_hash == expected => Ok(()),
+        Some(_) => Err(anyhow::anyhow!("ID token c_hash mismatch")),
+        None => Err(anyhow::anyhow!("ID token has no c_hash")),
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::{Context, Result};
+use askama_axum::Template;
+use async_session::{Session, SessionStore};
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    response::{Html, IntoResponse, Response},
+    RequestPartsExt,
+};
+use axum_extra::{headers, TypedHeader};
+use http::{request::Parts, HeaderMap};
+use std::convert::Infallible;
+
+use crate::cookies::{CookieKeys, SetCookie};
+use crate::flash::{Flash, FLASH_COOKIE_NAME};
+use crate::session_store::AppSessionStore;
+use crate::users::UserStore;
+use crate::{User, COOKIE_NAME};
+
+// The session's synchronizer token, which every POST form of a logged in user carries.
+// Set at login, so it changes with every session.
+pub(crate) static FORM_TOKEN_KEY: &str = "csrf_token";
+
+// What `base.j2` shows around every page: who is logged in, a pending flash message and
+// the form token for the logout button.
+#[derive(Debug, Clone, Default)]
+pub struct Layout {
+    pub user: Option<User>,
+    pub flash: Option<Flash>,
+    pub csrf_token: String,
+}
+
+impl Layout {
+    // The page as a response. A flash message shown on it is not shown again.
+    pub fn render(&self, template: &impl Template) -> Result<Response> {
+        let mut headers = HeaderMap::new();
+        if self.flash.is_some() {
+            SetCookie::removal(FLASH_COOKIE_NAME).append_to(&mut headers)?;
+        }
+        Ok((headers, Html(template.render()?)).into_response())
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Layout
+where
+    AppSessionStore: FromRef<S>,
+    CookieKeys: FromRef<S>,
+    UserStore: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = User::from_request_parts(parts, state).await.ok();
+        let Ok(TypedHeader(cookies)) = parts.extract::<TypedHeader<headers::Cookie>>().await else {
+            return Ok(Self {
+                user,
+                ..Self::default()
+            });
+        };
+        let cookie_keys = CookieKeys::from_ref(state);
+        let flash = Flash::from_cookies(&cookie_keys, &cookies);
+
+        let mut csrf_token = String::new();
+        if let (Some(_), Some(cookie)) = (&user, cookie_keys.get(&cookies, COOKIE_NAME)) {
+            match AppSessionStore::from_ref(state).load_session(cookie).await {
+                Ok(Some(session)) => {
+                    csrf_token = session.get(FORM_TOKEN_KEY).unwrap_or_default();
+                }
+                Ok(None) => {}
+                Err(e) => tracing::error!("Failed to load session: {:#}", e),
+            }
+        }
+
+        Ok(Self {
+            user,
+            flash,
+            csrf_token,
+        })
+    }
+}
+
+// A form posted with a token other than the session's is rejected, so other sites can't
+// submit it on the user's behalf.
+pub(crate) fn check_form_token(session: &Session, submitted: &str) -> Result<()> {
+    let expected = session
+        .get::<String>(FORM_TOKEN_KEY)
+        .context("session has no form token")?;
+    if expected.is_empty() || expected != submitted {
+        return Err(anyhow::anyhow!("form token mismatch, please try again"));
+    }
+    Ok(())
+}
+
+// `check_form_token` for the session of the request's cookie.
+pub(crate) async fn check_request_form_token(
+    store: &AppSessionStore,
+    cookie_keys: &CookieKeys,
+    cookies: &headers::Cookie,
+    submitted: &str,
+) -> Result<()> {
+    let cookie = cookie_keys
+        .get(cookies, COOKIE_NAME)
+        .context("no session cookie")?;
+    let session = store
+        .load_session(cookie)
+        .await
+        .context("failed to load session")?
+        .context("session not found")?;
+    check_form_token(&session, submitted)
+}
@@ -0,0 +1,1445 @@
+use anyhow::{Context, Result};
+use async_session::{Session, SessionStore};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRef, FromRequestParts, Path, Query, State},
+    http::HeaderMap,
+    middleware,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Json, RequestPartsExt, Router,
+};
+use axum_extra::{headers, typed_header::TypedHeaderRejectionReason, TypedHeader};
+use http::{header, request::Parts, StatusCode};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{env, net::SocketAddr};
+
+use url::Url;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Duration, Utc};
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+
+// use askama::Template;
+use askama_axum::Template;
+use axum::response::Html;
+
+pub mod audit;
+pub mod authorization;
+pub mod backend;
+pub mod cookies;
+pub mod dev_certs;
+pub mod domains;
+pub mod flash;
+pub mod https;
+pub mod id_token;
+pub mod layout;
+pub mod logout;
+pub mod popup;
+pub mod provider;
+pub mod roles;
+pub mod session_store;
+pub mod sessions;
+pub mod tokens;
+pub mod users;
+
+use audit::{AuditLog, AuthEvent, AuthEventFilter, AuthEventKind};
+use authorization::AuthorizationRequest;
+use backend::Backend;
+use cookies::{CookieKeys, SameSite, SetCookie};
+use domains::{DomainAllowlist, DomainRejected};
+use flash::Flash;
+use layout::Layout;
+use popup::{LoginMode, LoginQuery, LoginResult, PopupOrigins};
+use provider::{ProviderKind, Providers};
+use roles::{Admin, RequireRole, RoleGrants};
+use session_store::AppSessionStore;
+use sessions::SessionOrigin;
+use tokens::{AccessToken, StoredTokens, TokenCipher};
+use users::UserStore;
+
+// "__Host-" prefix are added to make cookies "host-only".
+pub static COOKIE_NAME: &str = "__Host-SessionId";
+pub static CSRF_COOKIE_NAME: &str = "__Host-CsrfId";
+static COOKIE_MAX_AGE: i64 = 600; // 10 minutes
+static CSRF_COOKIE_MAX_AGE: i64 = 20; // 20 seconds
+
+// Everything comes from the environment; see the Readme for the variables. OAUTH2_BACKEND
+// overrides `default_backend`, e.g. `Backend::http`.
+pub async fn app_state_init(default_backend: fn(reqwest::Client) -> Backend) -> Result<AppState> {
+    let client = backend::http_client()?;
+    let backend = Backend::from_env(default_backend, client.clone())?;
+    let store = AppSessionStore::from_env()
+        .await
+        .context("Failed to initialize session store")?;
+
+    let origin = env::var("ORIGIN").context("Missing ORIGIN!")?;
+    let providers = Providers::from_env(&origin, &client)
+        .await
+        .context("Failed to initialize OIDC providers")?;
+
+    let cipher = TokenCipher::from_env().context("Failed to initialize token cipher")?;
+    let cookie_keys = CookieKeys::from_env().context("Failed to initialize cookie keys")?;
+    let users = UserStore::from_env()
+        .await
+        .context("Failed to initialize user database")?;
+    let role_grants = RoleGrants::from_env().context("Invalid ROLE_GRANTS")?;
+    let domains = DomainAllowlist::from_env().context("Invalid DOMAIN_ALLOWLIST_FILE")?;
+    let audit = AuditLog::from_env(&users);
+    let popup_origins = PopupOrigins::from_env(&origin).context("Invalid POPUP_ORIGINS")?;
+
+    Ok(AppState {
+        store,
+        providers,
+        cipher,
+        cookie_keys,
+        users,
+        role_grants,
+        domains,
+        audit,
+        popup_origins,
+        backend,
+    })
+}
+
+pub fn create_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", get(index))
+        .route("/auth/:provider", get(provider_auth))
+        .route(
+            "/auth/:provider/backchannel_logout",
+            post(logout::backchannel_logout),
+        )
+        .route(
+            "/auth/authorized",
+            get(login_authorized).post(post_login_authorized),
+        )
+        .route("/protected", get(protected))
+        .route("/profile", get(profile))
+        .route("/me", get(me))
+        .route("/admin", get(admin))
+        .route("/admin/sessions/:id/revoke", post(admin_revoke_session))
+        .route("/admin/events", get(admin_events))
+        .route("/sessions", get(user_sessions))
+        .route("/sessions/revoke_all", post(revoke_all_sessions))
+        .route("/sessions/:id/revoke", post(revoke_user_session))
+        .route("/logout", get(logout_page).post(logout))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            tokens::renew_session,
+        ))
+        .with_state(app_state)
+}
+
+#[derive(Debug, Clone)]
+pub enum ResponseMode {
+    Query,
+    Fragment,
+    FormPost,
+}
+
+impl ResponseMode {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Query => "query",
+            Self::Fragment => "fragment",
+            Self::FormPost => "form_post",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "query" => Ok(Self::Query),
+            "fragment" => Ok(Self::Fragment),
+            "form_post" => Ok(Self::FormPost),
+            other => Err(anyhow::anyhow!("Unknown response mode {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Prompt {
+    None,
+    Consent,
+    SelectAccount,
+    Login,
+    ConsentSelectAccount,
+    ConsentLogin,
+    SelectAccountLogin,
+    ConsentSelectAccountLogin,
+}
+
+impl Prompt {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::None => "none",
+            Self::Consent => "consent",
+            Self::SelectAccount => "select_account",
+            Self::Login => "login",
+            Self::ConsentSelectAccount => "consent select_account",
+            Self::ConsentLogin => "consent login",
+            Self::SelectAccountLogin => "select_account login",
+            Self::ConsentSelectAccountLogin => "consent select_account login",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AccessType {
+    Online,
+    Offline,
+}
+
+impl AccessType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Online => "online",
+            Self::Offline => "offline",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseType {
+    None = 0b000,
+    Code = 0b001,
+    Token = 0b010,
+    IdToken = 0b100,
+    CodeToken = 0b011,
+    CodeIdToken = 0b101,
+    TokenIdToken = 0b110,
+    CodeTokenIdToken = 0b111,
+}
+
+impl ResponseType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::None => "none",
+            Self::Code => "code",
+            Self::Token => "token",
+            Self::IdToken => "id_token",
+            Self::CodeToken => "code token",
+            Self::CodeIdToken => "code id_token",
+            Self::TokenIdToken => "token id_token",
+            Self::CodeTokenIdToken => "code token id_token",
+        }
+    }
+
+    // Space separated and unordered, as in the `response_type` parameter.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut bits = 0;
+        for value in s.split_whitespace() {
+            bits |= match value {
+                "code" => Self::Code as u8,
+                "token" => Self::Token as u8,
+                "id_token" => Self::IdToken as u8,
+                "none" => Self::None as u8,
+                other => return Err(anyhow::anyhow!("Unknown response type {}", other)),
+            };
+        }
+        Ok(match bits {
+            0b001 => Self::Code,
+            0b010 => Self::Token,
+            0b100 => Self::IdToken,
+            0b011 => Self::CodeToken,
+            0b101 => Self::CodeIdToken,
+            0b110 => Self::TokenIdToken,
+            0b111 => Self::CodeTokenIdToken,
+            _ => Self::None,
+        })
+    }
+
+    pub fn has_code(self) -> bool {
+        self as u8 & Self::Code as u8 != 0
+    }
+
+    pub fn has_token(self) -> bool {
+        self as u8 & Self::Token as u8 != 0
+    }
+
+    pub fn has_id_token(self) -> bool {
+        self as u8 & Self::IdToken as u8 != 0
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct OAuth2Params {
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: String,
+    auth_url: String,
+    token_url: String,
+    revocation_url: Option<String>,
+    response_type: String,
+    scope: String,
+    nonce: Option<String>,
+    state: Option<String>,
+    csrf_token: Option<String>,
+    response_mode: Option<ResponseMode>,
+    prompt: Option<Prompt>,
+    access_type: Option<AccessType>,
+    login_hint: Option<String>,
+    hd: Option<String>,
+    extra_params: Vec<(String, String)>,
+}
+
+// What an `OAuth2Backend` outside this crate needs for the token requests.
+impl OAuth2Params {
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn client_secret(&self) -> Option<&str> {
+        self.client_secret.as_deref()
+    }
+
+    pub fn redirect_uri(&self) -> &str {
+        &self.redirect_uri
+    }
+
+    pub fn token_url(&self) -> &str {
+        &self.token_url
+    }
+
+    pub fn revocation_url(&self) -> Option<&str> {
+        self.revocation_url.as_deref()
+    }
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    pub store: AppSessionStore,
+    pub providers: Providers,
+    pub cipher: TokenCipher,
+    pub cookie_keys: CookieKeys,
+    pub users: UserStore,
+    pub role_grants: RoleGrants,
+    pub domains: DomainAllowlist,
+    pub audit: AuditLog,
+    pub popup_origins: PopupOrigins,
+    pub backend: Backend,
+}
+
+impl FromRef<AppState> for AppSessionStore {
+    fn from_ref(state: &AppState) -> Self {
+        state.store.clone()
+    }
+}
+
+impl FromRef<AppState> for Providers {
+    fn from_ref(state: &AppState) -> Self {
+        state.providers.clone()
+    }
+}
+
+impl FromRef<AppState> for TokenCipher {
+    fn from_ref(state: &AppState) -> Self {
+        state.cipher.clone()
+    }
+}
+
+impl FromRef<AppState> for CookieKeys {
+    fn from_ref(state: &AppState) -> Self {
+        state.cookie_keys.clone()
+    }
+}
+
+impl FromRef<AppState> for UserStore {
+    fn from_ref(state: &AppState) -> Self {
+        state.users.clone()
+    }
+}
+
+impl FromRef<AppState> for RoleGrants {
+    fn from_ref(state: &AppState) -> Self {
+        state.role_grants.clone()
+    }
+}
+
+impl FromRef<AppState> for DomainAllowlist {
+    fn from_ref(state: &AppState) -> Self {
+        state.domains.clone()
+    }
+}
+
+impl FromRef<AppState> for AuditLog {
+    fn from_ref(state: &AppState) -> Self {
+        state.audit.clone()
+    }
+}
+
+impl FromRef<AppState> for PopupOrigins {
+    fn from_ref(state: &AppState) -> Self {
+        state.popup_origins.clone()
+    }
+}
+
+// The normalised user data we take from a verified ID token, plus the local account
+// it belongs to. Only `id` (the subject) is guaranteed by every provider.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    #[serde(default)]
+    pub user_id: i64,
+    pub provider: String,
+    pub family_name: Option<String>,
+    pub name: String,
+    pub picture: Option<String>,
+    pub email: Option<String>,
+    pub given_name: Option<String>,
+    pub id: String,
+    pub hd: Option<String>,
+    pub verified_email: bool,
+}
+
+#[derive(Template)]
+#[template(path = "index.j2")]
+struct IndexTemplate {
+    layout: Layout,
+    providers: Vec<String>,
+}
+
+async fn index(layout: Layout, State(providers): State<Providers>) -> Result<Response, AppError> {
+    let template = IndexTemplate {
+        layout: layout.clone(),
+        providers: providers.names(),
+    };
+    Ok(layout.render(&template)?)
+}
+
+#[derive(Serialize, Deserialize)]
+struct CsrfData {
+    provider: String,
+    response_type: String,
+    response_mode: String,
+    // Set when a logged in user adds another provider to their account.
+    link_user_id: Option<i64>,
+    csrf_token: String,
+    nonce: String,
+    pkce_verifier: String,
+    expires_at: DateTime<Utc>,
+    user_agent: String,
+    #[serde(default)]
+    login_mode: LoginMode,
+}
+
+async fn provider_auth(
+    user: Option<User>,
+    Path(provider_name): Path<String>,
+    Query(query): Query<LoginQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let AppState {
+        providers,
+        store,
+        cookie_keys,
+        popup_origins,
+        domains,
+        ..
+    } = state;
+    let Some(provider) = providers.get(&provider_name) else {
+        return Ok((StatusCode::NOT_FOUND, "Unknown provider").into_response());
+    };
+    let login_mode = match LoginMode::from_query(&query, &popup_origins) {
+        Ok(login_mode) => login_mode,
+        Err(e) => return Ok((StatusCode::BAD_REQUEST, format!("{:#}", e)).into_response()),
+    };
+    let mut params = provider.params();
+
+    let csrf_token = random_string(32);
+    // Binds the ID token to this login attempt; checked in `login_authorized`.
+    let nonce = random_string(32);
+    // PKCE (RFC 7636): only the S256 challenge leaves the server before the token exchange.
+    let pkce_verifier = random_string(64);
+    let pkce_challenge = pkce_challenge_s256(&pkce_verifier);
+
+    let expires_at = Utc::now() + Duration::seconds(CSRF_COOKIE_MAX_AGE);
+
+    let user_agent = headers
+        .get(axum::http::header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("Unknown")
+        .to_string();
+
+    let response_mode = params.response_mode.clone().unwrap_or(ResponseMode::Query);
+    let csrf_data = CsrfData {
+        provider: provider_name,
+        response_type: params.response_type.clone(),
+        response_mode: response_mode.as_str().to_string(),
+        link_user_id: user.map(|user| user.user_id),
+        csrf_token: csrf_token.clone(),
+        nonce: nonce.clone(),
+        pkce_verifier,
+        expires_at,
+        user_agent,
+        login_mode,
+    };
+
+    let mut session = Session::new();
+    session.insert("csrf_data", csrf_data)?;
+    session.set_expiry(expires_at);
+
+    let csrf_id = store
+        .store_session(session)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Failed to store session"))?;
+
+    params.nonce = Some(nonce);
+    params.csrf_token = Some(csrf_token.clone());
+    params.state = Some(csrf_token);
+    params.login_hint = query.login_hint;
+    // Only a hint for Google's account chooser; `complete_login` enforces the allowlist.
+    if provider.config().kind == ProviderKind::Google {
+        params.hd = domains.rules().hd_param();
+    }
+
+    let auth_url = AuthorizationRequest::from_params(&params)?
+        .pkce_s256(&pkce_challenge)
+        .build()?;
+    tracing::debug!("Redirecting to {} for login", params.auth_url);
+
+    // A form_post response is a cross-site POST, on which browsers don't send Lax cookies.
+    // The CSRF cookie has to be SameSite=None then; `state`, the origin check and the
+    // cookie's short lifetime still tie the callback to this login attempt.
+    let same_site = match response_mode {
+        ResponseMode::FormPost => SameSite::None,
+        _ => SameSite::Lax,
+    };
+    let mut headers = HeaderMap::new();
+    SetCookie::private(
+        &cookie_keys,
+        CSRF_COOKIE_NAME,
+        &csrf_id,
+        CSRF_COOKIE_MAX_AGE,
+    )?
+    .same_site(same_site)
+    .append_to(&mut headers)?;
+
+    Ok((headers, Redirect::to(auth_url.as_str())).into_response())
+}
+
+#[derive(Template)]
+#[template(path = "protected.j2")]
+struct ProtectedTemplate {
+    layout: Layout,
+    user: User,
+    token_info: String,
+}
+
+// Valid user session required. If there is none, redirect to the auth page
+async fn protected(
+    user: User,
+    layout: Layout,
+    access_token: Option<AccessToken>,
+) -> Result<Response, AppError> {
+    let token_info = match access_token {
+        Some(token) => format!("Access token expires at {}", token.expires_at),
+        None => "No access token".to_string(),
+    };
+    let template = ProtectedTemplate {
+        layout: layout.clone(),
+        user,
+        token_info,
+    };
+    Ok(layout.render(&template)?)
+}
+
+#[derive(Template)]
+#[template(path = "profile.j2")]
+struct ProfileTemplate {
+    layout: Layout,
+    account: users::Account,
+    identities: Vec<users::Identity>,
+    // Providers the account can still be linked with.
+    unlinked_providers: Vec<String>,
+}
+
+// The local account and its linked identities, as a page; `/me` has the same as JSON.
+async fn profile(
+    user: User,
+    layout: Layout,
+    State(users): State<UserStore>,
+    State(providers): State<Providers>,
+) -> Result<Response, AppError> {
+    let account = users
+        .get(user.user_id)
+        .await?
+        .context("account not found")?;
+    let identities = users.identities(user.user_id).await?;
+    let unlinked_providers = providers
+        .names()
+        .into_iter()
+        .filter(|name| !identities.iter().any(|identity| &identity.provider == name))
+        .collect();
+    let template = ProfileTemplate {
+        layout: layout.clone(),
+        account,
+        identities,
+        unlinked_providers,
+    };
+    Ok(layout.render(&template)?)
+}
+
+// The local account with all its linked identities, as JSON.
+async fn me(user: Option<User>, State(users): State<UserStore>) -> Result<Response, AppError> {
+    let Some(user) = user else {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    };
+    let Some(account) = users.get(user.user_id).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let identities = users.identities(user.user_id).await?;
+    Ok(Json(json!({
+        "id": account.id,
+        "email": account.email,
+        "name": account.name,
+        "picture": account.picture,
+        "created_at": account.created_at,
+        "last_login_at": account.last_login_at,
+        "logged_in_with": user.provider,
+        "identities": identities,
+    }))
+    .into_response())
+}
+
+#[derive(Template)]
+#[template(path = "admin.j2")]
+struct AdminTemplate {
+    layout: Layout,
+    sessions: Vec<sessions::SessionRecord>,
+}
+
+// Every active session, for admins.
+async fn admin(
+    _: RequireRole<Admin>,
+    layout: Layout,
+    State(users): State<UserStore>,
+) -> Result<Response, AppError> {
+    let sessions = users.active_sessions().await?;
+    let template = AdminTemplate {
+        layout: layout.clone(),
+        sessions,
+    };
+    Ok(layout.render(&template)?)
+}
+
+// A form POST with the session's form token, which other sites can't know.
+async fn admin_revoke_session(
+    RequireRole { user, .. }: RequireRole<Admin>,
+    Path(session_id): Path<String>,
+    State(state): State<AppState>,
+    TypedHeader(cookies): TypedHeader<headers::Cookie>,
+    Form(form): Form<TokenForm>,
+) -> Result<Response, AppError> {
+    let AppState {
+        store,
+        users,
+        cookie_keys,
+        audit,
+        ..
+    } = &state;
+    if let Err(e) =
+        layout::check_request_form_token(store, cookie_keys, &cookies, &form.csrf_token).await
+    {
+        let flash = Flash::error(format!("Revoke failed: {}", e));
+        return redirect_with_flash(cookie_keys, flash, "/admin");
+    }
+    sessions::revoke_session(store, users, &session_id).await?;
+    let event = AuthEvent::new(AuthEventKind::SessionRevoked)
+        .user_id(user.user_id)
+        .session(&session_id)
+        .reason("revoked by admin");
+    audit.record(event).await;
+    Ok(Redirect::to("/admin").into_response())
+}
+
+// Recent audit events as JSON, e.g. `/admin/events?kind=csrf_failure&limit=20`.
+async fn admin_events(
+    _: RequireRole<Admin>,
+    Query(filter): Query<AuthEventFilter>,
+    State(audit): State<AuditLog>,
+) -> Result<Response, AppError> {
+    if !audit.is_persistent() {
+        return Ok((StatusCode::NOT_FOUND, "Audit events are not persisted").into_response());
+    }
+    Ok(Json(audit.recent(&filter).await?).into_response())
+}
+
+#[derive(Template)]
+#[template(path = "sessions.j2")]
+struct SessionsTemplate {
+    layout: Layout,
+    current_session_id: String,
+    sessions: Vec<sessions::SessionRecord>,
+}
+
+// The logged in user's own sessions, one per device.
+async fn user_sessions(
+    user: User,
+    layout: Layout,
+    State(users): State<UserStore>,
+    State(cookie_keys): State<CookieKeys>,
+    TypedHeader(cookies): TypedHeader<headers::Cookie>,
+) -> Result<Response, AppError> {
+    let cookie = cookie_keys
+        .get(&cookies, COOKIE_NAME)
+        .context("unexpected error getting cookie name")?;
+    let current_session_id = Session::id_from_cookie_value(&cookie)?;
+    let sessions = users.user_sessions(user.user_id).await?;
+    let template = SessionsTemplate {
+        layout: layout.clone(),
+        current_session_id,
+        sessions,
+    };
+    Ok(layout.render(&template)?)
+}
+
+// Only the user's own sessions can be revoked here. Revoking the current one logs out.
+async fn revoke_user_session(
+    user: User,
+    Path(session_id): Path<String>,
+    State(state): State<AppState>,
+    TypedHeader(cookies): TypedHeader<headers::Cookie>,
+    Form(form): Form<TokenForm>,
+) -> Result<Response, AppError> {
+    let AppState {
+        store,
+        users,
+        cookie_keys,
+        audit,
+        ..
+    } = &state;
+    if let Err(e) =
+        layout::check_request_form_token(store, cookie_keys, &cookies, &form.csrf_token).await
+    {
+        let flash = Flash::error(format!("Revoke failed: {}", e));
+        return redirect_with_flash(cookie_keys, flash, "/sessions");
+    }
+    if !users.is_session_active(&session_id, user.user_id).await? {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    sessions::revoke_session(store, users, &session_id).await?;
+    let event = AuthEvent::new(AuthEventKind::SessionRevoked)
+        .user_id(user.user_id)
+        .session(&session_id)
+        .reason("revoked by user");
+    audit.record(event).await;
+
+    let current = cookie_keys
+        .get(&cookies, COOKIE_NAME)
+        .map(|cookie| Session::id_from_cookie_value(&cookie))
+        .transpose()?;
+    if current.as_deref() == Some(session_id.as_str()) {
+        return Ok((clear_session_cookie()?, Redirect::to("/")).into_response());
+    }
+    Ok(Redirect::to("/sessions").into_response())
+}
+
+// Log out everywhere, this device included.
+async fn revoke_all_sessions(
+    user: User,
+    State(store): State<AppSessionStore>,
+    State(users): State<UserStore>,
+    State(cookie_keys): State<CookieKeys>,
+    State(audit): State<AuditLog>,
+    TypedHeader(cookies): TypedHeader<headers::Cookie>,
+    Form(form): Form<TokenForm>,
+) -> Result<Response, AppError> {
+    if let Err(e) =
+        layout::check_request_form_token(&store, &cookie_keys, &cookies, &form.csrf_token).await
+    {
+        let flash = Flash::error(format!("Logout failed: {}", e));
+        return redirect_with_flash(&cookie_keys, flash, "/sessions");
+    }
+    sessions::revoke_user_sessions(&store, &users, user.user_id).await?;
+    let event = AuthEvent::new(AuthEventKind::SessionRevoked)
+        .user_id(user.user_id)
+        .reason("logged out everywhere");
+    audit.record(event).await;
+    Ok((clear_session_cookie()?, Redirect::to("/")).into_response())
+}
+
+fn clear_session_cookie() -> Result<HeaderMap, AppError> {
+    let mut headers = HeaderMap::new();
+    SetCookie::removal(COOKIE_NAME).append_to(&mut headers)?;
+    Ok(headers)
+}
+
+// Back to `to`, with a message for the page there.
+fn redirect_with_flash(
+    cookie_keys: &CookieKeys,
+    flash: Flash,
+    to: &str,
+) -> Result<Response, AppError> {
+    let mut headers = HeaderMap::new();
+    flash.append_to(cookie_keys, &mut headers)?;
+    Ok((headers, Redirect::to(to)).into_response())
+}
+
+#[derive(Template)]
+#[template(path = "logout.j2")]
+struct LogoutTemplate {
+    layout: Layout,
+}
+
+// Asks before logging out; the logout itself is the POST of the form on this page.
+async fn logout_page(layout: Layout) -> Result<Response, AppError> {
+    let template = LogoutTemplate {
+        layout: layout.clone(),
+    };
+    Ok(layout.render(&template)?)
+}
+
+// The POST forms of a logged in user, which carry nothing but the form token.
+#[derive(Debug, Deserialize)]
+struct TokenForm {
+    #[serde(default)]
+    csrf_token: String,
+}
+
+// A failed logout keeps the session and says why on the start page.
+async fn logout(
+    user: Option<User>,
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    request_headers: HeaderMap,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    Form(form): Form<TokenForm>,
+) -> Result<Response, AppError> {
+    let cookie =
+        cookies.and_then(|TypedHeader(cookies)| state.cookie_keys.get(&cookies, COOKIE_NAME));
+    let client = SessionOrigin {
+        user_agent: user_agent(&request_headers),
+        ip: client_ip(connect_info),
+    };
+    match end_session(user, &state, cookie, &form, client).await {
+        Ok(location) => Ok((clear_session_cookie()?, Redirect::to(&location)).into_response()),
+        Err(e) => {
+            tracing::error!("Logout failed: {:#}", e.0);
+            let flash = Flash::error(format!("Logout failed: {}", e.0));
+            redirect_with_flash(&state.cookie_keys, flash, "/")
+        }
+    }
+}
+
+// Ends the session here and, as configured, at the provider. Returns where the browser
+// goes next: the provider's logout page or the start page.
+async fn end_session(
+    user: Option<User>,
+    state: &AppState,
+    cookie: Option<String>,
+    form: &TokenForm,
+    client: SessionOrigin,
+) -> Result<String, AppError> {
+    let AppState {
+        store,
+        providers,
+        cipher,
+        users,
+        audit,
+        backend,
+        ..
+    } = state;
+    // A missing or tampered cookie has nothing to log out of.
+    let Some(cookie) = cookie else {
+        return Ok("/".to_string());
+    };
+    let session_id = Session::id_from_cookie_value(&cookie)?;
+    let Some(session) = store
+        .load_session(cookie)
+        .await
+        .context("failed to load session")?
+    else {
+        users.forget_session(&session_id).await?;
+        return Ok("/".to_string());
+    };
+    layout::check_form_token(&session, &form.csrf_token)?;
+
+    // What the provider side of the logout needs goes with the session.
+    let tokens = session.get::<StoredTokens>("tokens");
+    let id_token = session.get::<String>("id_token");
+    users.forget_session(&session_id).await?;
+    store
+        .destroy_session(session)
+        .await
+        .context("failed to destroy session")?;
+
+    let Some(user) = user else {
+        return Ok("/".to_string());
+    };
+    let event = AuthEvent::new(AuthEventKind::Logout)
+        .user_id(user.user_id)
+        .provider(&user.provider)
+        .session(&session_id)
+        .client(client.ip, client.user_agent);
+    audit.record(event).await;
+
+    let Some(provider) = providers.get(&user.provider) else {
+        return Ok("/".to_string());
+    };
+    if let (true, Some(tokens)) = (provider.config().logout.revoke_tokens, &tokens) {
+        logout::revoke_tokens(provider.as_ref(), backend, cipher, tokens).await;
+    }
+    match logout::end_session_url(provider.as_ref(), id_token.as_deref())? {
+        Some(url) => Ok(url.to_string()),
+        None => Ok("/".to_string()),
+    }
+}
+
+async fn delete_session_from_store(
+    cookie: &str,
+    store: &impl SessionStore,
+) -> Result<(), AppError> {
+    // No session active is fine too.
+    if let Some(session) = store
+        .load_session(cookie.to_string())
+        .await
+        .context("failed to load session")?
+    {
+        store
+            .destroy_session(session)
+            .await
+            .context("failed to destroy session")?;
+    }
+    Ok(())
+}
+
+// The authorization response, from the query string or a form_post body.
+// `code` and `id_token` depend on the response type.
+#[derive(Debug, Deserialize)]
+struct AuthRequest {
+    code: Option<String>,
+    state: String,
+    id_token: Option<String>,
+}
+
+async fn login_authorized(
+    Query(query): Query<AuthRequest>,
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    TypedHeader(cookies): TypedHeader<headers::Cookie>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let ip = client_ip(connect_info);
+    authorized(query, ResponseMode::Query, &state, ip, cookies, headers).await
+}
+
+async fn post_login_authorized(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    TypedHeader(cookies): TypedHeader<headers::Cookie>,
+    headers: HeaderMap,
+    Form(form): Form<AuthRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let ip = client_ip(connect_info);
+    authorized(form, ResponseMode::FormPost, &state, ip, cookies, headers).await
+}
+
+// Audits the outcome of `complete_login`; failed CSRF checks are recorded on their own.
+// The login mode of the attempt, once loaded, decides how the result is reported.
+async fn authorized(
+    auth_response: AuthRequest,
+    response_mode: ResponseMode,
+    state: &AppState,
+    ip: Option<String>,
+    cookies: headers::Cookie,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let user_agent = user_agent(&headers);
+    let csrf = match state.cookie_keys.get(&cookies, CSRF_COOKIE_NAME) {
+        Some(csrf_id) => csrf_checks(&csrf_id, &state.store, &auth_response, headers.clone())
+            .await
+            .map(|csrf_data| (csrf_id, csrf_data)),
+        None => Err((anyhow::anyhow!("No session cookie found").into(), None)),
+    };
+    let (csrf_id, csrf_data) = match csrf {
+        Ok(csrf) => csrf,
+        Err((e, csrf_data)) => {
+            tracing::error!("Login failed: {:#}", e.0);
+            let event = AuthEvent::new(AuthEventKind::CsrfFailure)
+                .client(ip, user_agent)
+                .reason(format!("{:#}", e.0));
+            state.audit.record(event).await;
+            return match csrf_data {
+                None
+                | Some(CsrfData {
+                    login_mode: LoginMode::Redirect,
+                    ..
+                }) => {
+                    let flash = Flash::error(format!("Login failed: {}", e.0));
+                    redirect_with_flash(&state.cookie_keys, flash, "/")
+                }
+                Some(CsrfData {
+                    provider,
+                    login_mode: LoginMode::Popup { opener_origin },
+                    ..
+                }) => {
+                    let result = LoginResult::failure(&provider);
+                    Ok((
+                        StatusCode::UNAUTHORIZED,
+                        popup_result(result, opener_origin)?,
+                    )
+                        .into_response())
+                }
+            };
+        }
+    };
+
+    let provider_name = csrf_data.provider.clone();
+    let login_mode = csrf_data.login_mode.clone();
+    let result = complete_login(
+        auth_response,
+        response_mode,
+        state,
+        ip.clone(),
+        &csrf_id,
+        csrf_data,
+        headers,
+    )
+    .await;
+    let event = match &result {
+        Ok((user_id, session_id, _)) => AuthEvent::new(AuthEventKind::LoginSuccess)
+            .user_id(*user_id)
+            .session(session_id),
+        Err(e) => AuthEvent::new(AuthEventKind::LoginFailure).reason(format!("{:#}", e.0)),
+    };
+    state
+        .audit
+        .record(event.provider(&provider_name).client(ip, user_agent))
+        .await;
+
+    match (login_mode, result) {
+        (LoginMode::Redirect, Ok((_, _, headers))) => {
+            Ok((headers, Redirect::to("/")).into_response())
+        }
+        (LoginMode::Redirect, Err(e)) if e.0.is::<DomainRejected>() => {
+            tracing::warn!("Login rejected: {:#}", e.0);
+            domain_rejected(&provider_name, e)
+        }
+        (LoginMode::Redirect, Err(e)) => {
+            tracing::error!("Login failed: {:#}", e.0);
+            let flash = Flash::error(format!("Login with {} failed: {}", provider_name, e.0));
+            redirect_with_flash(&state.cookie_keys, flash, "/")
+        }
+        (LoginMode::Popup { opener_origin }, Ok((_, _, headers))) => {
+            let result = LoginResult::success(&provider_name);
+            Ok((headers, popup_result(result, opener_origin)?).into_response())
+        }
+        (LoginMode::Popup { opener_origin }, Err(e)) => {
+            tracing::error!("Popup login failed: {:#}", e.0);
+            let result = if e.0.is::<DomainRejected>() {
+                LoginResult::rejected(&provider_name)
+            } else {
+                LoginResult::failure(&provider_name)
+            };
+            Ok((
+                StatusCode::UNAUTHORIZED,
+                popup_result(result, opener_origin)?,
+            )
+                .into_response())
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "domain_rejected.j2")]
+struct DomainRejectedTemplate {
+    layout: Layout,
+    provider: String,
+    rejected: DomainRejected,
+}
+
+// Explains a login the allowlist turned away, instead of a flash on the start page.
+fn domain_rejected(provider: &str, e: AppError) -> Result<Response, AppError> {
+    let rejected =
+        e.0.downcast::<DomainRejected>()
+            .map_err(|e| anyhow::anyhow!("not a rejected login: {:#}", e))?;
+    let template = DomainRejectedTemplate {
+        layout: Layout::default(),
+        provider: provider.to_string(),
+        rejected,
+    };
+    Ok((StatusCode::FORBIDDEN, Html(template.render()?)).into_response())
+}
+
+#[derive(Template)]
+#[template(path = "popup_result.j2")]
+struct PopupResultTemplate {
+    result: String,
+    opener_origin: String,
+    success: bool,
+}
+
+// The page the popup ends on; it posts `result` to the opener and closes.
+fn popup_result(result: LoginResult, opener_origin: String) -> Result<Html<String>, AppError> {
+    let template = PopupResultTemplate {
+        success: result.error.is_none(),
+        result: serde_json::to_string(&result)?,
+        opener_origin,
+    };
+    Ok(Html(template.render()?))
+}
+
+// The rest of the callback, once the CSRF checks passed. Returns the new user id and
+// session id, and the cookies to set.
+async fn complete_login(
+    auth_response: AuthRequest,
+    response_mode: ResponseMode,
+    state: &AppState,
+    ip: Option<String>,
+    csrf_id: &str,
+    csrf_data: CsrfData,
+    headers: HeaderMap,
+) -> Result<(i64, String, HeaderMap), AppError> {
+    let AppState {
+        store,
+        providers,
+        cipher,
+        cookie_keys,
+        users,
+        role_grants,
+        domains,
+        backend,
+        ..
+    } = state;
+    // The response has to arrive the way we asked for it.
+    if csrf_data.response_mode != response_mode.as_str() {
+        return Err(anyhow::anyhow!("Unexpected response mode {}", response_mode.as_str()).into());
+    }
+    let provider = providers
+        .get(&csrf_data.provider)
+        .ok_or_else(|| anyhow::anyhow!("Unknown provider {}", csrf_data.provider))?;
+    let params = provider.params();
+
+    validate_origin(&headers, &params.auth_url).await?;
+
+    let mut headers = HeaderMap::new();
+    SetCookie::removal(CSRF_COOKIE_NAME).append_to(&mut headers)?;
+
+    delete_session_from_store(csrf_id, store).await?;
+
+    let response_type = ResponseType::parse(&csrf_data.response_type)?;
+    let client_id = params.client_id.clone();
+    let issuers = provider.issuers();
+
+    // Hybrid and implicit flows deliver an ID token with the authorization response.
+    let front_channel_claims = match auth_response.id_token.as_deref() {
+        Some(id_token) if response_type.has_id_token() => Some(
+            id_token::verify_id_token(
+                id_token,
+                provider.jwks(),
+                &issuers,
+                &client_id,
+                &csrf_data.nonce,
+            )
+            .await?,
+        ),
+        Some(_) => return Err(anyhow::anyhow!("Unexpected id_token in response").into()),
+        None if response_type.has_id_token() => {
+            return Err(anyhow::anyhow!("No id_token in response").into())
+        }
+        None => None,
+    };
+
+    let (claims, id_token, tokens) = match auth_response.code {
+        Some(code) if response_type.has_code() => {
+            if let Some(claims) = &front_channel_claims {
+                id_token::verify_c_hash(claims, &code)?;
+            }
+            let token_response = backend
+                .exchange_code(&params, code, csrf_data.pkce_verifier.clone())
+                .await?;
+            let id_token = token_response
+                .id_token
+                .clone()
+                .context("token response has no id_token")?;
+
+            let claims = id_token::verify_id_token(
+                &id_token,
+                provider.jwks(),
+                &issuers,
+                &client_id,
+                &csrf_data.nonce,
+            )
+            .await?;
+            // Both ID tokens must be about the same user (OIDC Core 3.3.3.6).
+            if let Some(front) = &front_channel_claims {
+                if front.iss != claims.iss || front.sub != claims.sub {
+                    return Err(anyhow::anyhow!("ID token subject mismatch").into());
+                }
+            }
+            let tokens = StoredTokens::seal(cipher, provider.name(), &token_response)?;
+            (claims, id_token, Some(tokens))
+        }
+        Some(_) => return Err(anyhow::anyhow!("Unexpected code in response").into()),
+        None if response_type.has_code() => {
+            return Err(anyhow::anyhow!("No code in response").into())
+        }
+        None => (
+            front_channel_claims.context("No id_token in response")?,
+            auth_response.id_token.context("No id_token in response")?,
+            None,
+        ),
+    };
+    // Before anything is stored, so a rejected login leaves no account behind.
+    domains.rules().check(provider.config().kind, &claims)?;
+    let sid = claims.sid.clone();
+    let mut user_data = provider.map_claims(claims);
+    user_data.user_id = users.login(&user_data, csrf_data.link_user_id).await?;
+    users
+        .sync_config_roles(user_data.user_id, &role_grants.roles_for(&user_data))
+        .await?;
+    let user_id = user_data.user_id;
+    let provider_name = user_data.provider.clone();
+
+    let max_age = COOKIE_MAX_AGE;
+    let expires_at = Utc::now() + Duration::seconds(max_age);
+    let cookie = create_and_store_session(user_data, &id_token, tokens, store, expires_at).await?;
+    let session_id = Session::id_from_cookie_value(&cookie)?;
+    let origin = SessionOrigin {
+        user_agent: Some(csrf_data.user_agent.clone()),
+        ip,
+    };
+    users
+        .record_session(&session_id, user_id, &provider_name, &origin, expires_at)
+        .await?;
+    if let Some(sid) = &sid {
+        users.record_sid(&session_id, sid).await?;
+    }
+    SetCookie::private(cookie_keys, COOKIE_NAME, &cookie, max_age)?.append_to(&mut headers)?;
+
+    Ok((user_id, session_id, headers))
+}
+
+fn user_agent(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_string)
+}
+
+fn client_ip(connect_info: Option<ConnectInfo<SocketAddr>>) -> Option<String> {
+    connect_info.map(|ConnectInfo(addr)| addr.ip().to_string())
+}
+
+async fn validate_origin(headers: &HeaderMap, auth_url: &str) -> Result<(), AppError> {
+    let parsed_url = Url::parse(auth_url).expect("Invalid URL");
+    let scheme = parsed_url.scheme();
+    let host = parsed_url.host_str().unwrap_or_default();
+    let port = parsed_url
+        .port()
+        .map_or("".to_string(), |p| format!(":{}", p));
+    let expected_origin = format!("{}://{}{}", scheme, host, port);
+
+    let origin = headers
+        .get("Origin")
+        .or_else(|| headers.get("Referer"))
+        .and_then(|h| h.to_str().ok());
+
+    match origin {
+        Some(origin) if origin.starts_with(&expected_origin) => Ok(()),
+        _ => Err(anyhow::anyhow!("Invalid origin").into()),
+    }
+}
+
+// On failure, also returns the stored login attempt if it could be loaded, so that a
+// popup login reports the failure to its opener.
+async fn csrf_checks(
+    csrf_id: &str,
+    store: &impl SessionStore,
+    auth_response: &AuthRequest,
+    headers: HeaderMap,
+) -> Result<CsrfData, (AppError, Option<CsrfData>)> {
+    let csrf_data = load_csrf_data(csrf_id, store)
+        .await
+        .map_err(|e| (e, None))?;
+    match check_csrf_data(&csrf_data, auth_response, headers) {
+        Ok(()) => Ok(csrf_data),
+        Err(e) => Err((e, Some(csrf_data))),
+    }
+}
+
+async fn load_csrf_data(csrf_id: &str, store: &impl SessionStore) -> Result<CsrfData, AppError> {
+    let session = store
+        .load_session(csrf_id.to_string())
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("CSRF Session not found"))?;
+    let csrf_data: CsrfData = session
+        .get("csrf_data")
+        .ok_or_else(|| anyhow::anyhow!("No CSRF data in session"))?;
+    Ok(csrf_data)
+}
+
+fn check_csrf_data(
+    csrf_data: &CsrfData,
+    auth_response: &AuthRequest,
+    headers: HeaderMap,
+) -> Result<(), AppError> {
+    if auth_response.state != csrf_data.csrf_token {
+        return Err(anyhow::anyhow!("CSRF token mismatch").into());
+    }
+    if Utc::now() > csrf_data.expires_at {
+        return Err(anyhow::anyhow!("CSRF token expired").into());
+    }
+    let user_agent = headers
+        .get(axum::http::header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("Unknown")
+        .to_string();
+    if user_agent != csrf_data.user_agent {
+        // return Err(anyhow::anyhow!("User agent mismatch").into());
+        return Err(anyhow::anyhow!("Funny thing happend").into());
+    }
+    Ok(())
+}
+
+// The ID token is kept as the `id_token_hint` for RP-initiated logout.
+async fn create_and_store_session(
+    user_data: User,
+    id_token: &str,
+    tokens: Option<StoredTokens>,
+    store: &impl SessionStore,
+    expires_at: DateTime<Utc>,
+) -> Result<String, AppError> {
+    let mut session = Session::new();
+    session
+        .insert("user", &user_data)
+        .context("failed in inserting serialized value into session")?;
+    session
+        .insert("id_token", id_token)
+        .context("failed in inserting serialized value into session")?;
+    session
+        .insert(layout::FORM_TOKEN_KEY, random_string(32))
+        .context("failed in inserting serialized value into session")?;
+    if let Some(tokens) = tokens {
+        session
+            .insert("tokens", &tokens)
+            .context("failed in inserting serialized value into session")?;
+    }
+    session.set_expiry(expires_at);
+    let session_id = store
+        .store_session(session)
+        .await
+        .context("failed to store session")?
+        .context("unexpected error retrieving cookie value")?;
+    Ok(session_id)
+}
+
+fn pkce_challenge_s256(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+fn random_string(len: usize) -> String {
+    thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+pub struct AuthRedirect;
+
+impl IntoResponse for AuthRedirect {
+    fn into_response(self) -> Response {
+        // Redirect::temporary("/auth/google").into_response()
+        Redirect::temporary("/").into_response()
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for User
+where
+    AppSessionStore: FromRef<S>,
+    CookieKeys: FromRef<S>,
+    UserStore: FromRef<S>,
+    S: Send + Sync,
+{
+    // If anything goes wrong or no session is found, redirect to the auth page
+    type Rejection = AuthRedirect;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let store = AppSessionStore::from_ref(state);
+
+        let cookies = parts
+            .extract::<TypedHeader<headers::Cookie>>()
+            .await
+            .map_err(|e| match *e.name() {
+                header::COOKIE => match e.reason() {
+                    TypedHeaderRejectionReason::Missing => AuthRedirect,
+                    _ => panic!("unexpected error getting Cookie header(s): {e}"),
+                },
+                _ => panic!("unexpected error getting cookies: {e}"),
+            })?;
+        let session_cookie = CookieKeys::from_ref(state)
+            .get(&cookies, COOKIE_NAME)
+            .ok_or(AuthRedirect)?;
+
+        // Retrieve session from store
+        let session = store
+            .load_session(session_cookie)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to load session: {:#}", e);
+                AuthRedirect
+            })?
+            .ok_or(AuthRedirect)?;
+
+        // Retrieve user data from session
+        let user = session.get::<User>("user").ok_or(AuthRedirect)?;
+
+        // Revoked sessions are dropped from the index, even if the store still has them.
+        let users = UserStore::from_ref(state);
+        match users.is_session_active(session.id(), user.user_id).await {
+            Ok(true) => Ok(user),
+            Ok(false) => Err(AuthRedirect),
+            Err(e) => {
+                tracing::error!("Failed to look up session: {:#}", e);
+                Err(AuthRedirect)
+            }
+        }
+    }
+}
+
+// Use anyhow, define error and enable '?'
+// For a simplified example of using anyhow in axum check /examples/anyhow-error-response
+#[derive(Debug)]
+struct AppError(anyhow::Error);
+
+#[derive(Template)]
+#[template(path = "error.j2")]
+struct ErrorTemplate {
+    layout: Layout,
+    message: String,
+}
+
+// Tell axum how to convert `AppError` into a response: an error page in the site's layout.
+// Login and logout failures don't get here; they go back to the start page with a flash.
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        tracing::error!("Application error: {:#}", self.0);
+
+        let message = self.0.to_string();
+        let template = ErrorTemplate {
+            layout: Layout::default(),
+            message: message.clone(),
+        };
+        match template.render() {
+            Ok(html) => (StatusCode::INTERNAL_SERVER_ERROR, Html(html)).into_response(),
+            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, message).into_response(),
+        }
+    }
+}
+
+// This enables using `?` on functions that return `Result<_, anyhow::Error>` to turn them into
+// `Result<_, AppError>`. That way you don't need to do that manually.
+impl<E> From<E> for AppError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        Self(err.into())
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    Form, Json,
+};
+use http::{header, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+
+use crate::audit::{AuthEvent, AuthEventKind};
+use crate::backend::Backend;
+use crate::id_token::{self, LogoutTokenClaims};
+use crate::provider::OidcProvider;
+use crate::tokens::{StoredTokens, TokenCipher};
+use crate::users::UserStore;
+use crate::{sessions, AppState};
+
+// What logging out does at the provider, on top of ending our own session. All off by default.
+#[derive(Debug, Clone, Default)]
+pub struct LogoutConfig {
+    // Revoke the session's refresh and access tokens (RFC 7009).
+    pub revoke_tokens: bool,
+    // Send the browser on to the provider's `end_session_endpoint`, which sends it back here
+    // (OpenID Connect RP-Initiated Logout). Off when unset.
+    pub post_logout_redirect_uri: Option<String>,
+    // Accept logout tokens at `/auth/:provider/backchannel_logout`
+    // (OpenID Connect Back-Channel Logout).
+    pub backchannel: bool,
+}
+
+impl LogoutConfig {
+    // Reads _REVOKE_TOKENS, _RP_LOGOUT, _POST_LOGOUT_REDIRECT_URI (`{origin}/` by default)
+    // and _BACKCHANNEL_LOGOUT with the provider's `var`.
+    pub(crate) fn from_env(var: impl Fn(&str) -> Option<String>, origin: &str) -> Self {
+        let flag = |key: &str| var(key).is_some_and(|v| v == "true" || v == "1");
+        let post_logout_redirect_uri = flag("RP_LOGOUT")
+            .then(|| var("POST_LOGOUT_REDIRECT_URI").unwrap_or_else(|| format!("{}/", origin)));
+        Self {
+            revoke_tokens: flag("REVOKE_TOKENS"),
+            post_logout_redirect_uri,
+            backchannel: flag("BACKCHANNEL_LOGOUT"),
+        }
+    }
+}
+
+// Failures are only logged: the local session is gone either way.
+pub(crate) async fn revoke_tokens(
+    provider: &dyn OidcProvider,
+    backend: &Backend,
+    cipher: &TokenCipher,
+    tokens: &StoredTokens,
+) {
+    let tokens = match tokens.revocable(cipher) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            tracing::warn!("Failed to decrypt tokens to revoke: {:#}", e);
+            return;
+        }
+    };
+    let params = provider.params();
+    for token in tokens {
+        let type_hint = token.type_hint().to_string();
+        match backend.revoke(&params, token).await {
+            Ok(()) => tracing::debug!("Revoked {} at provider {}", type_hint, provider.name()),
+            Err(e) => tracing::warn!(
+                "Failed to revoke {} at provider {}: {:#}",
+                type_hint,
+                provider.name(),
+                e
+            ),
+        }
+    }
+}
+
+// The provider's logout page, if RP-initiated logout is on and the provider has one.
+// The ID token from the login tells the provider which session to end.
+pub(crate) fn end_session_url(
+    provider: &dyn OidcProvider,
+    id_token: Option<&str>,
+) -> Result<Option<Url>> {
+    let config = provider.config();
+    let Some(post_logout_redirect_uri) = &config.logout.post_logout_redirect_uri else {
+        return Ok(None);
+    };
+    let Some(endpoint) = &provider.metadata().end_session_endpoint else {
+        tracing::warn!("Provider {} has no end_session_endpoint", provider.name());
+        return Ok(None);
+    };
+    let mut url = Url::parse(endpoint)
+        .with_context(|| format!("invalid end_session_endpoint {}", endpoint))?;
+    {
+        let mut query = url.query_pairs_mut();
+        if let Some(id_token) = id_token {
+            query.append_pair("id_token_hint", id_token);
+        }
+        query
+            .append_pair("client_id", &config.client_id)
+            .append_pair("post_logout_redirect_uri", post_logout_redirect_uri);
+    }
+    Ok(Some(url))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct BackchannelLogout {
+    logout_token: String,
+}
+
+// The provider tells us a session there has ended, and every matching session here is
+// revoked. Responses must not be cached; a rejected token is a 400 (Back-Channel Logout 2.8).
+pub(crate) async fn backchannel_logout(
+    Path(provider_name): Path<String>,
+    State(state): State<AppState>,
+    Form(form): Form<BackchannelLogout>,
+) -> Response {
+    let no_store = [(header::CACHE_CONTROL, "no-store")];
+    match logout_sessions(&state, &provider_name, &form.logout_token).await {
+        Ok(()) => (StatusCode::OK, no_store).into_response(),
+        Err(e) => {
+            tracing::warn!("Rejected back-channel logout: {:#}", e);
+            let error = json!({"error": "invalid_request", "error_description": e.to_string()});
+            (StatusCode::BAD_REQUEST, no_store, Json(error)).into_response()
+        }
+    }
+}
+
+async fn logout_sessions(state: &AppState, provider_name: &str, logout_token: &str) -> Result<()> {
+    let provider = state
+        .providers
+        .get(provider_name)
+        .filter(|provider| provider.config().logout.backchannel)
+        .ok_or_else(|| {
+            anyhow::anyhow!("Back-channel logout is not enabled for {}", provider_name)
+        })?;
+    let claims = id_token::verify_logout_token(
+        logout_token,
+        provider.jwks(),
+        &provider.issuers(),
+        &provider.config().client_id,
+    )
+    .await?;
+
+    for (session_id, user_id) in state
+        .users
+        .logout_token_sessions(provider.name(), &claims)
+        .await?
+    {
+        sessions::revoke_session(&state.store, &state.users, &session_id).await?;
+        let event = AuthEvent::new(AuthEventKind::SessionRevoked)
+            .user_id(user_id)
+            .provider(provider.name())
+            .session(&session_id)
+            .reason("back-channel logout");
+        state.audit.record(event).await;
+    }
+    Ok(())
+}
+
+impl UserStore {
+    // The provider's session id (`sid`) of each of our sessions that has one.
+    pub(crate) async fn migrate_logout(&self) -> sqlx::Result<()> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS session_sids (
+                session_id TEXT PRIMARY KEY NOT NULL
+                    REFERENCES user_sessions(id) ON DELETE CASCADE,
+                sid TEXT NOT NULL
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS session_sids_sid ON session_sids (sid)")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn record_sid(&self, session_id: &str, sid: &str) -> Result<()> {
+        sqlx::query("INSERT INTO session_sids (session_id, sid) VALUES (?, ?)")
+            .bind(session_id)
+            .bind(sid)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // The sessions a logout token is about, as (session id, user id): logged in with this
+    // provider, matching `sub` and `sid` where given, and older than the token, so that
+    // replaying it can't end a later login.
+    pub async fn logout_token_sessions(
+        &self,
+        provider: &str,
+        claims: &LogoutTokenClaims,
+    ) -> Result<Vec<(String, i64)>> {
+        let sessions = sqlx::query_as(
+            "SELECT DISTINCT s.id, s.user_id FROM user_sessions s
+             JOIN identities i ON i.user_id = s.user_id AND i.provider = s.provider
+             LEFT JOIN session_sids p ON p.session_id = s.id
+             WHERE s.provider = ? AND s.created_at <= ?
+               AND (? IS NULL OR i.subject = ?)
+               AND (? IS NULL OR p.sid = ?)",
+        )
+        .bind(provider)
+        .bind(claims.iat)
+        .bind(&claims.sub)
+        .bind(&claims.sub)
+        .bind(&claims.sid)
+        .bind(&claims.sid)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(sessions)
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::env;
+use url::Url;
+
+// How the browser comes back from the provider, chosen per login with `?mode=`.
+// `redirect` (the default) lands on "/" like any full page login. `popup` needs
+// `&origin=`, the origin of the opener window: the callback page then reports the result
+// to it with `postMessage` and closes itself.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum LoginMode {
+    #[default]
+    Redirect,
+    Popup {
+        opener_origin: String,
+    },
+}
+
+// The query string of `/auth/:provider`.
+#[derive(Debug, Default, Deserialize)]
+pub struct LoginQuery {
+    pub mode: Option<String>,
+    pub origin: Option<String>,
+    // Passed on to the provider to preselect the account.
+    pub login_hint: Option<String>,
+}
+
+impl LoginMode {
+    pub fn from_query(query: &LoginQuery, origins: &PopupOrigins) -> Result<Self> {
+        match query.mode.as_deref() {
+            None | Some("redirect") => Ok(LoginMode::Redirect),
+            Some("popup") => {
+                let origin = query
+                    .origin
+                    .as_deref()
+                    .context("popup mode needs the opener origin")?;
+                let opener_origin = origins
+                    .check(origin)
+                    .with_context(|| format!("origin {} is not allowed", origin))?;
+                Ok(LoginMode::Popup { opener_origin })
+            }
+            Some(mode) => Err(anyhow::anyhow!("Unknown login mode {}", mode)),
+        }
+    }
+}
+
+// Origins allowed to open the login popup: ORIGIN, plus any in POPUP_ORIGINS (comma
+// separated). The login result is posted to the opener only, so this decides who learns
+// about it.
+#[derive(Debug, Clone)]
+pub struct PopupOrigins(Vec<String>);
+
+impl PopupOrigins {
+    pub fn new<S: AsRef<str>>(origins: &[S]) -> Result<Self> {
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                let origin = origin.as_ref();
+                normalize(origin).with_context(|| format!("invalid origin {}", origin))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self(origins))
+    }
+
+    pub fn from_env(origin: &str) -> Result<Self> {
+        let mut origins = vec![origin.to_string()];
+        if let Ok(extra) = env::var("POPUP_ORIGINS") {
+            origins.extend(
+                extra
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|origin| !origin.is_empty())
+                    .map(str::to_string),
+            );
+        }
+        Self::new(&origins)
+    }
+
+    // The origin as the browser serializes it, when it's on the list.
+    pub fn check(&self, origin: &str) -> Option<String> {
+        let origin = normalize(origin)?;
+        self.0.contains(&origin).then_some(origin)
+    }
+}
+
+// "scheme://host[:port]" without default ports, trailing slashes and the like. None for
+// anything that has no tuple origin.
+fn normalize(origin: &str) -> Option<String> {
+    let origin = Url::parse(origin).ok()?.origin();
+    origin.is_tuple().then(|| origin.ascii_serialization())
+}
+
+// What the popup posts to its opener: `{"type": "login", "status": "success" | "error",
+// "provider": ..., "error": ...}`.
+#[derive(Debug, Serialize)]
+pub struct LoginResult {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub status: &'static str,
+    pub provider: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'static str>,
+}
+
+impl LoginResult {
+    pub fn success(provider: &str) -> Self {
+        Self {
+            kind: "login",
+            status: "success",
+            provider: provider.to_string(),
+            error: None,
+        }
+    }
+
+    // Details stay in the server log and the audit log.
+    pub fn failure(provider: &str) -> Self {
+        Self {
+            kind: "login",
+            status: "error",
+            provider: provider.to_string(),
+            error: Some("login_failed"),
+        }
+    }
+
+    // Turned away by the domain allowlist, so the opener can say so.
+    pub fn rejected(provider: &str) -> Self {
+        Self {
+            error: Some("domain_not_allowed"),
+            ..Self::failure(provider)
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use anyhow::{Context, Result};
+use axum::async_trait;
+use serde::Deserialize;
+use std::{collections::HashMap, env, sync::Arc};
+
+use crate::id_token::{IdTokenClaims, JwksCache};
+use crate::logout::LogoutConfig;
+use crate::{AccessType, OAuth2Params, Prompt, ResponseMode, ResponseType, User};
+
+static GOOGLE_ISSUER: &str = "https://accounts.google.com";
+static DEFAULT_SCOPE: &str = "openid email profile";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProviderKind {
+    Google,
+    Generic,
+}
+
+impl ProviderKind {
+    fn parse(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "google" => Ok(Self::Google),
+            "generic" | "oidc" => Ok(Self::Generic),
+            other => Err(anyhow::anyhow!("Unknown provider kind {}", other)),
+        }
+    }
+}
+
+// What we need to know about a provider before discovery.
+#[derive(Debug, Clone)]
+pub struct ProviderConfig {
+    pub name: String,
+    pub kind: ProviderKind,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scope: String,
+    pub redirect_uri: String,
+    // Ask for a refresh token (`access_type=offline` or the `offline_access` scope).
+    pub offline: bool,
+    pub response_type: ResponseType,
+    pub response_mode: ResponseMode,
+    // Extra authorization request parameters, e.g. Google's `include_granted_scopes`.
+    pub auth_params: Vec<(String, String)>,
+    pub logout: LogoutConfig,
+}
+
+impl ProviderConfig {
+    // Reads OIDC_{NAME}_ISSUER, _CLIENT_ID, _CLIENT_SECRET, _SCOPE, _KIND, _OFFLINE,
+    // _RESPONSE_TYPE, _RESPONSE_MODE, _AUTH_PARAMS (a query string) and the logout settings
+    // (see `LogoutConfig::from_env`).
+    // The "google" provider falls back to the legacy CLIENT_ID/CLIENT_SECRET variables.
+    pub fn from_env(name: &str, origin: &str) -> Result<Self> {
+        let prefix = format!("OIDC_{}", name.to_ascii_uppercase().replace('-', "_"));
+        let var = |key: &str| env::var(format!("{}_{}", prefix, key)).ok();
+        let is_google = name == "google";
+
+        let kind = match var("KIND") {
+            Some(kind) => ProviderKind::parse(&kind)?,
+            None if is_google => ProviderKind::Google,
+            None => ProviderKind::Generic,
+        };
+        let issuer = match var("ISSUER") {
+            Some(issuer) => issuer,
+            None if kind == ProviderKind::Google => GOOGLE_ISSUER.to_string(),
+            None => return Err(anyhow::anyhow!("Missing {}_ISSUER!", prefix)),
+        };
+        let client_id = var("CLIENT_ID")
+            .or_else(|| is_google.then(|| env::var("CLIENT_ID").ok()).flatten())
+            .ok_or_else(|| anyhow::anyhow!("Missing {}_CLIENT_ID!", prefix))?;
+        // Without a client secret we act as a public client and rely on PKCE alone.
+        let client_secret = var("CLIENT_SECRET")
+            .or_else(|| is_google.then(|| env::var("CLIENT_SECRET").ok()).flatten());
+        let response_type = ResponseType::parse(var("RESPONSE_TYPE").as_deref().unwrap_or("code"))?;
+        let response_mode =
+            ResponseMode::parse(var("RESPONSE_MODE").as_deref().unwrap_or("query"))?;
+        check_response(response_type, &response_mode)
+            .with_context(|| format!("Invalid response settings for provider {}", name))?;
+
+        Ok(Self {
+            name: name.to_string(),
+            kind,
+            issuer,
+            client_id,
+            client_secret,
+            scope: var("SCOPE").unwrap_or_else(|| DEFAULT_SCOPE.to_string()),
+            redirect_uri: format!("{}/auth/authorized", origin),
+            offline: var("OFFLINE").is_some_and(|v| v == "true" || v == "1"),
+            response_type,
+            response_mode,
+            auth_params: url::form_urlencoded::parse(
+                var("AUTH_PARAMS").unwrap_or_default().as_bytes(),
+            )
+            .into_owned()
+            .collect(),
+            logout: LogoutConfig::from_env(var, origin),
+        })
+    }
+}
+
+// The combinations the server side can receive and validate.
+fn check_response(response_type: ResponseType, response_mode: &ResponseMode) -> Result<()> {
+    if response_type == ResponseType::None {
+        return Err(anyhow::anyhow!("response_type none is not supported"));
+    }
+    // We'd have no way to validate an access token delivered in the front channel.
+    if response_type.has_token() {
+        return Err(anyhow::anyhow!("response_type token is not supported"));
+    }
+    match response_mode {
+        ResponseMode::Fragment => Err(anyhow::anyhow!(
+            "response_mode fragment never reaches the server"
+        )),
+        // Tokens must not be put in the query string (OAuth 2.0 Multiple Response Types).
+        ResponseMode::Query if response_type.has_id_token() => Err(anyhow::anyhow!(
+            "response_type {} requires response_mode form_post",
+            response_type.as_str()
+        )),
+        _ => Ok(()),
+    }
+}
+
+// The subset of `/.well-known/openid-configuration` we use.
+// https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderMetadata
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+    pub userinfo_endpoint: Option<String>,
+    pub end_session_endpoint: Option<String>,
+    pub revocation_endpoint: Option<String>,
+}
+
+impl ProviderMetadata {
+    pub async fn discover(issuer: &str, client: &reqwest::Client) -> Result<Self> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        );
+        let response_body = client
+            .get(&url)
+            .send()
+            .await
+            .with_context(|| format!("failed to fetch {}", url))?
+            .error_for_status()
+            .with_context(|| format!("failed to fetch {}", url))?
+            .text()
+            .await
+            .context("failed to get discovery response body")?;
+        let metadata: Self = serde_json::from_str(&response_body)
+            .context("failed to deserialize discovery document")?;
+        // The document must describe the issuer we asked for (Discovery 1.0, section 4.3).
+        if metadata.issuer.trim_end_matches('/') != issuer.trim_end_matches('/') {
+            return Err(anyhow::anyhow!(
+                "Discovery issuer mismatch: expected {}, got {}",
+                issuer,
+                metadata.issuer
+            ));
+        }
+        Ok(metadata)
+    }
+}
+
+#[async_trait]
+pub trait OidcProvider: Send + Sync {
+    async fn discover(config: ProviderConfig, client: &reqwest::Client) -> Result<Self>
+    where
+        Self: Sized;
+
+    fn config(&self) -> &ProviderConfig;
+
+    fn metadata(&self) -> &ProviderMetadata;
+
+    fn jwks(&self) -> &JwksCache;
+
+    fn name(&self) -> &str {
+        &self.config().name
+    }
+
+    // Authorization request parameters; the per-login values are filled in by `provider_auth`.
+    fn params(&self) -> OAuth2Params {
+        let config = self.config();
+        let metadata = self.metadata();
+        let mut scope = config.scope.clone();
+        if config.offline && !scope.split(' ').any(|s| s == "offline_access") {
+            scope.push_str(" offline_access");
+        }
+        OAuth2Params {
+            client_id: config.client_id.clone(),
+            client_secret: config.client_secret.clone(),
+            redirect_uri: config.redirect_uri.clone(),
+            auth_url: metadata.authorization_endpoint.clone(),
+            token_url: metadata.token_endpoint.clone(),
+            revocation_url: metadata.revocation_endpoint.clone(),
+            response_type: config.response_type.as_str().to_string(),
+            scope,
+            nonce: None,
+            state: None,
+            csrf_token: None,
+            response_mode: Some(config.response_mode.clone()),
+            prompt: None,
+            access_type: None,
+            login_hint: None,
+            hd: None,
+            extra_params: config.auth_params.clone(),
+        }
+    }
+
+    // Accepted values of the `iss` claim.
+    fn issuers(&self) -> Vec<String> {
+        vec![self.metadata().issuer.clone()]
+    }
+
+    // Normalises the verified ID token claims into our `User`.
+    fn map_claims(&self, claims: IdTokenClaims) -> User {
+        let name = claims
+            .name
+            .or(claims.preferred_username)
+            .or_else(|| claims.email.clone())
+            .unwrap_or_else(|| claims.sub.clone());
+        User {
+            user_id: 0,
+            provider: self.name().to_string(),
+            family_name: claims.family_name,
+            name,
+            picture: claims.picture,
+            email: claims.email,
+            given_name: claims.given_name,
+            id: claims.sub,
+            hd: claims.hd,
+            verified_email: claims.email_verified,
+        }
+    }
+}
+
+pub struct GenericOidcProvider {
+    config: ProviderConfig,
+    metadata: ProviderMetadata,
+    jwks: JwksCache,
+}
+
+#[async_trait]
+impl OidcProvider for GenericOidcProvider {
+    async fn discover(config: ProviderConfig, client: &reqwest::Client) -> Result<Self> {
+        let metadata = ProviderMetadata::discover(&config.issuer, client).await?;
+        let jwks = JwksCache::new(&metadata.jwks_uri, client.clone());
+        Ok(Self {
+            config,
+            metadata,
+            jwks,
+        })
+    }
+
+    fn config(&self) -> &ProviderConfig {
+        &self.config
+    }
+
+    fn metadata(&self) -> &ProviderMetadata {
+        &self.metadata
+    }
+
+    fn jwks(&self) -> &JwksCache {
+        &self.jwks
+    }
+}
+
+pub struct GoogleProvider {
+    inner: GenericOidcProvider,
+}
+
+#[async_trait]
+impl OidcProvider for GoogleProvider {
+    async fn discover(config: ProviderConfig, client: &reqwest::Client) -> Result<Self> {
+        Ok(Self {
+            inner: GenericOidcProvider::discover(config, client).await?,
+        })
+    }
+
+    fn config(&self) -> &ProviderConfig {
+        &self.inner.config
+    }
+
+    fn metadata(&self) -> &ProviderMetadata {
+        &self.inner.metadata
+    }
+
+    fn jwks(&self) -> &JwksCache {
+        &self.inner.jwks
+    }
+
+    // Google ignores `offline_access` and only returns a refresh token for
+    // `access_type=offline`, and only on consent.
+    fn params(&self) -> OAuth2Params {
+        let access_type = if self.inner.config.offline {
+            AccessType::Offline
+        } else {
+            AccessType::Online
+        };
+        OAuth2Params {
+            scope: self.inner.config.scope.clone(),
+            prompt: Some(Prompt::Consent),
+            access_type: Some(access_type),
+            ..self.inner.params()
+        }
+    }
+
+    // Google issues tokens with and without the scheme.
+    // https://developers.google.com/identity/openid-connect/openid-connect#validatinganidtoken
+    // Derived from the discovered issuer, so a Google provider can point at a test server.
+    fn issuers(&self) -> Vec<String> {
+        let issuer = self.metadata().issuer.clone();
+        let without_scheme = issuer.trim_start_matches("https://").to_string();
+        vec![issuer, without_scheme]
+    }
+}
+
+pub async fn discover_provider(
+    config: ProviderConfig,
+    client: &reqwest::Client,
+) -> Result<Arc<dyn OidcProvider>> {
+    let name = config.name.clone();
+    let provider: Arc<dyn OidcProvider> = match config.kind {
+        ProviderKind::Google => Arc::new(GoogleProvider::discover(config, client).await?),
+        ProviderKind::Generic => Arc::new(GenericOidcProvider::discover(config, client).await?),
+    };
+    tracing::debug!("Discovered OIDC provider {}", name);
+    Ok(provider)
+}
+
+// All configured providers, keyed by the name used in `/auth/:provider`.
+#[derive(Clone, Default)]
+pub struct Providers(Arc<HashMap<String, Arc<dyn OidcProvider>>>);
+
+impl Providers {
+    pub async fn discover(configs: Vec<ProviderConfig>, client: &reqwest::Client) -> Result<Self> {
+        let mut providers = HashMap::new();
+        for config in configs {
+            let provider = discover_provider(config, client).await?;
+            providers.insert(provider.name().to_string(), provider);
+        }
+        Ok(Self(Arc::new(providers)))
+    }
+
+    // OIDC_PROVIDERS is a comma separated list of provider names, "google" by default.
+    pub async fn from_env(origin: &str, client: &reqwest::Client) -> Result<Self> {
+        let names = env::var("OIDC_PROVIDERS").unwrap_or_else(|_| "google".to_string());
+        let configs = names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| ProviderConfig::from_env(name, origin))
+            .collect::<Result<Vec<_>>>()?;
+        Self::discover(configs, client).await
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn OidcProvider>> {
+        self.0.get(name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.0.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::Result;
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+};
+use http::{request::Parts, StatusCode};
+use std::{env, marker::PhantomData};
+
+use crate::cookies::CookieKeys;
+use crate::session_store::AppSessionStore;
+use crate::users::UserStore;
+use crate::User;
+
+// A role as a type, so handlers can state what they need: `RequireRole<Admin>`.
+pub trait Role: Send + Sync + 'static {
+    const NAME: &'static str;
+}
+
+pub struct Admin;
+
+impl Role for Admin {
+    const NAME: &'static str = "admin";
+}
+
+// Roles granted by configuration, e.g.
+// ROLE_GRANTS="admin=alice@example.com,admin=@example.com"
+// `@domain` matches every address in that domain. Only verified emails count.
+#[derive(Debug, Clone, Default)]
+pub struct RoleGrants(Vec<(String, String)>);
+
+impl RoleGrants {
+    pub fn parse(s: &str) -> Result<Self> {
+        let grants = s
+            .split(',')
+            .map(str::trim)
+            .filter(|grant| !grant.is_empty())
+            .map(|grant| {
+                let (role, who) = grant
+                    .split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("Invalid role grant {}", grant))?;
+                Ok((role.trim().to_string(), who.trim().to_ascii_lowercase()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self(grants))
+    }
+
+    pub fn from_env() -> Result<Self> {
+        match env::var("ROLE_GRANTS") {
+            Ok(grants) => Self::parse(&grants),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn roles_for(&self, user: &User) -> Vec<&str> {
+        let Some(email) = user.email.as_deref().filter(|_| user.verified_email) else {
+            return vec![];
+        };
+        let email = email.to_ascii_lowercase();
+        let domain = email.rsplit_once('@').map(|(_, domain)| domain);
+        self.0
+            .iter()
+            .filter(|(_, who)| match who.strip_prefix('@') {
+                Some(grant_domain) => domain == Some(grant_domain),
+                None => *who == email,
+            })
+            .map(|(role, _)| role.as_str())
+            .collect()
+    }
+}
+
+impl UserStore {
+    // `source` is "config" for roles from ROLE_GRANTS, which follow the configuration, and
+    // "manual" for those from `grant_role`, which stay until revoked.
+    pub(crate) async fn migrate_roles(&self) -> sqlx::Result<()> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS user_roles (
+                user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                role TEXT NOT NULL,
+                source TEXT NOT NULL DEFAULT 'config',
+                PRIMARY KEY (user_id, role)
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+        // Tables from before `source` only have roles from ROLE_GRANTS.
+        let has_source: Option<(i64,)> =
+            sqlx::query_as("SELECT 1 FROM pragma_table_info('user_roles') WHERE name = 'source'")
+                .fetch_optional(&self.pool)
+                .await?;
+        if has_source.is_none() {
+            sqlx::query("ALTER TABLE user_roles ADD COLUMN source TEXT NOT NULL DEFAULT 'config'")
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn grant_role(&self, user_id: i64, role: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO user_roles (user_id, role, source) VALUES (?, ?, 'manual')
+             ON CONFLICT (user_id, role) DO UPDATE SET source = 'manual'",
+        )
+        .bind(user_id)
+        .bind(role)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn revoke_role(&self, user_id: i64, role: &str) -> Result<()> {
+        sqlx::query("DELETE FROM user_roles WHERE user_id = ? AND role = ?")
+            .bind(user_id)
+            .bind(role)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Replaces the user's roles from ROLE_GRANTS with `roles`, on every login, so that
+    // grants removed from the configuration are taken away again. Manual grants stay.
+    pub async fn sync_config_roles(&self, user_id: i64, roles: &[&str]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM user_roles WHERE user_id = ? AND source = 'config'")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        for role in roles {
+            sqlx::query(
+                "INSERT OR IGNORE INTO user_roles (user_id, role, source) VALUES (?, ?, 'config')",
+            )
+            .bind(user_id)
+            .bind(role)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn roles(&self, user_id: i64) -> Result<Vec<String>> {
+        let roles: Vec<(String,)> =
+            sqlx::query_as("SELECT role FROM user_roles WHERE user_id = ? ORDER BY role")
+                .bind(user_id)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(roles.into_iter().map(|(role,)| role).collect())
+    }
+
+    pub async fn has_role(&self, user_id: i64, role: &str) -> Result<bool> {
+        let found: Option<(i64,)> =
+            sqlx::query_as("SELECT 1 FROM user_roles WHERE user_id = ? AND role = ?")
+                .bind(user_id)
+                .bind(role)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(found.is_some())
+    }
+}
+
+// The logged in user, if they have role `R`. Unlike `User` this never redirects:
+// 401 without a session, 403 without the role.
+pub struct RequireRole<R: Role> {
+    pub user: User,
+    _role: PhantomData<R>,
+}
+
+#[async_trait]
+impl<S, R> FromRequestParts<S> for RequireRole<R>
+where
+    AppSessionStore: FromRef<S>,
+    CookieKeys: FromRef<S>,
+    UserStore: FromRef<S>,
+    S: Send + Sync,
+    R: Role,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = User::from_request_parts(parts, state)
+            .await
+            .map_err(|_| StatusCode::UNAUTHORIZED)?;
+        let users = UserStore::from_ref(state);
+        match users.has_role(user.user_id, R::NAME).await {
+            Ok(true) => Ok(Self {
+                user,
+                _role: PhantomData,
+            }),
+            Ok(false) => Err(StatusCode::FORBIDDEN),
+            Err(e) => {
+                tracing::error!("Failed to look up roles: {:#}", e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use async_session::{async_trait, chrono::Utc, MemoryStore, Session, SessionStore};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use std::{env, str::FromStr, time::Duration};
+use tokio::task::JoinHandle;
+
+static DEFAULT_DATABASE_URL: &str = "sqlite:sessions.db";
+static DEFAULT_CLEANUP_INTERVAL_SECS: u64 = 300;
+
+// Sessions serialized as JSON into a single SQLite table.
+#[derive(Debug, Clone)]
+pub struct SqliteSessionStore {
+    pool: SqlitePool,
+}
+
+impl SqliteSessionStore {
+    pub async fn connect(database_url: &str) -> sqlx::Result<Self> {
+        let options = SqliteConnectOptions::from_str(database_url)?.create_if_missing(true);
+        let store = Self::from_pool(SqlitePool::connect_with(options).await?);
+        store.migrate().await?;
+        Ok(store)
+    }
+
+    // Every connection to `sqlite::memory:` is its own database, so keep just one.
+    pub async fn in_memory() -> sqlx::Result<Self> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(SqliteConnectOptions::from_str("sqlite::memory:")?)
+            .await?;
+        let store = Self::from_pool(pool);
+        store.migrate().await?;
+        Ok(store)
+    }
+
+    pub fn from_pool(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn migrate(&self) -> sqlx::Result<()> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY NOT NULL,
+                expires INTEGER NULL,
+                session TEXT NOT NULL
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Removes expired sessions and returns how many were deleted.
+    pub async fn cleanup(&self) -> sqlx::Result<u64> {
+        let result = sqlx::query("DELETE FROM sessions WHERE expires < ?")
+            .bind(Utc::now().timestamp())
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteSessionStore {
+    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        let row: Option<(String,)> = sqlx::query_as(
+            "SELECT session FROM sessions WHERE id = ? AND (expires IS NULL OR expires > ?)",
+        )
+        .bind(&id)
+        .bind(Utc::now().timestamp())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row
+            .map(|(session,)| serde_json::from_str::<Session>(&session))
+            .transpose()?
+            .and_then(Session::validate))
+    }
+
+    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+        let serialized = serde_json::to_string(&session)?;
+        sqlx::query(
+            "INSERT INTO sessions (id, expires, session) VALUES (?, ?, ?)
+             ON CONFLICT(id) DO UPDATE SET expires = excluded.expires, session = excluded.session",
+        )
+        .bind(session.id())
+        .bind(session.expiry().map(|expiry| expiry.timestamp()))
+        .bind(serialized)
+        .execute(&self.pool)
+        .await?;
+
+        session.reset_data_changed();
+        Ok(session.into_cookie_value())
+    }
+
+    async fn destroy_session(&self, session: Session) -> async_session::Result {
+        sqlx::query("DELETE FROM sessions WHERE id = ?")
+            .bind(session.id())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> async_session::Result {
+        sqlx::query("DELETE FROM sessions")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+// The session store selected by `SESSION_STORE` ("memory" or "sqlite").
+// Handlers and extractors only rely on the `SessionStore` trait.
+#[derive(Debug, Clone)]
+pub enum AppSessionStore {
+    Memory(MemoryStore),
+    Sqlite(SqliteSessionStore),
+}
+
+impl AppSessionStore {
+    pub async fn from_env() -> anyhow::Result<Self> {
+        match env::var("SESSION_STORE").as_deref() {
+            Ok("sqlite") => {
+                let database_url = env::var("SESSION_DATABASE_URL")
+                    .unwrap_or_else(|_| DEFAULT_DATABASE_URL.to_string());
+                tracing::debug!("Using SQLite session store at {}", database_url);
+                Ok(Self::Sqlite(
+                    SqliteSessionStore::connect(&database_url).await?,
+                ))
+            }
+            Ok("memory") | Err(_) => {
+                // `MemoryStore` loses every session on restart. Don't use this in production.
+                tracing::debug!("Using in-memory session store");
+                Ok(Self::Memory(MemoryStore::new()))
+            }
+            Ok(other) => Err(anyhow::anyhow!("Unknown SESSION_STORE: {}", other)),
+        }
+    }
+
+    pub async fn cleanup(&self) -> anyhow::Result<()> {
+        match self {
+            Self::Memory(store) => store.cleanup().await?,
+            Self::Sqlite(store) => {
+                let deleted = store.cleanup().await?;
+                if deleted > 0 {
+                    tracing::debug!("Deleted {} expired sessions", deleted);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for AppSessionStore {
+    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+        match self {
+            Self::Memory(store) => store.load_session(cookie_value).await,
+            Self::Sqlite(store) => store.load_session(cookie_value).await,
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+        match self {
+            Self::Memory(store) => store.store_session(session).await,
+            Self::Sqlite(store) => store.store_session(session).await,
+        }
+    }
+
+    async fn destroy_session(&self, session: Session) -> async_session::Result {
+        match self {
+            Self::Memory(store) => store.destroy_session(session).await,
+            Self::Sqlite(store) => store.destroy_session(session).await,
+        }
+    }
+
+    async fn clear_store(&self) -> async_session::Result {
+        match self {
+            Self::Memory(store) => store.clear_store().await,
+            Self::Sqlite(store) => store.clear_store().await,
+        }
+    }
+}
+
+// Periodically garbage-collects expired sessions. The interval comes from
+// `SESSION_CLEANUP_INTERVAL_SECS`, and is at least a second.
+pub fn spawn_cleanup_task(store: AppSessionStore) -> JoinHandle<()> {
+    let interval_secs = env::var("SESSION_CLEANUP_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_CLEANUP_INTERVAL_SECS)
+        .max(1);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+        loop {
+            interval.tick().await;
+            if let Err(e) = store.cleanup().await {
+                tracing::error!("Session cleanup failed: {:#}", e);
+            }
+        }
+    })
+}
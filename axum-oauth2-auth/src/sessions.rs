@@ -0,0 +1,175 @@
+use anyhow::Result;
+use async_session::{Session, SessionStore};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::session_store::AppSessionStore;
+use crate::users::UserStore;
+
+// Login sessions indexed by user, next to the session store itself. The store only knows
+// session ids by cookie value, so this is what lets us list and revoke sessions.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct SessionRecord {
+    pub id: String,
+    pub user_id: i64,
+    pub email: Option<String>,
+    pub provider: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: i64,
+    pub last_seen_at: i64,
+    pub expires_at: i64,
+}
+
+// What we know about the client when a session is created.
+#[derive(Debug, Clone, Default)]
+pub struct SessionOrigin {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl UserStore {
+    pub(crate) async fn migrate_sessions(&self) -> sqlx::Result<()> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS user_sessions (
+                id TEXT PRIMARY KEY NOT NULL,
+                user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                provider TEXT NOT NULL,
+                user_agent TEXT NULL,
+                ip TEXT NULL,
+                created_at INTEGER NOT NULL,
+                last_seen_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS user_sessions_user_id ON user_sessions (user_id)")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn record_session(
+        &self,
+        session_id: &str,
+        user_id: i64,
+        provider: &str,
+        origin: &SessionOrigin,
+        expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let now = Utc::now().timestamp();
+        sqlx::query(
+            "INSERT INTO user_sessions
+             (id, user_id, provider, user_agent, ip, created_at, last_seen_at, expires_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .bind(provider)
+        .bind(&origin.user_agent)
+        .bind(&origin.ip)
+        .bind(now)
+        .bind(now)
+        .bind(expires_at.timestamp())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn touch_session(&self, session_id: &str, expires_at: DateTime<Utc>) -> Result<()> {
+        sqlx::query("UPDATE user_sessions SET last_seen_at = ?, expires_at = ? WHERE id = ?")
+            .bind(Utc::now().timestamp())
+            .bind(expires_at.timestamp())
+            .bind(session_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn forget_session(&self, session_id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM user_sessions WHERE id = ?")
+            .bind(session_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Whether the session is still in the index for this user. A session that was revoked
+    // elsewhere may still be in a store that doesn't see the revocation (e.g. another
+    // instance's memory store), so the index is what counts.
+    pub async fn is_session_active(&self, session_id: &str, user_id: i64) -> Result<bool> {
+        let found: Option<(i64,)> =
+            sqlx::query_as("SELECT 1 FROM user_sessions WHERE id = ? AND user_id = ?")
+                .bind(session_id)
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(found.is_some())
+    }
+
+    // Active sessions of one user, most recently used first.
+    pub async fn user_sessions(&self, user_id: i64) -> Result<Vec<SessionRecord>> {
+        let sessions = sqlx::query_as(
+            "SELECT s.id, s.user_id, u.email, s.provider, s.user_agent, s.ip,
+                    s.created_at, s.last_seen_at, s.expires_at
+             FROM user_sessions s JOIN users u ON u.id = s.user_id
+             WHERE s.user_id = ? AND s.expires_at > ?
+             ORDER BY s.last_seen_at DESC",
+        )
+        .bind(user_id)
+        .bind(Utc::now().timestamp())
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(sessions)
+    }
+
+    // Active sessions of every user, most recently used first.
+    pub async fn active_sessions(&self) -> Result<Vec<SessionRecord>> {
+        let sessions = sqlx::query_as(
+            "SELECT s.id, s.user_id, u.email, s.provider, s.user_agent, s.ip,
+                    s.created_at, s.last_seen_at, s.expires_at
+             FROM user_sessions s JOIN users u ON u.id = s.user_id
+             WHERE s.expires_at > ?
+             ORDER BY s.last_seen_at DESC",
+        )
+        .bind(Utc::now().timestamp())
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(sessions)
+    }
+}
+
+// Destroys a session by its id (not the cookie value) and drops it from the index.
+pub async fn revoke_session(
+    store: &AppSessionStore,
+    users: &UserStore,
+    session_id: &str,
+) -> Result<()> {
+    // `SessionStore::destroy_session` only looks at the id, and a `Session` can't be
+    // built with a given id other than by deserializing one.
+    let session: Session = serde_json::from_value(serde_json::json!({
+        "id": session_id,
+        "expiry": null,
+        "data": {},
+    }))?;
+    store.destroy_session(session).await?;
+    users.forget_session(session_id).await?;
+    Ok(())
+}
+
+// Revokes every session of a user, including expired ones still in the index.
+pub async fn revoke_user_sessions(
+    store: &AppSessionStore,
+    users: &UserStore,
+    user_id: i64,
+) -> Result<()> {
+    let ids: Vec<(String,)> = sqlx::query_as("SELECT id FROM user_sessions WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_all(&users.pool)
+        .await?;
+    for (session_id,) in ids {
+        revoke_session(store, users, &session_id).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,318 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{Context, Result};
+use async_session::SessionStore;
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, Request, State},
+    middleware::Next,
+    response::Response,
+    RequestPartsExt,
+};
+use axum_extra::{headers, TypedHeader};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Duration, Utc};
+use http::{header::SET_COOKIE, request::Parts, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{env, sync::Arc};
+
+use crate::audit::{AuthEvent, AuthEventKind};
+use crate::backend::{Backend, OidcTokenResponse, RevocableToken};
+use crate::cookies::{CookieKeys, SetCookie};
+use crate::provider::{ProviderKind, Providers};
+use crate::session_store::AppSessionStore;
+use crate::sessions;
+use crate::{AppState, User, COOKIE_MAX_AGE, COOKIE_NAME};
+
+// Refresh a little before the provider would reject the access token.
+static REFRESH_SKEW_SECS: i64 = 60;
+// Assumed when the token response doesn't say how long the access token lasts.
+static DEFAULT_ACCESS_TOKEN_LIFETIME_SECS: u64 = 3600;
+
+// AES-256-GCM for tokens at rest. The key comes from TOKEN_ENCRYPTION_KEY (base64, 32 bytes);
+// without it a random key is used and stored tokens don't survive a restart.
+#[derive(Clone)]
+pub struct TokenCipher(Arc<Aes256Gcm>);
+
+impl TokenCipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self(Arc::new(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))))
+    }
+
+    pub fn random() -> Self {
+        Self(Arc::new(Aes256Gcm::new(&Aes256Gcm::generate_key(OsRng))))
+    }
+
+    pub fn from_env() -> Result<Self> {
+        match env::var("TOKEN_ENCRYPTION_KEY") {
+            Ok(encoded) => {
+                let key: [u8; 32] = STANDARD
+                    .decode(encoded.trim())
+                    .context("TOKEN_ENCRYPTION_KEY is not valid base64")?
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("TOKEN_ENCRYPTION_KEY must be 32 bytes"))?;
+                Ok(Self::new(&key))
+            }
+            Err(_) => {
+                tracing::warn!("TOKEN_ENCRYPTION_KEY not set, using a random key");
+                Ok(Self::random())
+            }
+        }
+    }
+
+    // base64(nonce || ciphertext)
+    pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|_| anyhow::anyhow!("failed to encrypt token"))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(STANDARD.encode(sealed))
+    }
+
+    pub fn decrypt(&self, sealed: &str) -> Result<String> {
+        let sealed = STANDARD
+            .decode(sealed)
+            .context("malformed encrypted token")?;
+        if sealed.len() < 12 {
+            return Err(anyhow::anyhow!("malformed encrypted token"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(12);
+        let plaintext = self
+            .0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("failed to decrypt token"))?;
+        String::from_utf8(plaintext).context("decrypted token is not UTF-8")
+    }
+}
+
+// The provider's tokens as kept in the session, encrypted with `TokenCipher`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredTokens {
+    provider: String,
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_at: DateTime<Utc>,
+}
+
+fn expires_at(response: &OidcTokenResponse) -> DateTime<Utc> {
+    let lifetime = response
+        .expires_in
+        .unwrap_or(DEFAULT_ACCESS_TOKEN_LIFETIME_SECS);
+    Utc::now() + Duration::seconds(lifetime as i64)
+}
+
+impl StoredTokens {
+    pub(crate) fn seal(
+        cipher: &TokenCipher,
+        provider: &str,
+        response: &OidcTokenResponse,
+    ) -> Result<Self> {
+        Ok(Self {
+            provider: provider.to_string(),
+            access_token: cipher.encrypt(&response.access_token)?,
+            refresh_token: response
+                .refresh_token
+                .as_deref()
+                .map(|token| cipher.encrypt(token))
+                .transpose()?,
+            expires_at: expires_at(response),
+        })
+    }
+
+    // Decrypted, refresh token first: revoking it usually ends the whole grant.
+    pub(crate) fn revocable(&self, cipher: &TokenCipher) -> Result<Vec<RevocableToken>> {
+        let mut tokens = vec![];
+        if let Some(refresh_token) = &self.refresh_token {
+            tokens.push(RevocableToken::RefreshToken(cipher.decrypt(refresh_token)?));
+        }
+        tokens.push(RevocableToken::AccessToken(
+            cipher.decrypt(&self.access_token)?,
+        ));
+        Ok(tokens)
+    }
+
+    fn needs_refresh(&self) -> bool {
+        self.refresh_token.is_some()
+            && Utc::now() + Duration::seconds(REFRESH_SKEW_SECS) >= self.expires_at
+    }
+
+    // Providers may or may not rotate the refresh token; keep the old one if they don't.
+    fn refreshed(self, cipher: &TokenCipher, response: &OidcTokenResponse) -> Result<Self> {
+        let refresh_token = match response.refresh_token.as_deref() {
+            Some(token) => Some(cipher.encrypt(token)?),
+            None => self.refresh_token,
+        };
+        Ok(Self {
+            provider: self.provider,
+            access_token: cipher.encrypt(&response.access_token)?,
+            refresh_token,
+            expires_at: expires_at(response),
+        })
+    }
+}
+
+// Slides the session expiry forward on activity and refreshes an expiring access token,
+// before the handler runs. The renewed cookie is added to the response unless the handler
+// already set one (e.g. `/logout`).
+pub async fn renew_session(
+    State(state): State<AppState>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let cookie_keys = &state.cookie_keys;
+    let session_id = cookies.and_then(|TypedHeader(c)| cookie_keys.get(&c, COOKIE_NAME));
+    let renewed = match session_id {
+        Some(session_id) => match renew(&state, &session_id).await {
+            Ok(renewed) => renewed.then_some(session_id),
+            Err(e) => {
+                tracing::warn!("Failed to renew session: {:#}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let mut response = next.run(request).await;
+
+    if let Some(session_id) = renewed {
+        let already_set = response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.starts_with(&format!("{}=", COOKIE_NAME)));
+        if !already_set {
+            let renewed = SetCookie::private(cookie_keys, COOKIE_NAME, &session_id, COOKIE_MAX_AGE)
+                .and_then(|cookie| cookie.append_to(response.headers_mut()));
+            if let Err(e) = renewed {
+                tracing::warn!("Failed to renew session cookie: {:#}", e);
+            }
+        }
+    }
+    response
+}
+
+async fn renew(state: &AppState, session_id: &str) -> Result<bool> {
+    let AppState {
+        store,
+        providers,
+        cipher,
+        users,
+        backend,
+        domains,
+        audit,
+        ..
+    } = state;
+    let Some(mut session) = store.load_session(session_id.to_string()).await? else {
+        return Ok(false);
+    };
+
+    // The domain allowlist may have changed since login. A user it no longer lets in is
+    // logged out rather than kept alive by their activity.
+    if let Some(user) = session.get::<User>("user") {
+        let kind = providers
+            .get(&user.provider)
+            .map_or(ProviderKind::Generic, |provider| provider.config().kind);
+        if let Err(e) = domains.rules().check_user(kind, &user) {
+            sessions::revoke_session(store, users, session.id()).await?;
+            let event = AuthEvent::new(AuthEventKind::SessionRevoked)
+                .user_id(user.user_id)
+                .provider(&user.provider)
+                .session(session.id())
+                .reason(e.to_string());
+            audit.record(event).await;
+            return Ok(false);
+        }
+    }
+
+    if let Some(tokens) = session.get::<StoredTokens>("tokens") {
+        if tokens.needs_refresh() {
+            match refresh(providers, backend, cipher, tokens).await {
+                Ok(tokens) => session.insert("tokens", tokens)?,
+                Err(e) => {
+                    // Most likely revoked; the local session stays, the tokens go.
+                    tracing::warn!("Failed to refresh access token: {:#}", e);
+                    session.remove("tokens");
+                }
+            }
+        }
+    }
+
+    let expires_at = Utc::now() + Duration::seconds(COOKIE_MAX_AGE);
+    session.set_expiry(expires_at);
+    users.touch_session(session.id(), expires_at).await?;
+    store.store_session(session).await?;
+    Ok(true)
+}
+
+async fn refresh(
+    providers: &Providers,
+    backend: &Backend,
+    cipher: &TokenCipher,
+    tokens: StoredTokens,
+) -> Result<StoredTokens> {
+    let provider = providers
+        .get(&tokens.provider)
+        .ok_or_else(|| anyhow::anyhow!("Unknown provider {}", tokens.provider))?;
+    let refresh_token = tokens
+        .refresh_token
+        .as_deref()
+        .map(|token| cipher.decrypt(token))
+        .transpose()?
+        .context("no refresh token")?;
+    let response = backend.refresh(&provider.params(), refresh_token).await?;
+    tracing::debug!("Refreshed access token for provider {}", tokens.provider);
+    tokens.refreshed(cipher, &response)
+}
+
+// The current, decrypted access token of the logged in user.
+pub struct AccessToken {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AccessToken
+where
+    AppSessionStore: FromRef<S>,
+    TokenCipher: FromRef<S>,
+    CookieKeys: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let store = AppSessionStore::from_ref(state);
+        let cipher = TokenCipher::from_ref(state);
+
+        let TypedHeader(cookies) = parts
+            .extract::<TypedHeader<headers::Cookie>>()
+            .await
+            .map_err(|_| StatusCode::UNAUTHORIZED)?;
+        let session_id = CookieKeys::from_ref(state)
+            .get(&cookies, COOKIE_NAME)
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        let session = store
+            .load_session(session_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        let tokens = session
+            .get::<StoredTokens>("tokens")
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        let token = cipher
+            .decrypt(&tokens.access_token)
+            .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+        Ok(Self {
+            token,
+            expires_at: tokens.expires_at,
+        })
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use std::{env, str::FromStr};
+
+use crate::User;
+
+static DEFAULT_DATABASE_URL: &str = "sqlite:users.db";
+
+// A local account. It outlives sessions and can have several provider identities.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Account {
+    pub id: i64,
+    pub email: Option<String>,
+    pub name: Option<String>,
+    pub picture: Option<String>,
+    pub created_at: i64,
+    pub last_login_at: i64,
+}
+
+// One (provider, subject) pair, i.e. an account at an identity provider.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Identity {
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub hd: Option<String>,
+    pub created_at: i64,
+    pub last_login_at: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct UserStore {
+    pub(crate) pool: SqlitePool,
+}
+
+impl UserStore {
+    pub async fn connect(database_url: &str) -> sqlx::Result<Self> {
+        let options = SqliteConnectOptions::from_str(database_url)?
+            .create_if_missing(true)
+            .foreign_keys(true);
+        let store = Self::from_pool(SqlitePool::connect_with(options).await?);
+        store.migrate().await?;
+        Ok(store)
+    }
+
+    // Every connection to `sqlite::memory:` is its own database, so keep just one.
+    pub async fn in_memory() -> sqlx::Result<Self> {
+        let options = SqliteConnectOptions::from_str("sqlite::memory:")?.foreign_keys(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await?;
+        let store = Self::from_pool(pool);
+        store.migrate().await?;
+        Ok(store)
+    }
+
+    pub async fn from_env() -> Result<Self> {
+        let database_url =
+            env::var("USER_DATABASE_URL").unwrap_or_else(|_| DEFAULT_DATABASE_URL.to_string());
+        tracing::debug!("Using user database at {}", database_url);
+        Ok(Self::connect(&database_url).await?)
+    }
+
+    pub fn from_pool(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn migrate(&self) -> sqlx::Result<()> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS users (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                email TEXT NULL,
+                name TEXT NULL,
+                picture TEXT NULL,
+                created_at INTEGER NOT NULL,
+                last_login_at INTEGER NOT NULL
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS identities (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                provider TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                email TEXT NULL,
+                email_verified INTEGER NOT NULL DEFAULT 0,
+                hd TEXT NULL,
+                created_at INTEGER NOT NULL,
+                last_login_at INTEGER NOT NULL,
+                UNIQUE (provider, subject)
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS identities_user_id ON identities (user_id)")
+            .execute(&self.pool)
+            .await?;
+        self.migrate_roles().await?;
+        self.migrate_sessions().await?;
+        self.migrate_logout().await?;
+        self.migrate_audit().await?;
+        Ok(())
+    }
+
+    // Finds or creates the account for a verified provider identity and returns its id.
+    // With `link_to`, a new identity is attached to that (logged in) account instead.
+    // We never link by email: a provider vouching for an address is not proof that it's
+    // the same person as the account holder here.
+    pub async fn login(&self, user: &User, link_to: Option<i64>) -> Result<i64> {
+        let now = Utc::now().timestamp();
+        let mut tx = self.pool.begin().await?;
+
+        let existing: Option<(i64,)> =
+            sqlx::query_as("SELECT user_id FROM identities WHERE provider = ? AND subject = ?")
+                .bind(&user.provider)
+                .bind(&user.id)
+                .fetch_optional(&mut *tx)
+                .await?;
+
+        let user_id = match (existing, link_to) {
+            (Some((user_id,)), Some(link_to)) if user_id != link_to => {
+                return Err(anyhow::anyhow!(
+                    "This {} account is already linked to another user",
+                    user.provider
+                ));
+            }
+            (Some((user_id,)), _) => {
+                sqlx::query(
+                    "UPDATE identities SET email = ?, email_verified = ?, hd = ?, last_login_at = ?
+                     WHERE provider = ? AND subject = ?",
+                )
+                .bind(&user.email)
+                .bind(user.verified_email)
+                .bind(&user.hd)
+                .bind(now)
+                .bind(&user.provider)
+                .bind(&user.id)
+                .execute(&mut *tx)
+                .await?;
+                user_id
+            }
+            (None, link_to) => {
+                let user_id = match link_to {
+                    Some(user_id) => user_id,
+                    None => sqlx::query(
+                        "INSERT INTO users (email, name, picture, created_at, last_login_at)
+                         VALUES (?, ?, ?, ?, ?)",
+                    )
+                    .bind(&user.email)
+                    .bind(Some(&user.name).filter(|name| !name.is_empty()))
+                    .bind(&user.picture)
+                    .bind(now)
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?
+                    .last_insert_rowid(),
+                };
+                sqlx::query(
+                    "INSERT INTO identities
+                     (user_id, provider, subject, email, email_verified, hd, created_at, last_login_at)
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                )
+                .bind(user_id)
+                .bind(&user.provider)
+                .bind(&user.id)
+                .bind(&user.email)
+                .bind(user.verified_email)
+                .bind(&user.hd)
+                .bind(now)
+                .bind(now)
+                .execute(&mut *tx)
+                .await
+                .context("failed to link identity")?;
+                user_id
+            }
+        };
+
+        // Fill in profile fields the account doesn't have yet; never overwrite.
+        sqlx::query(
+            "UPDATE users SET email = COALESCE(email, ?), name = COALESCE(name, ?),
+             picture = COALESCE(picture, ?), last_login_at = ? WHERE id = ?",
+        )
+        .bind(&user.email)
+        .bind(Some(&user.name).filter(|name| !name.is_empty()))
+        .bind(&user.picture)
+        .bind(now)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(user_id)
+    }
+
+    pub async fn get(&self, user_id: i64) -> Result<Option<Account>> {
+        let account = sqlx::query_as(
+            "SELECT id, email, name, picture, created_at, last_login_at FROM users WHERE id = ?",
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(account)
+    }
+
+    pub async fn identities(&self, user_id: i64) -> Result<Vec<Identity>> {
+        let identities = sqlx::query_as(
+            "SELECT provider, subject, email, email_verified, hd, created_at, last_login_at
+             FROM identities WHERE user_id = ? ORDER BY created_at, id",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(identities)
+    }
+}
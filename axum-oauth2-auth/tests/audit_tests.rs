@@ -0,0 +1,148 @@
+mod common;
+
+use axum::http::{header, StatusCode};
+use axum_oauth2_auth::{
+    audit::{redact, AuditLog, AuthEventFilter, AuthEventKind},
+    create_router,
+    flash::FlashLevel,
+    roles::RoleGrants,
+};
+use common::{
+    body_string, flash, get, login, logout, mock_config, post_session_form, session_id,
+    start_login, test_state, MockIssuer, USER_AGENT,
+};
+
+#[tokio::test]
+async fn test_login_and_logout_are_recorded() {
+    let mock = MockIssuer::start().await;
+    let state = test_state(
+        vec![mock_config("mock", &mock.issuer)],
+        RoleGrants::default(),
+    )
+    .await;
+    let app = create_router(state.clone());
+
+    let session_cookie = login(&app, "mock", &mock).await;
+    let response = logout(&app, &session_cookie, &[(header::USER_AGENT, USER_AGENT)]).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+    let events = state
+        .audit
+        .recent(&AuthEventFilter::default())
+        .await
+        .unwrap();
+    let kinds: Vec<_> = events.iter().map(|e| e.kind.as_str()).collect();
+    assert_eq!(kinds, ["logout", "login_success"]);
+
+    let session = redact(&session_id(&session_cookie));
+    for event in &events {
+        assert_eq!(event.provider.as_deref(), Some("mock"));
+        assert_eq!(event.user_agent.as_deref(), Some(USER_AGENT));
+        assert_eq!(event.session.as_ref(), Some(&session));
+        assert!(event.user_id.is_some());
+    }
+}
+
+#[tokio::test]
+async fn test_failures_are_recorded() {
+    let mock = MockIssuer::start().await;
+    let state = test_state(
+        vec![mock_config("mock", &mock.issuer)],
+        RoleGrants::default(),
+    )
+    .await;
+    let app = create_router(state.clone());
+
+    // A callback without the CSRF cookie.
+    let (_, path) = start_login(&app, "mock", &mock, None).await;
+    let response = get(&app, &path, &[(header::USER_AGENT, USER_AGENT)]).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(flash(&response).unwrap().level, FlashLevel::Error);
+
+    let filter = AuthEventFilter {
+        kind: Some(AuthEventKind::CsrfFailure),
+        ..Default::default()
+    };
+    let events = state.audit.recent(&filter).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert!(events[0].reason.is_some());
+    assert_eq!(events[0].user_id, None);
+    assert_eq!(events[0].session, None);
+}
+
+#[tokio::test]
+async fn test_revocations_are_recorded() {
+    let mock = MockIssuer::start().await;
+    let state = test_state(
+        vec![mock_config("mock", &mock.issuer)],
+        RoleGrants::default(),
+    )
+    .await;
+    let app = create_router(state.clone());
+
+    let session_cookie = login(&app, "mock", &mock).await;
+    login(&app, "mock", &mock).await;
+    let response = post_session_form(&app, "/sessions/revoke_all", &session_cookie).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+    let filter = AuthEventFilter {
+        kind: Some(AuthEventKind::SessionRevoked),
+        ..Default::default()
+    };
+    let events = state.audit.recent(&filter).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert!(events[0].user_id.is_some());
+    assert!(events[0].reason.is_some());
+}
+
+#[tokio::test]
+async fn test_admin_events() {
+    let mock = MockIssuer::start().await;
+    let state = test_state(
+        vec![mock_config("mock", &mock.issuer)],
+        RoleGrants::parse("admin=@example.com").unwrap(),
+    )
+    .await;
+    let app = create_router(state.clone());
+
+    let response = get(&app, "/admin/events", &[]).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let session_cookie = login(&app, "mock", &mock).await;
+    login(&app, "mock", &mock).await;
+    let response = get(
+        &app,
+        "/admin/events?kind=login_success&limit=1",
+        &[(header::COOKIE, &session_cookie)],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_string(response).await;
+    let events: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(events.as_array().unwrap().len(), 1);
+    assert_eq!(events[0]["kind"], "login_success");
+
+    // Neither the cookie nor the session id itself ends up in the log.
+    let body =
+        body_string(get(&app, "/admin/events", &[(header::COOKIE, &session_cookie)]).await).await;
+    let (_, sealed) = session_cookie.split_once('=').unwrap();
+    assert!(!body.contains(sealed));
+    assert!(!body.contains(&session_id(&session_cookie)));
+    assert!(body.contains(&redact(&session_id(&session_cookie))));
+}
+
+#[tokio::test]
+async fn test_admin_events_not_persisted() {
+    let mock = MockIssuer::start().await;
+    let mut state = test_state(
+        vec![mock_config("mock", &mock.issuer)],
+        RoleGrants::parse("admin=@example.com").unwrap(),
+    )
+    .await;
+    state.audit = AuditLog::new(None);
+    let app = create_router(state);
+
+    let session_cookie = login(&app, "mock", &mock).await;
+    let response = get(&app, "/admin/events", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,220 @@
+use axum_oauth2_auth::{
+    authorization::AuthorizationRequest, AccessType, Prompt, ResponseMode, ResponseType,
+};
+use std::collections::HashMap;
+use url::Url;
+
+static AUTH_URL: &str = "https://issuer.example/authorize";
+
+const RESPONSE_TYPES: [ResponseType; 8] = [
+    ResponseType::None,
+    ResponseType::Code,
+    ResponseType::Token,
+    ResponseType::IdToken,
+    ResponseType::CodeToken,
+    ResponseType::CodeIdToken,
+    ResponseType::TokenIdToken,
+    ResponseType::CodeTokenIdToken,
+];
+
+fn response_modes() -> [Option<ResponseMode>; 4] {
+    [
+        None,
+        Some(ResponseMode::Query),
+        Some(ResponseMode::Fragment),
+        Some(ResponseMode::FormPost),
+    ]
+}
+
+fn prompts() -> [Option<Prompt>; 9] {
+    [
+        None,
+        Some(Prompt::None),
+        Some(Prompt::Consent),
+        Some(Prompt::SelectAccount),
+        Some(Prompt::Login),
+        Some(Prompt::ConsentSelectAccount),
+        Some(Prompt::ConsentLogin),
+        Some(Prompt::SelectAccountLogin),
+        Some(Prompt::ConsentSelectAccountLogin),
+    ]
+}
+
+fn access_types() -> [Option<AccessType>; 3] {
+    [None, Some(AccessType::Online), Some(AccessType::Offline)]
+}
+
+fn request() -> AuthorizationRequest {
+    AuthorizationRequest::new(AUTH_URL, "client id", "https://app.example/auth/authorized")
+}
+
+// Decoded, so a value that came through intact compares equal.
+fn params(url: &Url) -> HashMap<String, String> {
+    let pairs: Vec<_> = url.query_pairs().into_owned().collect();
+    let params: HashMap<_, _> = pairs.iter().cloned().collect();
+    assert_eq!(pairs.len(), params.len(), "duplicate parameter in {}", url);
+    params
+}
+
+#[test]
+fn test_every_enum_combination() {
+    for response_type in RESPONSE_TYPES {
+        for response_mode in response_modes() {
+            for prompt in prompts() {
+                for access_type in access_types() {
+                    let mut builder = request().response_type(response_type).nonce("n");
+                    if let Some(response_mode) = response_mode.clone() {
+                        builder = builder.response_mode(response_mode);
+                    }
+                    if let Some(prompt) = prompt.clone() {
+                        builder = builder.prompt(prompt);
+                    }
+                    if let Some(access_type) = access_type.clone() {
+                        builder = builder.access_type(access_type);
+                    }
+                    let result = builder.build();
+
+                    let front_channel_tokens =
+                        response_type.has_token() || response_type.has_id_token();
+                    if matches!(response_mode, Some(ResponseMode::Query)) && front_channel_tokens {
+                        assert!(result.is_err(), "{:?} with query", response_type);
+                        continue;
+                    }
+                    let url = result.unwrap();
+                    let params = params(&url);
+                    assert_eq!(params["response_type"], response_type.as_str());
+                    assert_eq!(
+                        params.get("response_mode").map(String::as_str),
+                        response_mode.as_ref().map(|m| m.as_str())
+                    );
+                    assert_eq!(
+                        params.get("prompt").map(String::as_str),
+                        prompt.as_ref().map(|p| p.as_str())
+                    );
+                    assert_eq!(
+                        params.get("access_type").map(String::as_str),
+                        access_type.as_ref().map(|a| a.as_str())
+                    );
+                    // client_id, redirect_uri, response_type and nonce, plus what was set.
+                    let expected = 4
+                        + response_mode.is_some() as usize
+                        + prompt.is_some() as usize
+                        + access_type.is_some() as usize;
+                    assert_eq!(params.len(), expected, "{}", url);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_response_type_round_trip() {
+    for response_type in RESPONSE_TYPES {
+        assert_eq!(
+            ResponseType::parse(response_type.as_str()).unwrap(),
+            response_type
+        );
+    }
+    assert_eq!(
+        ResponseType::parse("id_token code").unwrap(),
+        ResponseType::CodeIdToken
+    );
+    assert!(ResponseType::parse("code bogus").is_err());
+}
+
+#[test]
+fn test_unset_parameters_are_omitted() {
+    let url = request().build().unwrap();
+    let params = params(&url);
+    assert_eq!(params.len(), 3);
+    assert_eq!(params["client_id"], "client id");
+    assert_eq!(
+        params["redirect_uri"],
+        "https://app.example/auth/authorized"
+    );
+    assert_eq!(params["response_type"], "code");
+}
+
+#[test]
+fn test_all_parameters_are_encoded() {
+    let url = request()
+        .scope("openid email")
+        .state("a&b=c")
+        .nonce("n+1")
+        .pkce_s256("challenge")
+        .login_hint("alice+test@example.com")
+        .hd("example.com")
+        .extra_param("include_granted_scopes", "true")
+        .extra_param("ui_locales", "ja en")
+        .build()
+        .unwrap();
+
+    // Nothing leaks into the structure of the URL.
+    assert_eq!(url.scheme(), "https");
+    assert_eq!(url.host_str(), Some("issuer.example"));
+    assert_eq!(url.path(), "/authorize");
+    assert_eq!(url.fragment(), None);
+    assert!(!url.query().unwrap().contains(' '));
+
+    let params = params(&url);
+    assert_eq!(params["client_id"], "client id");
+    assert_eq!(params["scope"], "openid email");
+    assert_eq!(params["state"], "a&b=c");
+    assert_eq!(params["nonce"], "n+1");
+    assert_eq!(params["code_challenge"], "challenge");
+    assert_eq!(params["code_challenge_method"], "S256");
+    assert_eq!(params["login_hint"], "alice+test@example.com");
+    assert_eq!(params["hd"], "example.com");
+    assert_eq!(params["include_granted_scopes"], "true");
+    assert_eq!(params["ui_locales"], "ja en");
+}
+
+#[test]
+fn test_existing_query_is_kept() {
+    let url = AuthorizationRequest::new(
+        "https://issuer.example/authorize?tenant=a",
+        "client",
+        "https://app.example/cb",
+    )
+    .build()
+    .unwrap();
+    assert_eq!(params(&url)["tenant"], "a");
+}
+
+#[test]
+fn test_validation() {
+    // The extras can't replace standard parameters.
+    assert!(request().extra_param("state", "x").build().is_err());
+    assert!(request().extra_param("hd", "x").build().is_err());
+    // An ID token needs a nonce.
+    assert!(request()
+        .response_type(ResponseType::CodeIdToken)
+        .response_mode(ResponseMode::FormPost)
+        .build()
+        .is_err());
+    assert!(
+        AuthorizationRequest::new("not a url", "client", "https://app.example/cb")
+            .build()
+            .is_err()
+    );
+    assert!(
+        AuthorizationRequest::new("ftp://issuer.example/authorize", "client", "https://a.b/")
+            .build()
+            .is_err()
+    );
+    assert!(AuthorizationRequest::new(
+        "https://issuer.example/authorize#x",
+        "client",
+        "https://a.b/"
+    )
+    .build()
+    .is_err());
+    assert!(
+        AuthorizationRequest::new(AUTH_URL, "", "https://app.example/cb")
+            .build()
+            .is_err()
+    );
+    assert!(AuthorizationRequest::new(AUTH_URL, "client", "/relative")
+        .build()
+        .is_err());
+}
@@ -0,0 +1,69 @@
+mod common;
+
+// Both token backends against the mock issuer: code exchange with PKCE, then refresh.
+use axum::http::{header, StatusCode};
+use axum_oauth2_auth::{
+    backend::{http_client, Backend},
+    create_router,
+    provider::ProviderConfig,
+    roles::RoleGrants,
+};
+use common::{
+    body_string, get, login, mock_config, test_state, MockIssuer, MockOptions, TEST_EMAIL,
+};
+
+async fn login_and_refresh(backend: Backend) {
+    let mock = MockIssuer::start().await;
+    let config = ProviderConfig {
+        offline: true,
+        ..mock_config("mock", &mock.issuer)
+    };
+    let mut state = test_state(vec![config], RoleGrants::default()).await;
+    state.backend = backend;
+    let app = create_router(state);
+
+    let session_cookie = login(&app, "mock", &mock).await;
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_string(response).await;
+    assert!(body.contains(TEST_EMAIL));
+    assert!(body.contains("Access token expires at"));
+    assert_eq!(mock.refreshes(), 1);
+}
+
+#[tokio::test]
+async fn test_http_backend() {
+    login_and_refresh(Backend::http(http_client().unwrap())).await;
+}
+
+#[tokio::test]
+async fn test_oauth2_crate_backend() {
+    login_and_refresh(Backend::oauth2(http_client().unwrap())).await;
+}
+
+#[tokio::test]
+async fn test_token_response_without_expires_in() {
+    for backend in [
+        Backend::http(http_client().unwrap()),
+        Backend::oauth2(http_client().unwrap()),
+    ] {
+        let mock = MockIssuer::start_with(MockOptions {
+            expires_in: None,
+            ..MockOptions::default()
+        })
+        .await;
+        let config = ProviderConfig {
+            offline: true,
+            ..mock_config("mock", &mock.issuer)
+        };
+        let mut state = test_state(vec![config], RoleGrants::default()).await;
+        state.backend = backend;
+        let app = create_router(state);
+
+        let session_cookie = login(&app, "mock", &mock).await;
+        let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // Taken to last long enough not to need a refresh yet.
+        assert_eq!(mock.refreshes(), 0);
+    }
+}
@@ -0,0 +1,673 @@
+#![allow(dead_code)] // Not every test file uses every helper.
+
+// A minimal OpenID Connect issuer for the tests: discovery, JWKS, an authorization
+// endpoint that approves every request and a token endpoint that checks PKCE and
+// hands out short lived access tokens plus refresh tokens for `offline_access`.
+// It records revocations and signs back-channel logout tokens on request.
+use async_session::MemoryStore;
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, Request, Response, StatusCode},
+    response::{Html, IntoResponse, Redirect},
+    routing::post,
+    Form, Json, Router,
+};
+use axum_oauth2_auth::{
+    audit::AuditLog,
+    backend::{http_client, Backend},
+    cookies::CookieKeys,
+    create_router,
+    domains::DomainAllowlist,
+    flash::{Flash, FLASH_COOKIE_NAME},
+    logout::LogoutConfig,
+    popup::PopupOrigins,
+    provider::{ProviderConfig, ProviderKind, Providers},
+    roles::RoleGrants,
+    session_store::AppSessionStore,
+    tokens::TokenCipher,
+    users::UserStore,
+    AppState, ResponseMode, ResponseType, COOKIE_NAME, CSRF_COOKIE_NAME,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use http_body_util::BodyExt; // for `collect`
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::net::TcpListener;
+use tower::ServiceExt; // for `app.oneshot()`
+use url::Url;
+
+static TEST_KEY_PEM: &str = include_str!("test_key.pem");
+static TEST_KEY_N: &str = "nwn1GnKcS8tgs-0iLLFgC-bk78-8IAuWVoYJMmSR3FbVVa74UUHjxR-zT5iP7u4m7QwfCdKdqgQZGq-keY9RYDlpt4SzGtjUDSWt-EnA1k9Ww825BwijizntT1tu4S9jSkUuvBBDmkJ4gS2v6tmswyOZhEYaTLDgIUg9aucysRXJOrKzkhg95llppUTQPT_tYYCc0D9pScadBP0C0QDNZF57O7inphTEN5cPM9nYrv443wGkE1Hi6IvM2zM9MsL5skCoBK1kspyr2KsgmVDoDBaw7WtbuiGYz-WPJffWq-1hXICm9SPzl2vx4Trfcez8VBPjuSa-wKM8waDzMaqpKQ";
+static TEST_KEY_ID: &str = "test-key";
+
+pub static TEST_EMAIL: &str = "alice@example.com";
+// Below the app's refresh skew, so every request after login triggers a refresh.
+static ACCESS_TOKEN_EXPIRES_IN: i64 = 30;
+
+struct PendingCode {
+    client_id: String,
+    nonce: String,
+    code_challenge: String,
+    offline: bool,
+    sid: String,
+}
+
+// What the mock puts in its responses, for tests that need something else.
+#[derive(Clone)]
+pub struct MockOptions {
+    // Left out of token responses when None.
+    pub expires_in: Option<i64>,
+    // The `hd` claim of alice's ID tokens, as for a Workspace account.
+    pub hd: Option<&'static str>,
+}
+
+impl Default for MockOptions {
+    fn default() -> Self {
+        Self {
+            expires_in: Some(ACCESS_TOKEN_EXPIRES_IN),
+            hd: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct MockState {
+    issuer: String,
+    options: MockOptions,
+    codes: Arc<Mutex<HashMap<String, PendingCode>>>,
+    refreshes: Arc<AtomicUsize>,
+    logins: Arc<AtomicUsize>,
+    revocations: Arc<Mutex<Vec<(String, String)>>>,
+    jwks_fetches: Arc<AtomicUsize>,
+}
+
+pub struct MockIssuer {
+    pub issuer: String,
+    refreshes: Arc<AtomicUsize>,
+    revocations: Arc<Mutex<Vec<(String, String)>>>,
+    jwks_fetches: Arc<AtomicUsize>,
+}
+
+impl MockIssuer {
+    pub async fn start() -> Self {
+        Self::start_with(MockOptions::default()).await
+    }
+
+    pub async fn start_with(options: MockOptions) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let revocations = Arc::new(Mutex::new(vec![]));
+        let jwks_fetches = Arc::new(AtomicUsize::new(0));
+        let state = MockState {
+            issuer: issuer.clone(),
+            options,
+            codes: Arc::new(Mutex::new(HashMap::new())),
+            refreshes: refreshes.clone(),
+            logins: Arc::new(AtomicUsize::new(0)),
+            revocations: revocations.clone(),
+            jwks_fetches: jwks_fetches.clone(),
+        };
+        let app = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                axum::routing::get(discovery),
+            )
+            .route("/jwks", axum::routing::get(jwks))
+            .route("/authorize", axum::routing::get(authorize))
+            .route("/token", post(token))
+            .route("/revoke", post(revoke))
+            .with_state(state);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        Self {
+            issuer,
+            refreshes,
+            revocations,
+            jwks_fetches,
+        }
+    }
+
+    pub fn refreshes(&self) -> usize {
+        self.refreshes.load(Ordering::SeqCst)
+    }
+
+    pub fn jwks_fetches(&self) -> usize {
+        self.jwks_fetches.load(Ordering::SeqCst)
+    }
+
+    // (token, token_type_hint) of every revocation request, in order.
+    pub fn revocations(&self) -> Vec<(String, String)> {
+        self.revocations.lock().unwrap().clone()
+    }
+
+    // The `sid` in the ID tokens of the n-th login, counting from 0.
+    pub fn sid(&self, login: usize) -> String {
+        format!("mock-sid-{}", login)
+    }
+
+    // A back-channel logout token for `sub` and/or `sid`, with `claims` merged over it.
+    pub fn logout_token(
+        &self,
+        sub: Option<&str>,
+        sid: Option<&str>,
+        claims: serde_json::Value,
+    ) -> String {
+        self.logout_token_with_kid(TEST_KEY_ID, sub, sid, claims)
+    }
+
+    // The same, naming the signing key `kid`, e.g. one the JWKS doesn't have.
+    pub fn logout_token_with_kid(
+        &self,
+        kid: &str,
+        sub: Option<&str>,
+        sid: Option<&str>,
+        claims: serde_json::Value,
+    ) -> String {
+        let mut token = json!({
+            "iss": self.issuer,
+            "aud": "test-client",
+            "iat": chrono::Utc::now().timestamp(),
+            "jti": "logout-token",
+            "events": {"http://schemas.openid.net/event/backchannel-logout": {}},
+        });
+        if let Some(sub) = sub {
+            token["sub"] = json!(sub);
+        }
+        if let Some(sid) = sid {
+            token["sid"] = json!(sid);
+        }
+        for (name, value) in claims.as_object().unwrap() {
+            token[name] = value.clone();
+        }
+        sign_with_kid(&token, kid)
+    }
+}
+
+async fn discovery(State(state): State<MockState>) -> impl IntoResponse {
+    Json(json!({
+        "issuer": state.issuer,
+        "authorization_endpoint": format!("{}/authorize", state.issuer),
+        "token_endpoint": format!("{}/token", state.issuer),
+        "jwks_uri": format!("{}/jwks", state.issuer),
+        "revocation_endpoint": format!("{}/revoke", state.issuer),
+        "end_session_endpoint": format!("{}/logout", state.issuer),
+    }))
+}
+
+async fn jwks(State(state): State<MockState>) -> impl IntoResponse {
+    state.jwks_fetches.fetch_add(1, Ordering::SeqCst);
+    Json(json!({
+        "keys": [{
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": TEST_KEY_ID,
+            "n": TEST_KEY_N,
+            "e": "AQAB",
+        }]
+    }))
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    client_id: String,
+    redirect_uri: String,
+    response_type: String,
+    response_mode: Option<String>,
+    state: String,
+    scope: String,
+    nonce: String,
+    code_challenge: String,
+    code_challenge_method: String,
+}
+
+async fn authorize(
+    State(state): State<MockState>,
+    Query(query): Query<AuthorizeQuery>,
+) -> impl IntoResponse {
+    assert_eq!(query.code_challenge_method, "S256");
+    let response_types: Vec<&str> = query.response_type.split(' ').collect();
+    let mut params = vec![("state", query.state.clone())];
+
+    let code = format!("code-{}", state.codes.lock().unwrap().len());
+    let sid = format!("mock-sid-{}", state.logins.fetch_add(1, Ordering::SeqCst));
+    if response_types.contains(&"code") {
+        state.codes.lock().unwrap().insert(
+            code.clone(),
+            PendingCode {
+                client_id: query.client_id.clone(),
+                nonce: query.nonce.clone(),
+                code_challenge: query.code_challenge,
+                offline: query.scope.split(' ').any(|s| s == "offline_access"),
+                sid: sid.clone(),
+            },
+        );
+        params.push(("code", code.clone()));
+    }
+    if response_types.contains(&"id_token") {
+        let c_hash = response_types.contains(&"code").then(|| {
+            let digest = Sha256::digest(code.as_bytes());
+            URL_SAFE_NO_PAD.encode(&digest[..16])
+        });
+        params.push((
+            "id_token",
+            id_token(&state, &query.client_id, &query.nonce, c_hash, &sid),
+        ));
+    }
+
+    if query.response_mode.as_deref() == Some("form_post") {
+        // What a provider sends to the browser: a form that posts itself to the client.
+        let inputs: String = params
+            .iter()
+            .map(|(name, value)| format!(r#"<input type="hidden" name="{name}" value="{value}">"#))
+            .collect();
+        return Html(format!(
+            r#"<form method="post" action="{}">{}</form>"#,
+            query.redirect_uri, inputs
+        ))
+        .into_response();
+    }
+    let query_string: Vec<String> = params
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    Redirect::to(&format!(
+        "{}?{}",
+        query.redirect_uri,
+        query_string.join("&")
+    ))
+    .into_response()
+}
+
+fn id_token(
+    state: &MockState,
+    client_id: &str,
+    nonce: &str,
+    c_hash: Option<String>,
+    sid: &str,
+) -> String {
+    let now = chrono::Utc::now().timestamp();
+    let mut claims = json!({
+        "iss": state.issuer,
+        "sub": "alice",
+        "aud": client_id,
+        "exp": now + 300,
+        "iat": now,
+        "nonce": nonce,
+        "c_hash": c_hash,
+        "email": TEST_EMAIL,
+        "email_verified": true,
+        "preferred_username": "alice",
+        "sid": sid,
+    });
+    if let Some(hd) = state.options.hd {
+        claims["hd"] = json!(hd);
+    }
+    sign(&claims)
+}
+
+fn sign(claims: &serde_json::Value) -> String {
+    sign_with_kid(claims, TEST_KEY_ID)
+}
+
+fn sign_with_kid(claims: &serde_json::Value, kid: &str) -> String {
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(kid.to_string());
+    let key = EncodingKey::from_rsa_pem(TEST_KEY_PEM.as_bytes()).unwrap();
+    encode(&header, claims, &key).unwrap()
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    grant_type: String,
+    client_id: String,
+    code: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+}
+
+async fn token(State(state): State<MockState>, Form(form): Form<TokenForm>) -> impl IntoResponse {
+    if form.grant_type == "refresh_token" {
+        if form.refresh_token.as_deref() != Some("mock-refresh-token") {
+            return (StatusCode::BAD_REQUEST, "invalid_grant").into_response();
+        }
+        let n = state.refreshes.fetch_add(1, Ordering::SeqCst) + 1;
+        return Json(json!({
+            "access_token": format!("mock-access-token-{}", n),
+            "token_type": "Bearer",
+            "expires_in": state.options.expires_in,
+        }))
+        .into_response();
+    }
+
+    let code = form.code.unwrap_or_default();
+    let Some(pending) = state.codes.lock().unwrap().remove(&code) else {
+        return (StatusCode::BAD_REQUEST, "invalid_grant").into_response();
+    };
+    let verifier = form.code_verifier.unwrap_or_default();
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    if challenge != pending.code_challenge || form.client_id != pending.client_id {
+        return (StatusCode::BAD_REQUEST, "invalid_grant").into_response();
+    }
+
+    Json(json!({
+        "access_token": "mock-access-token",
+        "token_type": "Bearer",
+        "expires_in": state.options.expires_in,
+        "refresh_token": pending.offline.then_some("mock-refresh-token"),
+        "scope": "openid email profile",
+        "id_token": id_token(&state, &pending.client_id, &pending.nonce, None, &pending.sid),
+    }))
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct RevokeForm {
+    token: String,
+    token_type_hint: String,
+}
+
+async fn revoke(State(state): State<MockState>, Form(form): Form<RevokeForm>) -> StatusCode {
+    state
+        .revocations
+        .lock()
+        .unwrap()
+        .push((form.token, form.token_type_hint));
+    StatusCode::OK
+}
+
+pub static USER_AGENT: &str = "provider-tests";
+// The app's own origin, the only one allowed to open the login popup.
+pub static TEST_ORIGIN: &str = "https://localhost";
+
+pub fn mock_config(name: &str, issuer: &str) -> ProviderConfig {
+    ProviderConfig {
+        name: name.to_string(),
+        kind: ProviderKind::Generic,
+        issuer: issuer.to_string(),
+        client_id: "test-client".to_string(),
+        client_secret: None,
+        scope: "openid email profile".to_string(),
+        redirect_uri: "https://localhost/auth/authorized".to_string(),
+        offline: false,
+        response_type: ResponseType::Code,
+        response_mode: ResponseMode::Query,
+        auth_params: vec![],
+        logout: LogoutConfig::default(),
+    }
+}
+
+pub async fn test_app(configs: Vec<ProviderConfig>) -> Router {
+    test_app_with_grants(configs, RoleGrants::default()).await
+}
+
+pub async fn test_app_with_grants(configs: Vec<ProviderConfig>, role_grants: RoleGrants) -> Router {
+    create_router(test_state(configs, role_grants).await)
+}
+
+// The state behind `test_app`, for tests that need to reach into the stores.
+pub async fn test_state(configs: Vec<ProviderConfig>, role_grants: RoleGrants) -> AppState {
+    let client = http_client().unwrap();
+    let providers = Providers::discover(configs, &client).await.unwrap();
+    let users = UserStore::in_memory().await.unwrap();
+    AppState {
+        store: AppSessionStore::Memory(MemoryStore::new()),
+        providers,
+        cipher: TokenCipher::random(),
+        cookie_keys: cookie_keys(),
+        audit: AuditLog::new(Some(users.clone())),
+        users,
+        role_grants,
+        domains: DomainAllowlist::default(),
+        popup_origins: PopupOrigins::new(&[TEST_ORIGIN]).unwrap(),
+        backend: Backend::http(client),
+    }
+}
+
+pub async fn get(
+    app: &Router,
+    uri: &str,
+    headers: &[(header::HeaderName, &str)],
+) -> Response<Body> {
+    let mut request = Request::builder().uri(uri);
+    for (name, value) in headers {
+        request = request.header(name, *value);
+    }
+    app.clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+pub async fn body_string(response: Response<Body>) -> String {
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+pub fn location(response: &Response<Body>) -> String {
+    response.headers()[header::LOCATION]
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+// "name=value; ..." of the Set-Cookie header for `name`, as a Cookie header value.
+pub fn cookie(response: &Response<Body>, name: &str) -> String {
+    response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .find(|v| v.starts_with(&format!("{}=", name)))
+        .and_then(|v| v.split(';').next())
+        .unwrap()
+        .to_string()
+}
+
+// Runs the whole authorization code flow against `mock` and returns the session cookie.
+pub async fn login(app: &Router, provider: &str, mock: &MockIssuer) -> String {
+    login_with(app, provider, mock, None).await
+}
+
+// Like `login`, optionally while already logged in with `session_cookie`.
+pub async fn login_with(
+    app: &Router,
+    provider: &str,
+    mock: &MockIssuer,
+    session_cookie: Option<&str>,
+) -> String {
+    let (csrf_cookie, callback) = start_login(app, provider, mock, session_cookie).await;
+    let referer = format!("{}/", mock.issuer);
+    let response = get(
+        app,
+        &callback,
+        &[
+            (header::USER_AGENT, USER_AGENT),
+            (header::COOKIE, &csrf_cookie),
+            (header::REFERER, &referer),
+        ],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    cookie(&response, COOKIE_NAME)
+}
+
+// The first half of `login_with`: returns the CSRF cookie and the path and query of the
+// callback the mock issuer redirects the browser to.
+pub async fn start_login(
+    app: &Router,
+    provider: &str,
+    mock: &MockIssuer,
+    session_cookie: Option<&str>,
+) -> (String, String) {
+    start_login_at(app, &format!("/auth/{}", provider), mock, session_cookie).await
+}
+
+// `start_login` from a login URL with a query string, e.g. for popup mode.
+pub async fn start_login_at(
+    app: &Router,
+    login_path: &str,
+    mock: &MockIssuer,
+    session_cookie: Option<&str>,
+) -> (String, String) {
+    let mut headers = vec![(header::USER_AGENT, USER_AGENT)];
+    if let Some(session_cookie) = session_cookie {
+        headers.push((header::COOKIE, session_cookie));
+    }
+    let response = get(app, login_path, &headers).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let csrf_cookie = cookie(&response, CSRF_COOKIE_NAME);
+    let auth_url = location(&response);
+    assert!(auth_url.starts_with(&format!("{}/authorize?", mock.issuer)));
+    assert!(auth_url.contains("code_challenge_method=S256"));
+
+    // Play the browser: the mock issuer approves and redirects back with a code.
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let callback = client.get(&auth_url).send().await.unwrap();
+    let callback = Url::parse(callback.headers()["location"].to_str().unwrap()).unwrap();
+    let path_and_query = format!("{}?{}", callback.path(), callback.query().unwrap());
+    (csrf_cookie, path_and_query)
+}
+
+// The cookie keys of `test_app`, so tests can look inside (or forge) private cookies.
+pub const TEST_COOKIE_KEY: [u8; 32] = [7; 32];
+
+pub fn cookie_keys() -> CookieKeys {
+    CookieKeys::new(&[TEST_COOKIE_KEY]).unwrap()
+}
+
+// The form token of a logged in session, from the logout form on its pages.
+pub async fn form_token(app: &Router, session_cookie: &str) -> String {
+    let response = get(app, "/logout", &[(header::COOKIE, session_cookie)]).await;
+    let body = body_string(response).await;
+    let (_, rest) = body
+        .split_once(r#"name="csrf_token" value=""#)
+        .expect("no logout form");
+    rest.split('"').next().unwrap().to_string()
+}
+
+// Submits the logout form of `session_cookie`'s pages.
+// A form of the logged in user's pages, as the browser would post it.
+pub async fn post_session_form(app: &Router, path: &str, session_cookie: &str) -> Response<Body> {
+    let csrf_token = form_token(app, session_cookie).await;
+    post_form(
+        app,
+        path,
+        &[("csrf_token".to_string(), csrf_token)],
+        &[(header::COOKIE, session_cookie)],
+    )
+    .await
+}
+
+pub async fn logout(
+    app: &Router,
+    session_cookie: &str,
+    headers: &[(header::HeaderName, &str)],
+) -> Response<Body> {
+    let csrf_token = form_token(app, session_cookie).await;
+    let mut all = vec![(header::COOKIE, session_cookie)];
+    all.extend_from_slice(headers);
+    post_form(
+        app,
+        "/logout",
+        &[("csrf_token".to_string(), csrf_token)],
+        &all,
+    )
+    .await
+}
+
+// The flash message a response leaves for the next page, if any.
+pub fn flash(response: &Response<Body>) -> Option<Flash> {
+    let set_cookie = response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .find(|v| v.starts_with(&format!("{}=", FLASH_COOKIE_NAME)))?;
+    let value = set_cookie.split(';').next()?.split_once('=')?.1;
+    let value = cookie_keys().open(FLASH_COOKIE_NAME, value).ok()?;
+    serde_json::from_str(&value).ok()
+}
+
+// The store's session id for a "name=value" session cookie.
+pub fn session_id(cookie: &str) -> String {
+    let (name, value) = cookie.split_once('=').unwrap();
+    let value = cookie_keys().open(name, value).unwrap();
+    async_session::Session::id_from_cookie_value(&value).unwrap()
+}
+
+pub async fn post_form(
+    app: &Router,
+    uri: &str,
+    form: &[(String, String)],
+    headers: &[(header::HeaderName, &str)],
+) -> Response<Body> {
+    let body = form
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, urlencoding::encode(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let mut request = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+    for (name, value) in headers {
+        request = request.header(name, *value);
+    }
+    app.clone()
+        .oneshot(request.body(Body::from(body)).unwrap())
+        .await
+        .unwrap()
+}
+
+// Starts a login whose response comes back as a form_post. Returns the CSRF cookie (as a
+// Set-Cookie value), and the fields of the form the provider would make the browser post.
+pub async fn start_form_post_login(
+    app: &Router,
+    provider: &str,
+) -> (String, Vec<(String, String)>) {
+    let response = get(
+        app,
+        &format!("/auth/{}", provider),
+        &[(header::USER_AGENT, USER_AGENT)],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let set_cookie = response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .map(|v| v.to_str().unwrap().to_string())
+        .find(|v| v.starts_with(CSRF_COOKIE_NAME))
+        .unwrap();
+    let auth_url = location(&response);
+    assert!(auth_url.contains("response_mode=form_post"));
+
+    let html = reqwest::get(&auth_url).await.unwrap().text().await.unwrap();
+    let fields = html
+        .split("<input ")
+        .skip(1)
+        .map(|input| {
+            let attr = |name: &str| {
+                let start = input.find(&format!(r#"{}=""#, name)).unwrap() + name.len() + 2;
+                let end = start + input[start..].find('"').unwrap();
+                input[start..end].to_string()
+            };
+            (attr("name"), attr("value"))
+        })
+        .collect();
+    (set_cookie, fields)
+}
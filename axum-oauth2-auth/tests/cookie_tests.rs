@@ -0,0 +1,193 @@
+mod common;
+
+use axum::http::{header, HeaderMap, StatusCode};
+use axum_extra::headers::{self, HeaderMapExt};
+use axum_oauth2_auth::{
+    cookies::{CookieKeys, SameSite, SetCookie},
+    COOKIE_NAME,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Duration, Utc};
+use common::{get, login, mock_config, test_app, MockIssuer, TEST_COOKIE_KEY};
+
+fn cookie_header(value: &str) -> headers::Cookie {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::COOKIE, value.parse().unwrap());
+    headers.typed_get().unwrap()
+}
+
+// The attributes of a Set-Cookie value, keyed by name.
+fn attributes(set_cookie: &str) -> Vec<(String, String)> {
+    set_cookie
+        .split(';')
+        .skip(1)
+        .map(|attribute| {
+            let (name, value) = attribute.trim().split_once('=').unwrap_or((attribute, ""));
+            (name.trim().to_string(), value.to_string())
+        })
+        .collect()
+}
+
+fn attribute(set_cookie: &str, name: &str) -> Option<String> {
+    attributes(set_cookie)
+        .into_iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, value)| value)
+}
+
+#[test]
+fn test_set_cookie_attributes() {
+    let set_cookie = SetCookie::new("__Host-Test", "value".to_string(), 600)
+        .same_site(SameSite::None)
+        .to_string();
+    assert!(set_cookie.starts_with("__Host-Test=value;"));
+    assert_eq!(attribute(&set_cookie, "SameSite").unwrap(), "None");
+    assert_eq!(attribute(&set_cookie, "Path").unwrap(), "/");
+    assert_eq!(attribute(&set_cookie, "Max-Age").unwrap(), "600");
+    assert!(attribute(&set_cookie, "Secure").is_some());
+    assert!(attribute(&set_cookie, "HttpOnly").is_some());
+    assert!(attribute(&set_cookie, "Domain").is_none());
+
+    // Expires agrees with Max-Age.
+    let expires = DateTime::parse_from_rfc2822(&attribute(&set_cookie, "Expires").unwrap())
+        .unwrap()
+        .with_timezone(&Utc);
+    let expected = Utc::now() + Duration::seconds(600);
+    assert!((expected - expires).num_seconds().abs() <= 2);
+
+    let removal = SetCookie::removal("__Host-Test").to_string();
+    assert_eq!(attribute(&removal, "Max-Age").unwrap(), "0");
+    assert_eq!(
+        attribute(&removal, "Expires").unwrap(),
+        "Thu, 01 Jan 1970 00:00:00 GMT"
+    );
+}
+
+#[test]
+fn test_host_prefix_rules() {
+    let cookie = SetCookie::new("__Host-Test", "value".to_string(), 600);
+    assert!(cookie.header_value().is_ok());
+    assert!(cookie.clone().domain("example.com").header_value().is_err());
+    assert!(cookie.path("/app").header_value().is_err());
+
+    // Other cookies may be scoped.
+    let cookie = SetCookie::new("Test", "value".to_string(), 600)
+        .domain("example.com")
+        .path("/app");
+    let set_cookie = cookie.header_value().unwrap();
+    let set_cookie = set_cookie.to_str().unwrap();
+    assert_eq!(attribute(set_cookie, "Domain").unwrap(), "example.com");
+    assert_eq!(attribute(set_cookie, "Path").unwrap(), "/app");
+}
+
+#[test]
+fn test_private_cookie_round_trip() {
+    let keys = CookieKeys::new(&[TEST_COOKIE_KEY]).unwrap();
+    let set_cookie = SetCookie::private(&keys, "__Host-Test", "secret value", 600)
+        .unwrap()
+        .to_string();
+    let pair = set_cookie.split(';').next().unwrap();
+    assert!(!pair.contains("secret"));
+
+    let cookies = cookie_header(&format!("other=1; {}; another=2", pair));
+    assert_eq!(
+        keys.get(&cookies, "__Host-Test").as_deref(),
+        Some("secret value")
+    );
+    assert_eq!(keys.get(&cookies, "__Host-Missing"), None);
+}
+
+#[test]
+fn test_private_cookie_rejects_tampering() {
+    let keys = CookieKeys::new(&[TEST_COOKIE_KEY]).unwrap();
+    let sealed = keys.seal("__Host-Test", "secret value").unwrap();
+    assert_eq!(keys.open("__Host-Test", &sealed).unwrap(), "secret value");
+
+    // Every single flipped character is caught.
+    for i in 0..sealed.len() {
+        let mut tampered = sealed.clone().into_bytes();
+        tampered[i] = if tampered[i] == b'A' { b'B' } else { b'A' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        if tampered != sealed {
+            assert!(keys.open("__Host-Test", &tampered).is_err());
+        }
+    }
+    assert!(keys
+        .open("__Host-Test", &sealed[..sealed.len() - 1])
+        .is_err());
+    assert!(keys.open("__Host-Test", "").is_err());
+    assert!(keys.open("__Host-Test", "not base64!").is_err());
+    // A value sealed for one cookie is no good in another.
+    assert!(keys.open("__Host-Other", &sealed).is_err());
+    // Nor under another key.
+    assert!(CookieKeys::random().open("__Host-Test", &sealed).is_err());
+}
+
+#[test]
+fn test_cookie_key_rotation() {
+    let old = [1u8; 32];
+    let new = [2u8; 32];
+    let sealed_old = CookieKeys::new(&[old])
+        .unwrap()
+        .seal("__Host-Test", "value")
+        .unwrap();
+
+    let rotated = CookieKeys::new(&[new, old]).unwrap();
+    assert_eq!(rotated.open("__Host-Test", &sealed_old).unwrap(), "value");
+    // New cookies use the first key, so they survive dropping the old one.
+    let sealed_new = rotated.seal("__Host-Test", "value").unwrap();
+    let retired = CookieKeys::new(&[new]).unwrap();
+    assert_eq!(retired.open("__Host-Test", &sealed_new).unwrap(), "value");
+    assert!(retired.open("__Host-Test", &sealed_old).is_err());
+}
+
+#[test]
+fn test_cookie_keys_parse() {
+    let keys = format!(
+        "{}, {}",
+        STANDARD.encode([2u8; 32]),
+        STANDARD.encode([1u8; 32])
+    );
+    let sealed = CookieKeys::new(&[[1u8; 32]])
+        .unwrap()
+        .seal("name", "value")
+        .unwrap();
+    assert_eq!(
+        CookieKeys::parse(&keys)
+            .unwrap()
+            .open("name", &sealed)
+            .unwrap(),
+        "value"
+    );
+    assert!(CookieKeys::parse("").is_err());
+    assert!(CookieKeys::parse("not base64!").is_err());
+    assert!(CookieKeys::parse(&STANDARD.encode([1u8; 16])).is_err());
+}
+
+#[tokio::test]
+async fn test_tampered_session_cookie_is_rejected() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+    let session_cookie = login(&app, "mock", &mock).await;
+
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let (name, value) = session_cookie.split_once('=').unwrap();
+    let mut tampered = value.to_string().into_bytes();
+    tampered[20] = if tampered[20] == b'A' { b'B' } else { b'A' };
+    let tampered = format!("{}={}", name, String::from_utf8(tampered).unwrap());
+    let response = get(&app, "/protected", &[(header::COOKIE, &tampered)]).await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+
+    // A raw session id, as cookies used to carry, is not accepted either.
+    let keys = CookieKeys::new(&[TEST_COOKIE_KEY]).unwrap();
+    let raw = keys.open(name, value).unwrap();
+    let response = get(
+        &app,
+        "/protected",
+        &[(header::COOKIE, &format!("{}={}", COOKIE_NAME, raw))],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+}
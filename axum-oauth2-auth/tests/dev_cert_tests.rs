@@ -0,0 +1,95 @@
+use axum::{routing::get, Router};
+use axum_oauth2_auth::dev_certs::{ensure_dev_certs, fingerprint};
+use axum_server::tls_rustls::RustlsConfig;
+use std::{fs, path::PathBuf};
+
+// A fresh directory per test; the certificates are only ever written by the tests.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "axum-google-oauth2-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn test_generates_once() {
+    let dir = temp_dir("generates-once");
+    let first = ensure_dev_certs(&dir).unwrap();
+    for path in [&first.ca_path, &first.cert_path, &first.key_path] {
+        assert!(path.exists(), "{} missing", path.display());
+    }
+    assert!(dir.join("ca-key.pem").exists());
+    assert_eq!(first.ca_fingerprint.split(':').count(), 32);
+    assert_eq!(
+        first.ca_fingerprint,
+        fingerprint(&fs::read_to_string(&first.ca_path).unwrap()).unwrap()
+    );
+
+    // Nothing changes on the next run.
+    let cert = fs::read_to_string(&first.cert_path).unwrap();
+    let second = ensure_dev_certs(&dir).unwrap();
+    assert_eq!(second.ca_fingerprint, first.ca_fingerprint);
+    assert_eq!(fs::read_to_string(&second.cert_path).unwrap(), cert);
+
+    // A deleted leaf is reissued by the same CA.
+    fs::remove_file(&first.cert_path).unwrap();
+    let third = ensure_dev_certs(&dir).unwrap();
+    assert_eq!(third.ca_fingerprint, first.ca_fingerprint);
+    assert_ne!(fs::read_to_string(&third.cert_path).unwrap(), cert);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&first.key_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o077, 0);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_clients_can_trust_the_ca() {
+    let dir = temp_dir("trust");
+    ensure_dev_certs(&dir).unwrap();
+    // Reissue the leaf, to check it still chains up to the CA from the first run.
+    fs::remove_file(dir.join("cert.pem")).unwrap();
+    let dev_certs = ensure_dev_certs(&dir).unwrap();
+
+    let config = RustlsConfig::from_pem_file(&dev_certs.cert_path, &dev_certs.key_path)
+        .await
+        .unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let app = Router::new().route("/", get(|| async { "hello" }));
+    tokio::spawn(async move {
+        axum_server::from_tcp_rustls(listener, config)
+            .serve(app.into_make_service())
+            .await
+            .unwrap();
+    });
+
+    let ca = reqwest::Certificate::from_pem(&fs::read(&dev_certs.ca_path).unwrap()).unwrap();
+    let client = reqwest::Client::builder()
+        .add_root_certificate(ca)
+        .build()
+        .unwrap();
+    for host in ["localhost", "127.0.0.1"] {
+        let body = client
+            .get(format!("https://{}:{}/", host, port))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, "hello");
+    }
+
+    // Without the CA, the certificate is not trusted.
+    assert!(reqwest::get(format!("https://localhost:{}/", port))
+        .await
+        .is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
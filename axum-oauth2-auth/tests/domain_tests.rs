@@ -0,0 +1,343 @@
+mod common;
+
+// The hosted domain and email allowlist.
+use axum::http::{header, StatusCode};
+use axum_oauth2_auth::{
+    audit::{AuthEventFilter, AuthEventKind},
+    create_router,
+    domains::{DomainAllowlist, DomainRules},
+    id_token::IdTokenClaims,
+    provider::{ProviderConfig, ProviderKind},
+    roles::RoleGrants,
+    AppState, COOKIE_NAME,
+};
+use common::{
+    body_string, get, location, login, mock_config, start_login_at, test_state, MockIssuer,
+    MockOptions, TEST_EMAIL, TEST_ORIGIN, USER_AGENT,
+};
+use serde_json::json;
+use std::{fs, path::PathBuf};
+use url::Url;
+
+fn claims(email: &str, email_verified: bool, hd: Option<&str>) -> IdTokenClaims {
+    serde_json::from_value(json!({
+        "iss": "https://issuer.example",
+        "sub": "alice",
+        "aud": "client",
+        "exp": 0,
+        "iat": 0,
+        "email": email,
+        "email_verified": email_verified,
+        "hd": hd,
+    }))
+    .unwrap()
+}
+
+fn allowed(rules: &str, claims: &IdTokenClaims) -> bool {
+    DomainRules::parse(rules)
+        .unwrap()
+        .check(ProviderKind::Google, claims)
+        .is_ok()
+}
+
+// A Workspace account of example.com, behind both a Google and a generic provider.
+async fn workspace_issuer() -> MockIssuer {
+    MockIssuer::start_with(MockOptions {
+        hd: Some("example.com"),
+        ..MockOptions::default()
+    })
+    .await
+}
+
+async fn domain_state(mock: &MockIssuer, domains: DomainAllowlist) -> AppState {
+    let google = ProviderConfig {
+        kind: ProviderKind::Google,
+        ..mock_config("google", &mock.issuer)
+    };
+    AppState {
+        domains,
+        ..test_state(
+            vec![google, mock_config("mock", &mock.issuer)],
+            RoleGrants::default(),
+        )
+        .await
+    }
+}
+
+// The callback of a login through the mock, as the browser would send it.
+async fn login_response(
+    app: &axum::Router,
+    login_path: &str,
+    mock: &MockIssuer,
+) -> axum::response::Response {
+    let (csrf_cookie, callback) = start_login_at(app, login_path, mock, None).await;
+    let referer = format!("{}/", mock.issuer);
+    get(
+        app,
+        &callback,
+        &[
+            (header::USER_AGENT, USER_AGENT),
+            (header::COOKIE, &csrf_cookie),
+            (header::REFERER, &referer),
+        ],
+    )
+    .await
+}
+
+fn sets_session_cookie(response: &axum::response::Response) -> bool {
+    response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .any(|v| v.to_str().unwrap().starts_with(COOKIE_NAME))
+}
+
+// A fresh file per test.
+fn temp_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("axum-oauth2-auth-{}-{}", name, std::process::id()))
+}
+
+#[test]
+fn test_parse() {
+    let rules = DomainRules::parse(
+        "# comment\n\n hd = Example.COM \nemail=*@example.org\r\nemail=bob@gmail.com\n",
+    )
+    .unwrap();
+    assert_eq!(rules.hosted_domains(), ["example.com"]);
+    assert!(!rules.is_empty());
+    assert!(DomainRules::parse("").unwrap().is_empty());
+
+    assert!(DomainRules::parse("example.com").is_err());
+    assert!(DomainRules::parse("hd=").is_err());
+    assert!(DomainRules::parse("email=example.com").is_err());
+    assert!(DomainRules::parse("domain=example.com").is_err());
+}
+
+#[test]
+fn test_check() {
+    let workspace = claims("alice@example.com", true, Some("example.com"));
+    let consumer = claims("bob@gmail.com", true, None);
+
+    // No rules, no restriction.
+    assert!(allowed("", &workspace));
+    assert!(allowed("", &consumer));
+
+    assert!(allowed("hd=example.com", &workspace));
+    assert!(allowed("hd=EXAMPLE.com", &workspace));
+    assert!(!allowed("hd=example.com", &consumer));
+    assert!(!allowed("hd=other.example", &workspace));
+    // The email's domain is no hosted domain.
+    assert!(!allowed("hd=gmail.com", &consumer));
+
+    assert!(allowed("email=bob@gmail.com", &consumer));
+    assert!(allowed("email=*@GMAIL.com", &consumer));
+    assert!(allowed("email=b*@*.com", &consumer));
+    assert!(!allowed("email=*@gmail.co", &consumer));
+    assert!(!allowed("email=bob@gmail", &consumer));
+    assert!(!allowed("email=*.bob@gmail.com", &consumer));
+    assert!(allowed("hd=example.com\nemail=bob@gmail.com", &consumer));
+
+    // Only a verified email counts.
+    let unverified = claims("bob@gmail.com", false, None);
+    assert!(!allowed("email=bob@gmail.com", &unverified));
+
+    // Only Google's `hd` counts; other providers may send anything.
+    let rules = DomainRules::parse("hd=example.com\nemail=bob@gmail.com").unwrap();
+    assert!(rules.check(ProviderKind::Generic, &workspace).is_err());
+    assert!(rules.check(ProviderKind::Generic, &consumer).is_ok());
+
+    let rejected = DomainRules::parse("hd=other.example")
+        .unwrap()
+        .check(ProviderKind::Google, &workspace)
+        .unwrap_err();
+    assert_eq!(rejected.email.as_deref(), Some("alice@example.com"));
+    assert_eq!(rejected.hd.as_deref(), Some("example.com"));
+    assert_eq!(rejected.allowed_domains, ["other.example"]);
+}
+
+#[test]
+fn test_hd_param() {
+    let hd_param = |rules: &str| DomainRules::parse(rules).unwrap().hd_param();
+    assert_eq!(hd_param(""), None);
+    assert_eq!(hd_param("hd=example.com").as_deref(), Some("example.com"));
+    assert_eq!(hd_param("hd=a.example\nhd=b.example").as_deref(), Some("*"));
+    assert_eq!(hd_param("hd=example.com\nemail=bob@gmail.com"), None);
+    assert_eq!(hd_param("email=*@example.com"), None);
+}
+
+#[tokio::test]
+async fn test_google_login_asks_for_hosted_domain() {
+    let mock = MockIssuer::start().await;
+    let google = ProviderConfig {
+        kind: ProviderKind::Google,
+        ..mock_config("google", &mock.issuer)
+    };
+    let mut state = test_state(
+        vec![google, mock_config("mock", &mock.issuer)],
+        RoleGrants::default(),
+    )
+    .await;
+    state.domains = DomainAllowlist::new(DomainRules::parse("hd=example.com").unwrap());
+    let app = create_router(state);
+
+    let hd = |path: &'static str| {
+        let app = app.clone();
+        async move {
+            let response = get(&app, path, &[(header::USER_AGENT, USER_AGENT)]).await;
+            Url::parse(&location(&response))
+                .unwrap()
+                .query_pairs()
+                .find(|(k, _)| k == "hd")
+                .map(|(_, v)| v.to_string())
+        }
+    };
+    assert_eq!(hd("/auth/google").await.as_deref(), Some("example.com"));
+    // `hd` means nothing to other providers.
+    assert_eq!(hd("/auth/mock").await, None);
+}
+
+#[tokio::test]
+async fn test_allowed_login() {
+    let mock = workspace_issuer().await;
+    for (rules, path) in [
+        ("hd=example.com", "/auth/google"),
+        ("email=*@example.com", "/auth/google"),
+        ("email=*@example.com", "/auth/mock"),
+    ] {
+        let domains = DomainAllowlist::new(DomainRules::parse(rules).unwrap());
+        let app = create_router(domain_state(&mock, domains).await);
+        let response = login_response(&app, path, &mock).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::SEE_OTHER,
+            "{} {}",
+            rules,
+            path
+        );
+        assert!(sets_session_cookie(&response));
+    }
+}
+
+#[tokio::test]
+async fn test_hosted_domain_only_from_google() {
+    let mock = workspace_issuer().await;
+    let domains = DomainAllowlist::new(DomainRules::parse("hd=example.com").unwrap());
+    let app = create_router(domain_state(&mock, domains).await);
+    let response = login_response(&app, "/auth/mock", &mock).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(!sets_session_cookie(&response));
+}
+
+#[tokio::test]
+async fn test_rejected_login_explains() {
+    let mock = MockIssuer::start().await;
+    let domains = DomainAllowlist::new(DomainRules::parse("hd=other.example").unwrap());
+    let state = domain_state(&mock, domains).await;
+    let app = create_router(state.clone());
+
+    let response = login_response(&app, "/auth/mock", &mock).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(!sets_session_cookie(&response));
+    let body = body_string(response).await;
+    assert!(body.contains("Login not allowed"));
+    assert!(body.contains(&format!("<strong>{}</strong>", TEST_EMAIL)));
+    assert!(body.contains("<strong>other.example</strong>"));
+    assert!(body.contains(r#"<a href="/auth/mock">"#));
+
+    // No account for whoever was turned away.
+    assert!(state.users.get(1).await.unwrap().is_none());
+    let filter = AuthEventFilter {
+        kind: Some(AuthEventKind::LoginFailure),
+        ..AuthEventFilter::default()
+    };
+    let events = state.audit.recent(&filter).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert!(events[0].reason.as_deref().unwrap().contains("not allowed"));
+}
+
+#[tokio::test]
+async fn test_rejected_popup_login() {
+    let mock = MockIssuer::start().await;
+    let domains = DomainAllowlist::new(DomainRules::parse("email=bob@example.com").unwrap());
+    let app = create_router(domain_state(&mock, domains).await);
+
+    let path = format!(
+        "/auth/mock?mode=popup&origin={}",
+        urlencoding::encode(TEST_ORIGIN)
+    );
+    let response = login_response(&app, &path, &mock).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(!sets_session_cookie(&response));
+    let body = body_string(response).await;
+    assert!(body.contains("&quot;error&quot;:&quot;domain_not_allowed&quot;"));
+}
+
+#[tokio::test]
+async fn test_reload() {
+    let mock = workspace_issuer().await;
+    let path = temp_file("domain-allowlist");
+    fs::write(&path, "hd=example.com\n").unwrap();
+    let domains = DomainAllowlist::from_file(&path).unwrap();
+    let app = create_router(domain_state(&mock, domains.clone()).await);
+
+    let response = login_response(&app, "/auth/google", &mock).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+    // The running app sees the new rules.
+    fs::write(&path, "hd=other.example\n").unwrap();
+    domains.reload().unwrap();
+    let response = login_response(&app, "/auth/google", &mock).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // A broken file keeps the rules in place.
+    fs::write(&path, "hd=example.com\nnonsense\n").unwrap();
+    assert!(domains.reload().is_err());
+    assert_eq!(domains.rules().hosted_domains(), ["other.example"]);
+    fs::remove_file(&path).unwrap();
+    assert!(domains.reload().is_err());
+    assert_eq!(domains.rules().hosted_domains(), ["other.example"]);
+
+    // Nor does a missing one start.
+    assert!(DomainAllowlist::from_file(&path).is_err());
+}
+
+#[tokio::test]
+async fn test_reload_ends_sessions_no_longer_allowed() {
+    let mock = workspace_issuer().await;
+    let path = temp_file("domain-allowlist-sessions");
+    fs::write(&path, "email=*@example.com\n").unwrap();
+    let domains = DomainAllowlist::from_file(&path).unwrap();
+    let state = domain_state(&mock, domains.clone()).await;
+    let app = create_router(state.clone());
+
+    let google_cookie = login(&app, "google", &mock).await;
+    let mock_cookie = login(&app, "mock", &mock).await;
+    for cookie in [&google_cookie, &mock_cookie] {
+        let response = get(&app, "/protected", &[(header::COOKIE, cookie)]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // Still allowed through Google's `hd`; the other login only had its email.
+    fs::write(&path, "hd=example.com\n").unwrap();
+    domains.reload().unwrap();
+    let response = get(&app, "/protected", &[(header::COOKIE, &google_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = get(&app, "/protected", &[(header::COOKIE, &mock_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+
+    // Logged out, not just turned away.
+    let filter = AuthEventFilter {
+        kind: Some(AuthEventKind::SessionRevoked),
+        ..AuthEventFilter::default()
+    };
+    let events = state.audit.recent(&filter).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].provider.as_deref(), Some("mock"));
+    assert!(events[0].reason.as_deref().unwrap().contains("not allowed"));
+    // Allowed again, the session doesn't come back.
+    fs::write(&path, "email=*@example.com\n").unwrap();
+    domains.reload().unwrap();
+    fs::remove_file(&path).unwrap();
+    let response = get(&app, "/protected", &[(header::COOKIE, &mock_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+}
@@ -0,0 +1,219 @@
+mod common;
+
+// The whole login flow against the mock issuer, as a Google provider, and the ways the
+// callback gets rejected.
+use async_session::SessionStore;
+use axum::http::{header, StatusCode};
+use axum_oauth2_auth::{
+    app_state_init,
+    backend::Backend,
+    create_router,
+    flash::FlashLevel,
+    provider::{ProviderConfig, ProviderKind},
+    roles::RoleGrants,
+    AppState, COOKIE_NAME, CSRF_COOKIE_NAME,
+};
+use chrono::{Duration, Utc};
+use common::{
+    body_string, cookie, cookie_keys, flash, get, location, logout, mock_config, start_login,
+    test_state, MockIssuer, TEST_EMAIL, USER_AGENT,
+};
+
+fn google_config(issuer: &str) -> ProviderConfig {
+    ProviderConfig {
+        kind: ProviderKind::Google,
+        ..mock_config("google", issuer)
+    }
+}
+
+async fn google_app(mock: &MockIssuer) -> (AppState, axum::Router) {
+    let state = test_state(vec![google_config(&mock.issuer)], RoleGrants::default()).await;
+    (state.clone(), create_router(state))
+}
+
+// Sends the callback like the browser would, with `headers` replacing the defaults.
+async fn callback(
+    app: &axum::Router,
+    mock: &MockIssuer,
+    path: &str,
+    csrf_cookie: &str,
+    headers: &[(header::HeaderName, &str)],
+) -> axum::response::Response {
+    let referer = format!("{}/", mock.issuer);
+    let mut all = vec![
+        (header::USER_AGENT, USER_AGENT),
+        (header::COOKIE, csrf_cookie),
+        (header::REFERER, referer.as_str()),
+    ];
+    for (name, value) in headers {
+        all.retain(|(n, _)| n != name);
+        all.push((name.clone(), value));
+    }
+    get(app, path, &all).await
+}
+
+// Back to the start page without a session, and the flash message there says why.
+async fn assert_rejected(response: axum::response::Response, reason: &str) {
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(location(&response), "/");
+    assert!(!response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .any(|v| v.to_str().unwrap().starts_with(COOKIE_NAME)));
+    let flash = flash(&response).expect("no flash message");
+    assert_eq!(flash.level, FlashLevel::Error);
+    assert!(
+        flash.message.contains(reason),
+        "{} does not contain {}",
+        flash.message,
+        reason
+    );
+}
+
+#[tokio::test]
+async fn test_google_login_protected_logout() {
+    let mock = MockIssuer::start().await;
+    let (_, app) = google_app(&mock).await;
+
+    // Not logged in: back to the start page.
+    let response = get(&app, "/protected", &[]).await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(location(&response), "/");
+
+    let response = get(&app, "/auth/google", &[(header::USER_AGENT, USER_AGENT)]).await;
+    let auth_url = location(&response);
+    assert!(auth_url.contains("prompt=consent"));
+    assert!(auth_url.contains("access_type=online"));
+
+    let (csrf_cookie, path) = start_login(&app, "google", &mock, None).await;
+    let response = callback(&app, &mock, &path, &csrf_cookie, &[]).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(location(&response), "/");
+    assert!(cookie(&response, CSRF_COOKIE_NAME).ends_with('='));
+    let session_cookie = cookie(&response, COOKIE_NAME);
+
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_string(response).await;
+    assert!(body.contains(TEST_EMAIL));
+    assert!(body.contains("Logged in with google"));
+
+    let response = logout(&app, &session_cookie, &[]).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(location(&response), "/");
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+
+    // The CSRF session is single use.
+    let response = callback(&app, &mock, &path, &csrf_cookie, &[]).await;
+    assert_rejected(response, "CSRF Session not found").await;
+}
+
+#[tokio::test]
+async fn test_csrf_mismatch() {
+    let mock = MockIssuer::start().await;
+    let (_, app) = google_app(&mock).await;
+
+    let (csrf_cookie, path) = start_login(&app, "google", &mock, None).await;
+    let url = url::Url::parse(&format!("http://localhost{}", path)).unwrap();
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(name, value)| match name.as_ref() {
+            "state" => (name.to_string(), "forged".to_string()),
+            _ => (name.to_string(), value.to_string()),
+        })
+        .collect();
+    let forged = format!(
+        "{}?{}",
+        url.path(),
+        url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(query)
+            .finish()
+    );
+    let response = callback(&app, &mock, &forged, &csrf_cookie, &[]).await;
+    assert_rejected(response, "CSRF token mismatch").await;
+
+    // Nor does another login attempt's CSRF cookie fit.
+    let (other_csrf_cookie, _) = start_login(&app, "google", &mock, None).await;
+    let response = callback(&app, &mock, &path, &other_csrf_cookie, &[]).await;
+    assert_rejected(response, "CSRF token mismatch").await;
+
+    // No CSRF cookie at all.
+    let response = callback(&app, &mock, &path, "other=1", &[]).await;
+    assert_rejected(response, "No session cookie found").await;
+}
+
+#[tokio::test]
+async fn test_expired_csrf() {
+    let mock = MockIssuer::start().await;
+    let (state, app) = google_app(&mock).await;
+
+    let (csrf_cookie, path) = start_login(&app, "google", &mock, None).await;
+
+    // Age the login attempt past its deadline, leaving the stored session itself alive.
+    let (name, value) = csrf_cookie.split_once('=').unwrap();
+    let csrf_id = cookie_keys().open(name, value).unwrap();
+    let mut session = state.store.load_session(csrf_id).await.unwrap().unwrap();
+    let mut csrf_data: serde_json::Value = session.get("csrf_data").unwrap();
+    csrf_data["expires_at"] = serde_json::json!(Utc::now() - Duration::seconds(1));
+    session.insert("csrf_data", csrf_data).unwrap();
+    state.store.store_session(session).await.unwrap();
+
+    let response = callback(&app, &mock, &path, &csrf_cookie, &[]).await;
+    assert_rejected(response, "CSRF token expired").await;
+}
+
+#[tokio::test]
+async fn test_user_agent_mismatch() {
+    let mock = MockIssuer::start().await;
+    let (_, app) = google_app(&mock).await;
+
+    let (csrf_cookie, path) = start_login(&app, "google", &mock, None).await;
+    let response = callback(
+        &app,
+        &mock,
+        &path,
+        &csrf_cookie,
+        &[(header::USER_AGENT, "someone else")],
+    )
+    .await;
+    assert_rejected(response, "Funny thing happend").await;
+}
+
+#[tokio::test]
+async fn test_origin_rejection() {
+    let mock = MockIssuer::start().await;
+    let (_, app) = google_app(&mock).await;
+
+    let (csrf_cookie, path) = start_login(&app, "google", &mock, None).await;
+    let response = callback(
+        &app,
+        &mock,
+        &path,
+        &csrf_cookie,
+        &[(header::REFERER, "https://evil.example/")],
+    )
+    .await;
+    assert_rejected(response, "Invalid origin").await;
+
+    let response = callback(
+        &app,
+        &mock,
+        &path,
+        &csrf_cookie,
+        &[(header::ORIGIN, "https://evil.example")],
+    )
+    .await;
+    assert_rejected(response, "Invalid origin").await;
+}
+
+#[tokio::test]
+async fn test_app_state_init_reports_missing_config() {
+    std::env::remove_var("ORIGIN");
+    let error = match app_state_init(Backend::http).await {
+        Ok(_) => panic!("app_state_init succeeded without ORIGIN"),
+        Err(e) => e,
+    };
+    assert!(error.to_string().contains("Missing ORIGIN!"));
+}
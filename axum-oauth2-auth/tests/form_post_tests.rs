@@ -0,0 +1,195 @@
+mod common;
+
+use axum::http::{header, StatusCode};
+use axum_oauth2_auth::{
+    provider::{ProviderConfig, ProviderKind},
+    ResponseMode, ResponseType, COOKIE_NAME,
+};
+use common::{
+    body_string, cookie, flash, get, mock_config, post_form, start_form_post_login, test_app,
+    MockIssuer, TEST_EMAIL, USER_AGENT,
+};
+
+fn form_post_config(issuer: &str, response_type: ResponseType) -> ProviderConfig {
+    ProviderConfig {
+        response_type,
+        response_mode: ResponseMode::FormPost,
+        ..mock_config("mock", issuer)
+    }
+}
+
+// The Cookie header value for a Set-Cookie header value.
+fn cookie_pair(set_cookie: &str) -> &str {
+    set_cookie.split(';').next().unwrap()
+}
+
+#[tokio::test]
+async fn test_form_post_code_flow() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![form_post_config(&mock.issuer, ResponseType::Code)]).await;
+
+    let (csrf_cookie, form) = start_form_post_login(&app, "mock").await;
+    // Otherwise the browser wouldn't send it along with the provider's cross-site POST.
+    assert!(csrf_cookie.contains("SameSite=None"));
+    assert!(csrf_cookie.contains("Secure"));
+    assert!(!form.iter().any(|(name, _)| name == "id_token"));
+
+    let response = post_form(
+        &app,
+        "/auth/authorized",
+        &form,
+        &[
+            (header::USER_AGENT, USER_AGENT),
+            (header::COOKIE, cookie_pair(&csrf_cookie)),
+            (header::ORIGIN, &mock.issuer),
+        ],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let session_cookie = cookie(&response, COOKIE_NAME);
+
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(body_string(response).await.contains(TEST_EMAIL));
+}
+
+#[tokio::test]
+async fn test_hybrid_code_id_token_flow() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![form_post_config(
+        &mock.issuer,
+        ResponseType::CodeIdToken,
+    )])
+    .await;
+
+    let (csrf_cookie, form) = start_form_post_login(&app, "mock").await;
+    assert!(form.iter().any(|(name, _)| name == "id_token"));
+    assert!(form.iter().any(|(name, _)| name == "code"));
+
+    let response = post_form(
+        &app,
+        "/auth/authorized",
+        &form,
+        &[
+            (header::USER_AGENT, USER_AGENT),
+            (header::COOKIE, cookie_pair(&csrf_cookie)),
+            (header::ORIGIN, &mock.issuer),
+        ],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let session_cookie = cookie(&response, COOKIE_NAME);
+
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    let body = body_string(response).await;
+    assert!(body.contains(TEST_EMAIL));
+    assert!(body.contains("Access token expires at"));
+}
+
+#[tokio::test]
+async fn test_implicit_id_token_flow() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![form_post_config(&mock.issuer, ResponseType::IdToken)]).await;
+
+    let (csrf_cookie, form) = start_form_post_login(&app, "mock").await;
+    assert!(!form.iter().any(|(name, _)| name == "code"));
+
+    let response = post_form(
+        &app,
+        "/auth/authorized",
+        &form,
+        &[
+            (header::USER_AGENT, USER_AGENT),
+            (header::COOKIE, cookie_pair(&csrf_cookie)),
+            (header::ORIGIN, &mock.issuer),
+        ],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let session_cookie = cookie(&response, COOKIE_NAME);
+
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    let body = body_string(response).await;
+    assert!(body.contains(TEST_EMAIL));
+    assert!(body.contains("No access token"));
+}
+
+#[tokio::test]
+async fn test_hybrid_rejects_swapped_code() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![form_post_config(
+        &mock.issuer,
+        ResponseType::CodeIdToken,
+    )])
+    .await;
+
+    let (csrf_cookie, mut form) = start_form_post_login(&app, "mock").await;
+    // The c_hash in the ID token no longer matches.
+    for (name, value) in form.iter_mut() {
+        if name == "code" {
+            *value = "code-from-elsewhere".to_string();
+        }
+    }
+
+    let response = post_form(
+        &app,
+        "/auth/authorized",
+        &form,
+        &[
+            (header::USER_AGENT, USER_AGENT),
+            (header::COOKIE, cookie_pair(&csrf_cookie)),
+            (header::ORIGIN, &mock.issuer),
+        ],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert!(flash(&response).unwrap().message.contains("c_hash"));
+}
+
+#[tokio::test]
+async fn test_form_post_rejects_query_callback() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![form_post_config(&mock.issuer, ResponseType::Code)]).await;
+
+    let (csrf_cookie, form) = start_form_post_login(&app, "mock").await;
+    let query = form
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let response = get(
+        &app,
+        &format!("/auth/authorized?{}", query),
+        &[
+            (header::USER_AGENT, USER_AGENT),
+            (header::COOKIE, cookie_pair(&csrf_cookie)),
+            (header::REFERER, &mock.issuer),
+        ],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert!(flash(&response).unwrap().message.contains("response mode"));
+}
+
+#[test]
+fn test_config_rejects_unsafe_response_settings() {
+    std::env::set_var("OIDC_FORMPOSTTEST_ISSUER", "https://issuer.example.com");
+    std::env::set_var("OIDC_FORMPOSTTEST_CLIENT_ID", "client");
+
+    std::env::set_var("OIDC_FORMPOSTTEST_RESPONSE_TYPE", "id_token code");
+    std::env::set_var("OIDC_FORMPOSTTEST_RESPONSE_MODE", "query");
+    assert!(ProviderConfig::from_env("formposttest", "https://localhost").is_err());
+
+    std::env::set_var("OIDC_FORMPOSTTEST_RESPONSE_MODE", "fragment");
+    assert!(ProviderConfig::from_env("formposttest", "https://localhost").is_err());
+
+    std::env::set_var("OIDC_FORMPOSTTEST_RESPONSE_TYPE", "code token");
+    std::env::set_var("OIDC_FORMPOSTTEST_RESPONSE_MODE", "form_post");
+    assert!(ProviderConfig::from_env("formposttest", "https://localhost").is_err());
+
+    std::env::set_var("OIDC_FORMPOSTTEST_RESPONSE_TYPE", "id_token code");
+    let config = ProviderConfig::from_env("formposttest", "https://localhost").unwrap();
+    assert_eq!(config.kind, ProviderKind::Generic);
+    assert_eq!(config.response_type, ResponseType::CodeIdToken);
+}
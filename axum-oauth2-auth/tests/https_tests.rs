@@ -0,0 +1,128 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    routing::get,
+    Router,
+};
+use axum_oauth2_auth::https::{self, ServerConfig};
+use common::location;
+use tower::ServiceExt; // for `app.oneshot()`
+
+async fn send(
+    app: &Router,
+    method: &str,
+    uri: &str,
+    host: Option<&str>,
+) -> axum::response::Response {
+    let mut request = Request::builder().method(method).uri(uri);
+    if let Some(host) = host {
+        request = request.header(header::HOST, host);
+    }
+    app.clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_http_redirects_to_https() {
+    let app = https::redirect_router(3443);
+
+    let response = send(&app, "GET", "/protected?a=1&b=2", Some("localhost:3001")).await;
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(
+        location(&response),
+        "https://localhost:3443/protected?a=1&b=2"
+    );
+
+    // Every path and method, nothing is served over HTTP.
+    let response = send(&app, "POST", "/auth/authorized", Some("example.com")).await;
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(
+        location(&response),
+        "https://example.com:3443/auth/authorized"
+    );
+
+    let response = send(&app, "GET", "/", Some("[::1]:3001")).await;
+    assert_eq!(location(&response), "https://[::1]:3443/");
+
+    let response = send(&app, "GET", "/", None).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // The default port is left out.
+    let app = https::redirect_router(443);
+    let response = send(&app, "GET", "/me", Some("example.com:80")).await;
+    assert_eq!(location(&response), "https://example.com/me");
+}
+
+#[tokio::test]
+async fn test_hsts_header() {
+    let router = Router::new().route("/", get(|| async { "hello" }));
+    let app = https::with_hsts(router.clone(), Some(600));
+
+    let response = send(&app, "GET", "/", Some("localhost")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::STRICT_TRANSPORT_SECURITY],
+        "max-age=600"
+    );
+    // Errors too.
+    let response = send(&app, "GET", "/missing", Some("localhost")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        response.headers()[header::STRICT_TRANSPORT_SECURITY],
+        "max-age=600"
+    );
+
+    // None in dev mode, by default.
+    let app = https::with_hsts(router, None);
+    let response = send(&app, "GET", "/", Some("localhost")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response
+        .headers()
+        .get(header::STRICT_TRANSPORT_SECURITY)
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_missing_certificate_is_an_error() {
+    let config = ServerConfig {
+        http_port: 0,
+        https_port: 0,
+        cert_path: "does/not/exist/cert.pem".into(),
+        key_path: "does/not/exist/key.pem".into(),
+        hsts_max_age: None,
+        dev_certs_dir: None,
+    };
+    let error = https::load_tls_config(&config).await.unwrap_err();
+    assert!(format!("{:#}", error).contains("does/not/exist/cert.pem"));
+}
+
+// The only test here that touches the environment.
+#[test]
+fn test_dev_mode_is_opt_in() {
+    for name in ["TLS_CERT_PATH", "TLS_KEY_PATH", "DEV_CERTS", "HSTS_MAX_AGE"] {
+        std::env::remove_var(name);
+    }
+    let error = ServerConfig::from_env().unwrap_err();
+    assert!(error.to_string().contains("No TLS certificate"));
+
+    std::env::set_var("DEV_CERTS", "true");
+    let config = ServerConfig::from_env().unwrap();
+    assert!(config.dev_certs_dir.is_some());
+    assert_eq!(config.hsts_max_age, None);
+
+    std::env::set_var("TLS_CERT_PATH", "cert.pem");
+    std::env::set_var("TLS_KEY_PATH", "key.pem");
+    assert!(ServerConfig::from_env().is_err());
+
+    std::env::remove_var("DEV_CERTS");
+    let config = ServerConfig::from_env().unwrap();
+    assert!(config.dev_certs_dir.is_none());
+    assert_eq!(config.hsts_max_age, Some(300));
+    for name in ["TLS_CERT_PATH", "TLS_KEY_PATH"] {
+        std::env::remove_var(name);
+    }
+}
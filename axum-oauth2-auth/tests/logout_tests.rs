@@ -0,0 +1,237 @@
+mod common;
+
+// Logging out at the provider too: token revocation, RP-initiated and back-channel logout.
+use axum::http::{header, StatusCode};
+use axum_oauth2_auth::{
+    audit::{AuthEventFilter, AuthEventKind},
+    create_router,
+    logout::LogoutConfig,
+    provider::ProviderConfig,
+    roles::RoleGrants,
+    AppState,
+};
+use common::{get, location, login, logout, mock_config, post_form, test_state, MockIssuer};
+use serde_json::json;
+use std::collections::HashMap;
+use url::Url;
+
+async fn logout_state(mock: &MockIssuer, logout: LogoutConfig) -> AppState {
+    let config = ProviderConfig {
+        offline: true,
+        logout,
+        ..mock_config("mock", &mock.issuer)
+    };
+    test_state(vec![config], RoleGrants::default()).await
+}
+
+fn backchannel() -> LogoutConfig {
+    LogoutConfig {
+        backchannel: true,
+        ..LogoutConfig::default()
+    }
+}
+
+async fn is_logged_in(app: &axum::Router, session_cookie: &str) -> bool {
+    let response = get(app, "/protected", &[(header::COOKIE, session_cookie)]).await;
+    response.status() == StatusCode::OK
+}
+
+async fn post_logout_token(app: &axum::Router, provider: &str, token: String) -> StatusCode {
+    let response = post_form(
+        app,
+        &format!("/auth/{}/backchannel_logout", provider),
+        &[("logout_token".to_string(), token)],
+        &[],
+    )
+    .await;
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+    response.status()
+}
+
+#[tokio::test]
+async fn test_logout_revokes_tokens() {
+    let mock = MockIssuer::start().await;
+    let logout_config = LogoutConfig {
+        revoke_tokens: true,
+        ..LogoutConfig::default()
+    };
+    let app = create_router(logout_state(&mock, logout_config).await);
+
+    let session_cookie = login(&app, "mock", &mock).await;
+    let response = logout(&app, &session_cookie, &[]).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(location(&response), "/");
+
+    let revocations = mock.revocations();
+    assert_eq!(revocations.len(), 2);
+    assert_eq!(
+        revocations[0],
+        (
+            "mock-refresh-token".to_string(),
+            "refresh_token".to_string()
+        )
+    );
+    // Refreshed on the way in, so whatever the session held last.
+    assert!(revocations[1].0.starts_with("mock-access-token"));
+    assert_eq!(revocations[1].1, "access_token");
+}
+
+#[tokio::test]
+async fn test_tokens_are_kept_unless_configured() {
+    let mock = MockIssuer::start().await;
+    let app = create_router(logout_state(&mock, LogoutConfig::default()).await);
+
+    let session_cookie = login(&app, "mock", &mock).await;
+    let response = logout(&app, &session_cookie, &[]).await;
+    assert_eq!(location(&response), "/");
+    assert!(mock.revocations().is_empty());
+}
+
+#[tokio::test]
+async fn test_rp_initiated_logout() {
+    let mock = MockIssuer::start().await;
+    let logout_config = LogoutConfig {
+        post_logout_redirect_uri: Some("https://localhost/".to_string()),
+        ..LogoutConfig::default()
+    };
+    let app = create_router(logout_state(&mock, logout_config).await);
+
+    let session_cookie = login(&app, "mock", &mock).await;
+    let response = logout(&app, &session_cookie, &[]).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert!(!is_logged_in(&app, &session_cookie).await);
+
+    let url = Url::parse(&location(&response)).unwrap();
+    assert_eq!(
+        url.as_str().split('?').next(),
+        Some(&*format!("{}/logout", mock.issuer))
+    );
+    let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+    assert_eq!(params["client_id"], "test-client");
+    assert_eq!(params["post_logout_redirect_uri"], "https://localhost/");
+    // The ID token from the login.
+    assert_eq!(params["id_token_hint"].split('.').count(), 3);
+
+    // Nothing to end at the provider without a session here.
+    let form = [("csrf_token".to_string(), String::new())];
+    let response = post_form(&app, "/logout", &form, &[]).await;
+    assert_eq!(location(&response), "/");
+}
+
+#[tokio::test]
+async fn test_backchannel_logout_by_sub() {
+    let mock = MockIssuer::start().await;
+    let state = logout_state(&mock, backchannel()).await;
+    let app = create_router(state.clone());
+
+    let first = login(&app, "mock", &mock).await;
+    let second = login(&app, "mock", &mock).await;
+    let token = mock.logout_token(Some("alice"), None, json!({}));
+    assert_eq!(post_logout_token(&app, "mock", token).await, StatusCode::OK);
+    assert!(!is_logged_in(&app, &first).await);
+    assert!(!is_logged_in(&app, &second).await);
+
+    let filter = AuthEventFilter {
+        kind: Some(AuthEventKind::SessionRevoked),
+        ..AuthEventFilter::default()
+    };
+    let events = state.audit.recent(&filter).await.unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].reason.as_deref(), Some("back-channel logout"));
+}
+
+#[tokio::test]
+async fn test_backchannel_logout_by_sid() {
+    let mock = MockIssuer::start().await;
+    let app = create_router(logout_state(&mock, backchannel()).await);
+
+    let first = login(&app, "mock", &mock).await;
+    let second = login(&app, "mock", &mock).await;
+    let token = mock.logout_token(None, Some(&mock.sid(0)), json!({}));
+    assert_eq!(post_logout_token(&app, "mock", token).await, StatusCode::OK);
+    assert!(!is_logged_in(&app, &first).await);
+    assert!(is_logged_in(&app, &second).await);
+
+    // Both given, both must match.
+    let token = mock.logout_token(Some("bob"), Some(&mock.sid(1)), json!({}));
+    assert_eq!(post_logout_token(&app, "mock", token).await, StatusCode::OK);
+    assert!(is_logged_in(&app, &second).await);
+}
+
+#[tokio::test]
+async fn test_backchannel_logout_spares_later_logins() {
+    let mock = MockIssuer::start().await;
+    let app = create_router(logout_state(&mock, backchannel()).await);
+
+    let session_cookie = login(&app, "mock", &mock).await;
+    let iat = chrono::Utc::now().timestamp() - 120;
+    let token = mock.logout_token(Some("alice"), None, json!({ "iat": iat }));
+    assert_eq!(post_logout_token(&app, "mock", token).await, StatusCode::OK);
+    assert!(is_logged_in(&app, &session_cookie).await);
+}
+
+#[tokio::test]
+async fn test_backchannel_logout_rejects_invalid_tokens() {
+    let mock = MockIssuer::start().await;
+    let config = ProviderConfig {
+        logout: backchannel(),
+        ..mock_config("mock", &mock.issuer)
+    };
+    let disabled = mock_config("disabled", &mock.issuer);
+    let app = create_router(test_state(vec![config, disabled], RoleGrants::default()).await);
+    let session_cookie = login(&app, "mock", &mock).await;
+
+    let now = chrono::Utc::now().timestamp();
+    let invalid = [
+        json!({ "nonce": "n" }),
+        json!({ "events": {} }),
+        json!({ "aud": "other-client" }),
+        json!({ "iss": "https://other.example" }),
+        json!({ "iat": now - 3600 }),
+        json!({ "iat": now + 3600 }),
+        json!({ "exp": now - 3600 }),
+    ];
+    for claims in invalid {
+        let token = mock.logout_token(Some("alice"), None, claims.clone());
+        let status = post_logout_token(&app, "mock", token).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", claims);
+    }
+    let token = mock.logout_token(None, None, json!({}));
+    assert_eq!(
+        post_logout_token(&app, "mock", token).await,
+        StatusCode::BAD_REQUEST
+    );
+    let token = mock.logout_token(Some("alice"), None, json!({}));
+    assert_eq!(
+        post_logout_token(&app, "disabled", token).await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        post_logout_token(&app, "mock", "not a jwt".to_string()).await,
+        StatusCode::BAD_REQUEST
+    );
+
+    assert!(is_logged_in(&app, &session_cookie).await);
+}
+
+#[tokio::test]
+async fn test_unknown_kid_refetches_jwks_at_most_once_a_minute() {
+    let mock = MockIssuer::start().await;
+    let app = create_router(logout_state(&mock, backchannel()).await);
+    let session_cookie = login(&app, "mock", &mock).await;
+    assert_eq!(mock.jwks_fetches(), 1);
+
+    // Unknown kids are rejected without asking the provider again right after a fetch.
+    for _ in 0..3 {
+        let token = mock.logout_token_with_kid("rotated", Some("alice"), None, json!({}));
+        let status = post_logout_token(&app, "mock", token).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+    assert_eq!(mock.jwks_fetches(), 1);
+
+    // Known keys are still served from the cache.
+    let token = mock.logout_token(Some("alice"), None, json!({}));
+    assert_eq!(post_logout_token(&app, "mock", token).await, StatusCode::OK);
+    assert_eq!(mock.jwks_fetches(), 1);
+    assert!(!is_logged_in(&app, &session_cookie).await);
+}
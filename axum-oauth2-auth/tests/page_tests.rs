@@ -0,0 +1,189 @@
+mod common;
+
+// The HTML pages: the shared layout, the profile page, flash messages and the POST forms.
+use axum::http::{header, StatusCode};
+use axum_oauth2_auth::{
+    flash::{FlashLevel, FLASH_COOKIE_NAME},
+    roles::RoleGrants,
+};
+use common::{
+    body_string, cookie, flash, form_token, get, location, login, logout, mock_config, post_form,
+    post_session_form, session_id, test_app, test_app_with_grants, MockIssuer, TEST_EMAIL,
+};
+
+async fn is_logged_in(app: &axum::Router, session_cookie: &str) -> bool {
+    let response = get(app, "/protected", &[(header::COOKIE, session_cookie)]).await;
+    response.status() == StatusCode::OK
+}
+
+#[tokio::test]
+async fn test_layout_shows_user() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+
+    let body = body_string(get(&app, "/", &[]).await).await;
+    assert!(!body.contains("Logged in as"));
+    assert!(!body.contains(r#"action="/logout""#));
+
+    let session_cookie = login(&app, "mock", &mock).await;
+    let body = body_string(get(&app, "/", &[(header::COOKIE, &session_cookie)]).await).await;
+    assert!(body.contains("Logged in as alice"));
+    assert!(body.contains(r#"<form method="post" action="/logout""#));
+    assert!(body.contains(&form_token(&app, &session_cookie).await));
+}
+
+#[tokio::test]
+async fn test_profile() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![
+        mock_config("mock", &mock.issuer),
+        mock_config("other", &mock.issuer),
+    ])
+    .await;
+
+    let response = get(&app, "/profile", &[]).await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+
+    let session_cookie = login(&app, "mock", &mock).await;
+    let response = get(&app, "/profile", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_string(response).await;
+    assert!(body.contains(TEST_EMAIL));
+    assert!(body.contains("<td>mock</td>"));
+    assert!(body.contains(r#"<a href="/auth/other">Link your other account</a>"#));
+    assert!(!body.contains("Link your mock account"));
+}
+
+#[tokio::test]
+async fn test_logout_needs_the_form() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+    let session_cookie = login(&app, "mock", &mock).await;
+
+    // Following a link only asks.
+    let response = get(&app, "/logout", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(body_string(response)
+        .await
+        .contains("Log out of this device?"));
+    assert!(is_logged_in(&app, &session_cookie).await);
+
+    // A form from another site doesn't know the token.
+    for csrf_token in ["", "forged"] {
+        let response = post_form(
+            &app,
+            "/logout",
+            &[("csrf_token".to_string(), csrf_token.to_string())],
+            &[(header::COOKIE, &session_cookie)],
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let flash = flash(&response).unwrap();
+        assert_eq!(flash.level, FlashLevel::Error);
+        assert!(flash.message.contains("form token mismatch"));
+        assert!(is_logged_in(&app, &session_cookie).await);
+    }
+
+    let response = logout(&app, &session_cookie, &[]).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(location(&response), "/");
+    assert!(!is_logged_in(&app, &session_cookie).await);
+}
+
+#[tokio::test]
+async fn test_form_token_is_per_session() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+    let first = login(&app, "mock", &mock).await;
+    let second = login(&app, "mock", &mock).await;
+    assert_ne!(
+        form_token(&app, &first).await,
+        form_token(&app, &second).await
+    );
+
+    // The first session's token doesn't log out the second.
+    let response = post_form(
+        &app,
+        "/logout",
+        &[("csrf_token".to_string(), form_token(&app, &first).await)],
+        &[(header::COOKIE, &second)],
+    )
+    .await;
+    assert!(flash(&response).is_some());
+    assert!(is_logged_in(&app, &second).await);
+}
+
+#[tokio::test]
+async fn test_flash_is_shown_once() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+    let session_cookie = login(&app, "mock", &mock).await;
+
+    let form = [("csrf_token".to_string(), "forged".to_string())];
+    let response = post_form(&app, "/logout", &form, &[(header::COOKIE, &session_cookie)]).await;
+    let flash_cookie = cookie(&response, FLASH_COOKIE_NAME);
+
+    let cookies = format!("{}; {}", session_cookie, flash_cookie);
+    let response = get(&app, "/", &[(header::COOKIE, &cookies)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let removal = cookie(&response, FLASH_COOKIE_NAME);
+    assert_eq!(removal, format!("{}=", FLASH_COOKIE_NAME));
+    let body = body_string(response).await;
+    assert!(body.contains(r#"<p class="flash flash-error" role="alert">Logout failed: "#));
+
+    // Shown without a session too, and never without the cookie.
+    let body = body_string(get(&app, "/", &[(header::COOKIE, &flash_cookie)]).await).await;
+    assert!(body.contains("Logout failed"));
+    let body = body_string(get(&app, "/", &[(header::COOKIE, &session_cookie)]).await).await;
+    assert!(!body.contains("Logout failed"));
+
+    // Only ever as set by us.
+    let forged = format!("{}=e30", FLASH_COOKIE_NAME);
+    let body = body_string(get(&app, "/", &[(header::COOKIE, &forged)]).await).await;
+    assert!(!body.contains("class=\"flash"));
+}
+
+#[tokio::test]
+async fn test_session_forms_need_the_token() {
+    let mock = MockIssuer::start().await;
+    let app = test_app_with_grants(
+        vec![mock_config("mock", &mock.issuer)],
+        RoleGrants::parse("admin=@example.com").unwrap(),
+    )
+    .await;
+    let session_cookie = login(&app, "mock", &mock).await;
+    let other = login(&app, "mock", &mock).await;
+
+    let body =
+        body_string(get(&app, "/sessions", &[(header::COOKIE, &session_cookie)]).await).await;
+    assert_eq!(body.matches(r#"name="csrf_token""#).count(), 4);
+    let body = body_string(get(&app, "/admin", &[(header::COOKIE, &session_cookie)]).await).await;
+    assert_eq!(body.matches(r#"name="csrf_token""#).count(), 3);
+
+    let revoke = format!(
+        "/sessions/{}/revoke",
+        urlencoding::encode(&session_id(&other))
+    );
+    let admin_revoke = format!(
+        "/admin/sessions/{}/revoke",
+        urlencoding::encode(&session_id(&other))
+    );
+    for path in [revoke.as_str(), &admin_revoke, "/sessions/revoke_all"] {
+        for form in [
+            vec![],
+            vec![("csrf_token".to_string(), "forged".to_string())],
+        ] {
+            let response = post_form(&app, path, &form, &[(header::COOKIE, &session_cookie)]).await;
+            assert_eq!(response.status(), StatusCode::SEE_OTHER, "{}", path);
+            let flash = flash(&response).unwrap();
+            assert_eq!(flash.level, FlashLevel::Error);
+            assert!(flash.message.contains("form token mismatch"), "{}", path);
+            assert!(is_logged_in(&app, &session_cookie).await);
+            assert!(is_logged_in(&app, &other).await);
+        }
+    }
+
+    let response = post_session_form(&app, &revoke, &session_cookie).await;
+    assert_eq!(location(&response), "/sessions");
+    assert!(!is_logged_in(&app, &other).await);
+}
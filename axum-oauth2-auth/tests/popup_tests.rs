@@ -0,0 +1,148 @@
+mod common;
+
+use async_session::SessionStore;
+use axum::http::{header, StatusCode};
+use axum_oauth2_auth::{create_router, popup::PopupOrigins, roles::RoleGrants, COOKIE_NAME};
+use chrono::{Duration, Utc};
+use common::{
+    body_string, cookie, cookie_keys, get, location, mock_config, start_login_at, test_app,
+    test_state, MockIssuer, TEST_ORIGIN, USER_AGENT,
+};
+
+fn popup_login_path(origin: &str) -> String {
+    format!(
+        "/auth/mock?mode=popup&origin={}",
+        urlencoding::encode(origin)
+    )
+}
+
+async fn callback(
+    app: &axum::Router,
+    path: &str,
+    csrf_cookie: &str,
+    referer: &str,
+) -> axum::response::Response {
+    get(
+        app,
+        path,
+        &[
+            (header::USER_AGENT, USER_AGENT),
+            (header::COOKIE, csrf_cookie),
+            (header::REFERER, referer),
+        ],
+    )
+    .await
+}
+
+#[test]
+fn test_popup_origins() {
+    let origins = PopupOrigins::new(&["https://localhost", "https://app.example:8443/"]).unwrap();
+    assert_eq!(
+        origins.check("https://localhost:443").as_deref(),
+        Some("https://localhost")
+    );
+    assert_eq!(
+        origins.check("https://app.example:8443").as_deref(),
+        Some("https://app.example:8443")
+    );
+    assert!(origins.check("http://localhost").is_none());
+    assert!(origins.check("https://localhost.evil.example").is_none());
+    assert!(origins.check("null").is_none());
+    assert!(PopupOrigins::new(&["not an origin"]).is_err());
+}
+
+#[tokio::test]
+async fn test_popup_login_posts_result_to_opener() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+
+    let (csrf_cookie, path) =
+        start_login_at(&app, &popup_login_path(TEST_ORIGIN), &mock, None).await;
+    let response = callback(&app, &path, &csrf_cookie, &format!("{}/", mock.issuer)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let session_cookie = cookie(&response, COOKIE_NAME);
+    let body = body_string(response).await;
+    assert!(body.contains(r#"data-origin="https://localhost""#));
+    assert!(body.contains("&quot;status&quot;:&quot;success&quot;"));
+    assert!(body.contains("postMessage"));
+
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_popup_login_failure() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+
+    let (csrf_cookie, path) =
+        start_login_at(&app, &popup_login_path(TEST_ORIGIN), &mock, None).await;
+    let response = callback(&app, &path, &csrf_cookie, "https://evil.example/").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(!response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .any(|v| v.to_str().unwrap().starts_with(COOKIE_NAME)));
+    let body = body_string(response).await;
+    assert!(body.contains("&quot;status&quot;:&quot;error&quot;"));
+    assert!(body.contains("&quot;error&quot;:&quot;login_failed&quot;"));
+    // The reason stays on the server.
+    assert!(!body.contains("Invalid origin"));
+}
+
+#[tokio::test]
+async fn test_popup_login_expired_csrf() {
+    let mock = MockIssuer::start().await;
+    let state = test_state(
+        vec![mock_config("mock", &mock.issuer)],
+        RoleGrants::default(),
+    )
+    .await;
+    let app = create_router(state.clone());
+
+    let (csrf_cookie, path) =
+        start_login_at(&app, &popup_login_path(TEST_ORIGIN), &mock, None).await;
+
+    // Age the login attempt past its deadline, leaving the stored session itself alive.
+    let (name, value) = csrf_cookie.split_once('=').unwrap();
+    let csrf_id = cookie_keys().open(name, value).unwrap();
+    let mut session = state.store.load_session(csrf_id).await.unwrap().unwrap();
+    let mut csrf_data: serde_json::Value = session.get("csrf_data").unwrap();
+    csrf_data["expires_at"] = serde_json::json!(Utc::now() - Duration::seconds(1));
+    session.insert("csrf_data", csrf_data).unwrap();
+    state.store.store_session(session).await.unwrap();
+
+    // The popup reports the failure to its opener instead of redirecting itself home.
+    let response = callback(&app, &path, &csrf_cookie, &format!("{}/", mock.issuer)).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let body = body_string(response).await;
+    assert!(body.contains(r#"data-origin="https://localhost""#));
+    assert!(body.contains("&quot;error&quot;:&quot;login_failed&quot;"));
+    assert!(!body.contains("CSRF token expired"));
+}
+
+#[tokio::test]
+async fn test_login_modes() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+    let headers = [(header::USER_AGENT, USER_AGENT)];
+
+    // The opener origin must be on the list, and is required in popup mode.
+    for path in [
+        popup_login_path("https://evil.example"),
+        "/auth/mock?mode=popup".to_string(),
+        "/auth/mock?mode=iframe".to_string(),
+    ] {
+        let response = get(&app, &path, &headers).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", path);
+    }
+
+    // Redirect mode, explicitly or by default, ends on the home page.
+    for login_path in ["/auth/mock?mode=redirect", "/auth/mock"] {
+        let (csrf_cookie, path) = start_login_at(&app, login_path, &mock, None).await;
+        let response = callback(&app, &path, &csrf_cookie, &format!("{}/", mock.issuer)).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(location(&response), "/");
+    }
+}
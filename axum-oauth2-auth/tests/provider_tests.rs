@@ -0,0 +1,124 @@
+mod common;
+
+use axum::http::{header, StatusCode};
+use axum_oauth2_auth::{
+    backend::http_client,
+    provider::{ProviderMetadata, Providers},
+    CSRF_COOKIE_NAME,
+};
+use common::{
+    body_string, cookie, flash, get, location, login, mock_config, test_app, MockIssuer,
+    TEST_EMAIL, USER_AGENT,
+};
+use url::Url;
+
+#[tokio::test]
+async fn test_discovery() {
+    let mock = MockIssuer::start().await;
+    let metadata = ProviderMetadata::discover(&mock.issuer, &http_client().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(metadata.issuer, mock.issuer);
+    assert_eq!(
+        metadata.authorization_endpoint,
+        format!("{}/authorize", mock.issuer)
+    );
+    assert_eq!(metadata.jwks_uri, format!("{}/jwks", mock.issuer));
+    assert_eq!(
+        metadata.end_session_endpoint,
+        Some(format!("{}/logout", mock.issuer))
+    );
+    assert_eq!(
+        metadata.revocation_endpoint,
+        Some(format!("{}/revoke", mock.issuer))
+    );
+}
+
+#[tokio::test]
+async fn test_discovery_rejects_wrong_issuer() {
+    let mock = MockIssuer::start().await;
+    let config = mock_config("mock", &format!("{}/other", mock.issuer));
+    assert!(Providers::discover(vec![config], &http_client().unwrap())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_index_lists_providers() {
+    let first = MockIssuer::start().await;
+    let second = MockIssuer::start().await;
+    let app = test_app(vec![
+        mock_config("first", &first.issuer),
+        mock_config("second", &second.issuer),
+    ])
+    .await;
+
+    let body = body_string(get(&app, "/", &[]).await).await;
+    assert!(body.contains("openPopup('first')"));
+    assert!(body.contains("openPopup('second')"));
+}
+
+#[tokio::test]
+async fn test_unknown_provider() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+
+    let response = get(&app, "/auth/nope", &[]).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_login_with_mock_provider() {
+    let other = MockIssuer::start().await;
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![
+        mock_config("other", &other.issuer),
+        mock_config("mock", &mock.issuer),
+    ])
+    .await;
+
+    let session_cookie = login(&app, "mock", &mock).await;
+
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_string(response).await;
+    assert!(body.contains(TEST_EMAIL));
+    assert!(body.contains("Logged in with mock as alice"));
+}
+
+#[tokio::test]
+async fn test_login_rejects_other_issuer_origin() {
+    let other = MockIssuer::start().await;
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![
+        mock_config("other", &other.issuer),
+        mock_config("mock", &mock.issuer),
+    ])
+    .await;
+
+    let response = get(&app, "/auth/mock", &[(header::USER_AGENT, USER_AGENT)]).await;
+    let csrf_cookie = cookie(&response, CSRF_COOKIE_NAME);
+    let auth_url = location(&response);
+    let state = Url::parse(&auth_url)
+        .unwrap()
+        .query_pairs()
+        .find(|(k, _)| k == "state")
+        .map(|(_, v)| v.to_string())
+        .unwrap();
+
+    // The callback must come from the provider the login was started with.
+    let referer = format!("{}/", other.issuer);
+    let response = get(
+        &app,
+        &format!("/auth/authorized?code=code-0&state={}", state),
+        &[
+            (header::USER_AGENT, USER_AGENT),
+            (header::COOKIE, &csrf_cookie),
+            (header::REFERER, &referer),
+        ],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(location(&response), "/");
+    assert!(flash(&response).unwrap().message.contains("Invalid origin"));
+}
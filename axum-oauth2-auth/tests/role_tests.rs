@@ -0,0 +1,191 @@
+mod common;
+
+use axum::http::{header, StatusCode};
+use axum_oauth2_auth::{create_router, roles::RoleGrants, users::UserStore, AppState, User};
+use common::{
+    body_string, get, login, mock_config, post_form, post_session_form, session_id, test_app,
+    test_app_with_grants, test_state, MockIssuer,
+};
+
+fn user(email: &str, verified_email: bool) -> User {
+    User {
+        user_id: 1,
+        provider: "mock".to_string(),
+        family_name: None,
+        name: "Alice".to_string(),
+        picture: None,
+        email: Some(email.to_string()),
+        given_name: None,
+        id: "alice".to_string(),
+        hd: None,
+        verified_email,
+    }
+}
+
+#[test]
+fn test_role_grants() {
+    let grants =
+        RoleGrants::parse("admin=alice@example.com, auditor=@Example.org ,admin=@corp.example")
+            .unwrap();
+    assert_eq!(
+        grants.roles_for(&user("Alice@Example.com", true)),
+        ["admin"]
+    );
+    assert_eq!(
+        grants.roles_for(&user("bob@example.org", true)),
+        ["auditor"]
+    );
+    assert_eq!(
+        grants.roles_for(&user("carol@corp.example", true)),
+        ["admin"]
+    );
+    assert!(grants.roles_for(&user("bob@example.com", true)).is_empty());
+    // A domain grant must not match a lookalike suffix.
+    assert!(grants
+        .roles_for(&user("eve@notcorp.example", true))
+        .is_empty());
+    // Unverified addresses get nothing.
+    assert!(grants
+        .roles_for(&user("alice@example.com", false))
+        .is_empty());
+
+    assert!(RoleGrants::parse("admin").is_err());
+    assert!(RoleGrants::parse("")
+        .unwrap()
+        .roles_for(&user("a@b.c", true))
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_admin_requires_login_and_role() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+
+    // No redirect to the login page, unlike `/protected`.
+    let response = get(&app, "/admin", &[]).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let session_cookie = login(&app, "mock", &mock).await;
+    let response = get(&app, "/admin", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_admin_lists_and_revokes_sessions() {
+    let mock = MockIssuer::start().await;
+    let app = test_app_with_grants(
+        vec![mock_config("mock", &mock.issuer)],
+        RoleGrants::parse("admin=@example.com").unwrap(),
+    )
+    .await;
+
+    let admin_cookie = login(&app, "mock", &mock).await;
+    let other_cookie = login(&app, "mock", &mock).await;
+
+    let response = get(&app, "/admin", &[(header::COOKIE, &admin_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_string(response).await;
+    assert_eq!(body.matches("Revoke</button>").count(), 2);
+    assert!(body.contains("alice@example.com"));
+
+    // Revoke whichever listed session isn't the admin's own.
+    let admin_session_id = session_id(&admin_cookie);
+    let revoke_path = body
+        .split(r#"action=""#)
+        .skip(1)
+        .map(|rest| rest[..rest.find('"').unwrap()].to_string())
+        .filter(|path| path.starts_with("/admin/sessions/"))
+        .find(|path| !path.contains(&urlencoding::encode(&admin_session_id).to_string()))
+        .unwrap();
+    let response = post_session_form(&app, &revoke_path, &admin_cookie).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+    let response = get(&app, "/protected", &[(header::COOKIE, &other_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    let response = get(&app, "/protected", &[(header::COOKIE, &admin_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = body_string(get(&app, "/admin", &[(header::COOKIE, &admin_cookie)]).await).await;
+    assert_eq!(body.matches("Revoke</button>").count(), 1);
+}
+
+#[tokio::test]
+async fn test_revoke_requires_admin() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+    let session_cookie = login(&app, "mock", &mock).await;
+
+    let path = format!(
+        "/admin/sessions/{}/revoke",
+        urlencoding::encode(&session_id(&session_cookie))
+    );
+    let response = post_form(&app, &path, &[], &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_config_roles_follow_the_configuration() {
+    let mock = MockIssuer::start().await;
+    let state = test_state(
+        vec![mock_config("mock", &mock.issuer)],
+        RoleGrants::parse("admin=@example.com,auditor=@example.com").unwrap(),
+    )
+    .await;
+    let app = create_router(state.clone());
+    let admin_cookie = login(&app, "mock", &mock).await;
+    let response = get(&app, "/admin", &[(header::COOKIE, &admin_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(state.users.roles(1).await.unwrap(), ["admin", "auditor"]);
+
+    // Restarted without the admin grant: the next login takes the role away, from
+    // every session.
+    let restarted = AppState {
+        role_grants: RoleGrants::parse("auditor=@example.com").unwrap(),
+        ..state.clone()
+    };
+    let app = create_router(restarted);
+    let session_cookie = login(&app, "mock", &mock).await;
+    for cookie in [&session_cookie, &admin_cookie] {
+        let response = get(&app, "/admin", &[(header::COOKIE, cookie)]).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+    assert_eq!(state.users.roles(1).await.unwrap(), ["auditor"]);
+
+    // A role granted by hand stays.
+    state.users.grant_role(1, "admin").await.unwrap();
+    login(&app, "mock", &mock).await;
+    let response = get(&app, "/admin", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    state.users.revoke_role(1, "admin").await.unwrap();
+    let response = get(&app, "/admin", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_roles_from_before_sources_are_config_roles() {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::query(
+        "CREATE TABLE user_roles (
+            user_id INTEGER NOT NULL,
+            role TEXT NOT NULL,
+            PRIMARY KEY (user_id, role)
+        )",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query("INSERT INTO user_roles (user_id, role) VALUES (1, 'admin')")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let users = UserStore::from_pool(pool);
+    users.migrate().await.unwrap();
+    assert!(users.has_role(1, "admin").await.unwrap());
+    users.sync_config_roles(1, &[]).await.unwrap();
+    assert!(!users.has_role(1, "admin").await.unwrap());
+}
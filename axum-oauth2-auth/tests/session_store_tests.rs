@@ -0,0 +1,98 @@
+// The session stores behind `SESSION_STORE`.
+use async_session::{MemoryStore, Session, SessionStore};
+use axum_oauth2_auth::session_store::{spawn_cleanup_task, AppSessionStore, SqliteSessionStore};
+use std::time::Duration;
+
+async fn stores() -> Vec<AppSessionStore> {
+    vec![
+        AppSessionStore::Memory(MemoryStore::new()),
+        AppSessionStore::Sqlite(SqliteSessionStore::in_memory().await.unwrap()),
+    ]
+}
+
+fn expired_session() -> Session {
+    let mut session = Session::new();
+    session.insert("user", "alice").unwrap();
+    session.set_expiry(
+        async_session::chrono::Utc::now() - async_session::chrono::Duration::seconds(1),
+    );
+    session
+}
+
+#[tokio::test]
+async fn test_store_load_destroy() {
+    for store in stores().await {
+        let mut session = Session::new();
+        session.insert("user", "alice").unwrap();
+        let cookie = store.store_session(session).await.unwrap().unwrap();
+
+        let mut loaded = store.load_session(cookie.clone()).await.unwrap().unwrap();
+        assert_eq!(loaded.get::<String>("user").as_deref(), Some("alice"));
+
+        // Stored again under the same id, the session is updated in place.
+        loaded.insert("user", "bob").unwrap();
+        let id = loaded.id().to_string();
+        assert!(store.store_session(loaded).await.unwrap().is_none());
+        let loaded = store.load_session(cookie.clone()).await.unwrap().unwrap();
+        assert_eq!(loaded.id(), id);
+        assert_eq!(loaded.get::<String>("user").as_deref(), Some("bob"));
+
+        store.destroy_session(loaded).await.unwrap();
+        assert!(store.load_session(cookie).await.unwrap().is_none());
+    }
+}
+
+#[tokio::test]
+async fn test_unknown_and_invalid_cookies() {
+    for store in stores().await {
+        let unknown = Session::new().into_cookie_value().unwrap();
+        assert!(store.load_session(unknown).await.unwrap().is_none());
+        assert!(store
+            .load_session("not a session!".to_string())
+            .await
+            .is_err());
+    }
+}
+
+#[tokio::test]
+async fn test_clear_store() {
+    for store in stores().await {
+        let first = store.store_session(Session::new()).await.unwrap().unwrap();
+        let second = store.store_session(Session::new()).await.unwrap().unwrap();
+        store.clear_store().await.unwrap();
+        assert!(store.load_session(first).await.unwrap().is_none());
+        assert!(store.load_session(second).await.unwrap().is_none());
+    }
+}
+
+#[tokio::test]
+async fn test_expired_sessions() {
+    for store in stores().await {
+        let expired = store
+            .store_session(expired_session())
+            .await
+            .unwrap()
+            .unwrap();
+        let live = store.store_session(Session::new()).await.unwrap().unwrap();
+        assert!(store.load_session(expired).await.unwrap().is_none());
+
+        store.cleanup().await.unwrap();
+        assert!(store.load_session(live).await.unwrap().is_some());
+    }
+
+    let store = SqliteSessionStore::in_memory().await.unwrap();
+    store.store_session(expired_session()).await.unwrap();
+    store.store_session(Session::new()).await.unwrap();
+    assert_eq!(store.cleanup().await.unwrap(), 1);
+    assert_eq!(store.cleanup().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_cleanup_task_survives_zero_interval() {
+    // The only test here that reads the variable.
+    std::env::set_var("SESSION_CLEANUP_INTERVAL_SECS", "0");
+    let task = spawn_cleanup_task(AppSessionStore::Memory(MemoryStore::new()));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!task.is_finished());
+    task.abort();
+}
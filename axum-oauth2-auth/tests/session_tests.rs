@@ -0,0 +1,143 @@
+mod common;
+
+use axum::http::{header, StatusCode};
+use axum_oauth2_auth::{create_router, roles::RoleGrants, COOKIE_NAME};
+use common::{
+    body_string, cookie_keys, get, login, mock_config, post_session_form, session_id, test_app,
+    test_state, MockIssuer, USER_AGENT,
+};
+
+fn revoke_path(cookie: &str) -> String {
+    format!(
+        "/sessions/{}/revoke",
+        urlencoding::encode(&session_id(cookie))
+    )
+}
+
+#[tokio::test]
+async fn test_sessions_lists_own_devices() {
+    let mock = MockIssuer::start().await;
+    let other = MockIssuer::start().await;
+    let app = test_app(vec![
+        mock_config("mock", &mock.issuer),
+        mock_config("other", &other.issuer),
+    ])
+    .await;
+
+    let response = get(&app, "/sessions", &[]).await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+
+    let laptop = login(&app, "mock", &mock).await;
+    let _phone = login(&app, "mock", &mock).await;
+    // A different account, through another provider.
+    let _stranger = login(&app, "other", &other).await;
+
+    let response = get(&app, "/sessions", &[(header::COOKIE, &laptop)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_string(response).await;
+    assert_eq!(body.matches("Revoke</button>").count(), 2);
+    assert_eq!(body.matches("This device").count(), 1);
+    assert!(body.contains(USER_AGENT));
+    assert!(!body.contains("<td>other</td>"));
+}
+
+#[tokio::test]
+async fn test_revoke_device() {
+    let mock = MockIssuer::start().await;
+    let other = MockIssuer::start().await;
+    let app = test_app(vec![
+        mock_config("mock", &mock.issuer),
+        mock_config("other", &other.issuer),
+    ])
+    .await;
+
+    let laptop = login(&app, "mock", &mock).await;
+    let phone = login(&app, "mock", &mock).await;
+    let stranger = login(&app, "other", &other).await;
+
+    // Someone else's session is not found, and stays valid.
+    let response = post_session_form(&app, &revoke_path(&stranger), &laptop).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = get(&app, "/protected", &[(header::COOKIE, &stranger)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = post_session_form(&app, &revoke_path(&phone), &laptop).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(response.headers()[header::LOCATION], "/sessions");
+
+    let response = get(&app, "/protected", &[(header::COOKIE, &phone)]).await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    let response = get(&app, "/protected", &[(header::COOKIE, &laptop)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Revoking the current device logs it out.
+    let response = post_session_form(&app, &revoke_path(&laptop), &laptop).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(response.headers()[header::LOCATION], "/");
+    let response = get(&app, "/protected", &[(header::COOKIE, &laptop)]).await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+}
+
+#[tokio::test]
+async fn test_log_out_everywhere() {
+    let mock = MockIssuer::start().await;
+    let other = MockIssuer::start().await;
+    let app = test_app(vec![
+        mock_config("mock", &mock.issuer),
+        mock_config("other", &other.issuer),
+    ])
+    .await;
+
+    let laptop = login(&app, "mock", &mock).await;
+    let phone = login(&app, "mock", &mock).await;
+    let stranger = login(&app, "other", &other).await;
+
+    let response = post_session_form(&app, "/sessions/revoke_all", &laptop).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert!(response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .any(|v| v.to_str().unwrap().contains("Max-Age=0")));
+
+    for cookie in [&laptop, &phone] {
+        let response = get(&app, "/protected", &[(header::COOKIE, cookie)]).await;
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    }
+    let response = get(&app, "/protected", &[(header::COOKIE, &stranger)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_unindexed_session_is_rejected() {
+    let mock = MockIssuer::start().await;
+    let state = test_state(
+        vec![mock_config("mock", &mock.issuer)],
+        RoleGrants::default(),
+    )
+    .await;
+    let app = create_router(state.clone());
+    let session_cookie = login(&app, "mock", &mock).await;
+
+    // Dropping only the index entry is enough, e.g. when revoked from another instance
+    // that doesn't share the session store.
+    state
+        .users
+        .forget_session(&session_id(&session_cookie))
+        .await
+        .unwrap();
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+}
+
+#[tokio::test]
+async fn test_session_store_error_redirects() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+
+    // Properly sealed, but no session cookie the store can read.
+    let value = cookie_keys().seal(COOKIE_NAME, "not a session!").unwrap();
+    let session_cookie = format!("{}={}", COOKIE_NAME, value);
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+}
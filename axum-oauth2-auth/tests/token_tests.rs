@@ -0,0 +1,104 @@
+mod common;
+
+use axum::http::{header, StatusCode};
+use axum_oauth2_auth::{provider::ProviderConfig, tokens::TokenCipher, COOKIE_NAME};
+use common::{
+    body_string, cookie, get, login, logout, mock_config, session_id, test_app, MockIssuer,
+};
+
+fn offline_config(name: &str, issuer: &str) -> ProviderConfig {
+    ProviderConfig {
+        offline: true,
+        ..mock_config(name, issuer)
+    }
+}
+
+#[test]
+fn test_cipher_round_trip() {
+    let cipher = TokenCipher::random();
+    let sealed = cipher.encrypt("refresh-token").unwrap();
+    assert!(!sealed.contains("refresh-token"));
+    assert_eq!(cipher.decrypt(&sealed).unwrap(), "refresh-token");
+    // A fresh nonce every time.
+    assert_ne!(sealed, cipher.encrypt("refresh-token").unwrap());
+}
+
+#[test]
+fn test_cipher_rejects_tampering_and_other_keys() {
+    let cipher = TokenCipher::random();
+    let sealed = cipher.encrypt("refresh-token").unwrap();
+
+    let mut tampered = sealed.clone().into_bytes();
+    let last = tampered.len() - 3;
+    tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+    assert!(cipher
+        .decrypt(&String::from_utf8(tampered).unwrap())
+        .is_err());
+
+    assert!(TokenCipher::random().decrypt(&sealed).is_err());
+    assert!(cipher.decrypt("short").is_err());
+}
+
+#[tokio::test]
+async fn test_offline_access_refreshes_expiring_token() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![offline_config("mock", &mock.issuer)]).await;
+    let session_cookie = login(&app, "mock", &mock).await;
+    assert_eq!(mock.refreshes(), 0);
+
+    // The mock's access tokens expire within the refresh skew.
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(body_string(response)
+        .await
+        .contains("Access token expires at"));
+    assert_eq!(mock.refreshes(), 1);
+
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(mock.refreshes(), 2);
+}
+
+#[tokio::test]
+async fn test_online_access_has_no_refresh_token() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+    let session_cookie = login(&app, "mock", &mock).await;
+
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(mock.refreshes(), 0);
+}
+
+#[tokio::test]
+async fn test_activity_renews_session_cookie() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+    let session_cookie = login(&app, "mock", &mock).await;
+
+    let response = get(&app, "/protected", &[(header::COOKIE, &session_cookie)]).await;
+    // Same session, freshly sealed.
+    assert_eq!(
+        session_id(&cookie(&response, COOKIE_NAME)),
+        session_id(&session_cookie)
+    );
+    let renewed = response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .map(|v| v.to_str().unwrap().to_string())
+        .find(|v| v.starts_with(COOKIE_NAME))
+        .unwrap();
+    assert!(renewed.contains("Max-Age=600"));
+
+    // Logging out must not be undone by the renewal.
+    let response = logout(&app, &session_cookie, &[]).await;
+    let cleared: Vec<_> = response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter(|v| v.to_str().unwrap().starts_with(COOKIE_NAME))
+        .collect();
+    assert_eq!(cleared.len(), 1);
+    assert!(cleared[0].to_str().unwrap().contains("Max-Age=0"));
+}
@@ -0,0 +1,143 @@
+mod common;
+
+use axum::http::{header, StatusCode};
+use axum_oauth2_auth::{users::UserStore, User};
+use common::{body_string, get, login, login_with, mock_config, test_app, MockIssuer};
+use serde_json::Value;
+
+async fn me(app: &axum::Router, session_cookie: &str) -> Value {
+    let response = get(app, "/me", &[(header::COOKIE, session_cookie)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    serde_json::from_str(&body_string(response).await).unwrap()
+}
+
+#[tokio::test]
+async fn test_first_login_creates_account() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+    let session_cookie = login(&app, "mock", &mock).await;
+
+    let me = me(&app, &session_cookie).await;
+    assert_eq!(me["email"], "alice@example.com");
+    // The mock sends no name, picture or hd.
+    assert_eq!(me["name"], "alice");
+    assert!(me["picture"].is_null());
+    assert_eq!(me["logged_in_with"], "mock");
+    let identities = me["identities"].as_array().unwrap();
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0]["provider"], "mock");
+    assert_eq!(identities[0]["subject"], "alice");
+    assert!(identities[0]["hd"].is_null());
+}
+
+#[tokio::test]
+async fn test_returning_login_reuses_account() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+
+    let first = me(&app, &login(&app, "mock", &mock).await).await;
+    let second = me(&app, &login(&app, "mock", &mock).await).await;
+    assert_eq!(first["id"], second["id"]);
+    assert_eq!(second["identities"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_link_second_provider() {
+    let first = MockIssuer::start().await;
+    let second = MockIssuer::start().await;
+    let app = test_app(vec![
+        mock_config("first", &first.issuer),
+        mock_config("second", &second.issuer),
+    ])
+    .await;
+
+    let session_cookie = login(&app, "first", &first).await;
+    let account_id = me(&app, &session_cookie).await["id"].clone();
+
+    // Logged in with "first", log in with "second" as well.
+    let linked_cookie = login_with(&app, "second", &second, Some(&session_cookie)).await;
+    let linked = me(&app, &linked_cookie).await;
+    assert_eq!(linked["id"], account_id);
+    assert_eq!(linked["logged_in_with"], "second");
+    let providers: Vec<_> = linked["identities"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|identity| identity["provider"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(providers, ["first", "second"]);
+
+    // From now on either provider leads to the same account.
+    let fresh = me(&app, &login(&app, "second", &second).await).await;
+    assert_eq!(fresh["id"], account_id);
+}
+
+#[tokio::test]
+async fn test_same_subject_at_other_provider_is_another_account() {
+    let first = MockIssuer::start().await;
+    let second = MockIssuer::start().await;
+    let app = test_app(vec![
+        mock_config("first", &first.issuer),
+        mock_config("second", &second.issuer),
+    ])
+    .await;
+
+    // Both mocks call their user "alice" with the same email; that alone links nothing.
+    let a = me(&app, &login(&app, "first", &first).await).await;
+    let b = me(&app, &login(&app, "second", &second).await).await;
+    assert_ne!(a["id"], b["id"]);
+}
+
+#[tokio::test]
+async fn test_me_requires_login() {
+    let mock = MockIssuer::start().await;
+    let app = test_app(vec![mock_config("mock", &mock.issuer)]).await;
+
+    let response = get(&app, "/me", &[]).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+fn identity(provider: &str, subject: &str) -> User {
+    User {
+        user_id: 0,
+        provider: provider.to_string(),
+        family_name: None,
+        name: String::new(),
+        picture: None,
+        email: None,
+        given_name: None,
+        id: subject.to_string(),
+        hd: None,
+        verified_email: false,
+    }
+}
+
+#[tokio::test]
+async fn test_identity_cannot_move_between_accounts() {
+    let users = UserStore::in_memory().await.unwrap();
+    let a = users
+        .login(&identity("first", "alice"), None)
+        .await
+        .unwrap();
+    let b = users
+        .login(&identity("second", "alice"), None)
+        .await
+        .unwrap();
+    assert_ne!(a, b);
+
+    // "second" already belongs to the other account.
+    assert!(users
+        .login(&identity("second", "alice"), Some(a))
+        .await
+        .is_err());
+    assert_eq!(users.identities(a).await.unwrap().len(), 1);
+
+    // Linking something new works, and an empty name doesn't end up in the account.
+    let linked = users
+        .login(&identity("third", "bob"), Some(a))
+        .await
+        .unwrap();
+    assert_eq!(linked, a);
+    assert_eq!(users.identities(a).await.unwrap().len(), 2);
+    assert!(users.get(a).await.unwrap().unwrap().name.is_none());
+}
@@ -0,0 +1,6 @@
+DROP INDEX IF EXISTS cities_name_country_admin1_idx;
+CREATE INDEX IF NOT EXISTS cities_name_idx ON cities (name);
+
+ALTER TABLE cities DROP COLUMN population;
+ALTER TABLE cities DROP COLUMN admin1;
+ALTER TABLE cities DROP COLUMN country;
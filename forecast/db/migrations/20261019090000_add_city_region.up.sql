@@ -0,0 +1,6 @@
+ALTER TABLE cities ADD COLUMN country TEXT NOT NULL DEFAULT '';
+ALTER TABLE cities ADD COLUMN admin1 TEXT NOT NULL DEFAULT '';
+ALTER TABLE cities ADD COLUMN population BIGINT;
+
+DROP INDEX IF EXISTS cities_name_idx;
+CREATE INDEX IF NOT EXISTS cities_name_country_admin1_idx ON cities (name, country, admin1);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS lookups (
+        id SERIAL PRIMARY KEY,
+        name TEXT NOT NULL,
+        country TEXT NOT NULL DEFAULT '',
+        admin1 TEXT NOT NULL DEFAULT '',
+        cache_hit BOOLEAN NOT NULL,
+        latency_ms BIGINT NOT NULL,
+        looked_up_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS lookups_looked_up_at_idx ON lookups (looked_up_at);
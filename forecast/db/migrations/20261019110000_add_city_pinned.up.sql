@@ -0,0 +1 @@
+ALTER TABLE cities ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS cities_name_country_admin1_key;
+CREATE INDEX IF NOT EXISTS cities_name_country_admin1_idx ON cities (name, country, admin1);
@@ -0,0 +1,7 @@
+-- Keep one row per (name, country, admin1), preferring a pinned one, then the oldest.
+DELETE FROM cities c USING cities d
+WHERE c.name = d.name AND c.country = d.country AND c.admin1 = d.admin1
+  AND (d.pinned, -d.id) > (c.pinned, -c.id);
+
+DROP INDEX IF EXISTS cities_name_country_admin1_idx;
+CREATE UNIQUE INDEX IF NOT EXISTS cities_name_country_admin1_key ON cities (name, country, admin1);
@@ -1,18 +1,23 @@
 use axum::{
     async_trait,
-    extract::{FromRequestParts, Query, State},
+    extract::{FromRef, FromRequestParts, Query, State},
     http::request::Parts,
-    response::{Html, IntoResponse},
-    routing::get,
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
     Router,
 };
 
+use axum::Json;
+use chrono::{DateTime, Utc};
 use reqwest::StatusCode;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::str::from_utf8;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
 use askama_axum::Template;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sqlx::{
     Error as SqlxError,
     PgPool as Pool,
@@ -25,25 +30,144 @@ use base64::{engine::general_purpose as BASE64, Engine as _};
 use thiserror::Error;
 
 use dotenv::dotenv;
-use tower_http::trace::TraceLayer;
-use tracing_subscriber;
+use tower_http::{services::ServeDir, trace::TraceLayer};
+
+mod map;
+mod prefetch;
+mod stats;
+mod upstream;
+
+use prefetch::{PrefetchConfig, Scheduler};
+
+use upstream::{Upstream, UpstreamError};
+
+static GEOCODING_URL: &str = "https://geocoding-api.open-meteo.com/v1/search";
+static FORECAST_URL: &str = "https://api.open-meteo.com/v1/forecast";
+
+// Number of candidates requested from the geocoding API.
+static GEOCODING_COUNT: u32 = 10;
+
+#[derive(Clone)]
+struct AppState {
+    pool: Pool,
+    geocoding: Upstream,
+    forecast: Upstream,
+    forecasts: ForecastCache,
+    scheduler: Scheduler,
+}
+
+impl FromRef<AppState> for Pool {
+    fn from_ref(state: &AppState) -> Self {
+        state.pool.clone()
+    }
+}
 
 #[derive(Deserialize)]
 pub struct GeoResponse {
-    pub results: Vec<LatLong>,
+    // The API omits `results` entirely when nothing matches.
+    #[serde(default)]
+    pub results: Vec<GeoCandidate>,
+}
+
+#[derive(sqlx::FromRow, Deserialize, Serialize, Debug, Clone)]
+pub struct GeoCandidate {
+    pub name: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(default)]
+    pub country: String,
+    #[serde(default)]
+    pub admin1: String,
+    pub population: Option<i64>,
+}
+
+impl GeoCandidate {
+    fn key(&self) -> CityKey {
+        CityKey {
+            name: self.name.clone(),
+            country: self.country.clone(),
+            admin1: self.admin1.clone(),
+        }
+    }
+
+    fn lat_long(&self) -> LatLong {
+        LatLong {
+            latitude: self.latitude,
+            longitude: self.longitude,
+        }
+    }
+
+    fn label(&self) -> String {
+        self.key().label()
+    }
+}
+
+// Cached cities are keyed by (name, country, admin1) so that e.g. Portland, Oregon
+// and Portland, Maine can live side by side.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CityKey {
+    pub name: String,
+    pub country: String,
+    pub admin1: String,
+}
+
+impl CityKey {
+    // Key for a query that never resolved to a geocoding candidate.
+    fn unresolved(name: &str) -> Self {
+        CityKey {
+            name: name.to_string(),
+            country: String::new(),
+            admin1: String::new(),
+        }
+    }
+
+    fn matches(&self, candidate: &GeoCandidate) -> bool {
+        self.name.eq_ignore_ascii_case(&candidate.name)
+            && self.country == candidate.country
+            && self.admin1 == candidate.admin1
+    }
+
+    fn label(&self) -> String {
+        [&self.name, &self.admin1, &self.country]
+            .iter()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
 }
+
 #[derive(sqlx::FromRow, Deserialize, Debug, Clone)]
 pub struct LatLong {
     pub latitude: f64,
     pub longitude: f64,
 }
 
+pub struct CityLookup {
+    pub lat_long: LatLong,
+    // Whether the coordinates came from the `cities` table rather than the geocoding API.
+    pub cache_hit: bool,
+}
+
 #[derive(Deserialize)]
 pub struct WeatherQuery {
     pub city: String,
+    // Both are set once the user has picked one of several candidates.
+    pub country: Option<String>,
+    pub admin1: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+impl WeatherQuery {
+    fn key(&self) -> Option<CityKey> {
+        Some(CityKey {
+            name: self.city.clone(),
+            country: self.country.clone()?,
+            admin1: self.admin1.clone()?,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct WeatherResponse {
     pub latitude: f64,
     pub longitude: f64,
@@ -51,7 +175,7 @@ pub struct WeatherResponse {
     pub hourly: Hourly,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Hourly {
     pub time: Vec<String>,
     pub temperature_2m: Vec<f64>,
@@ -62,6 +186,8 @@ pub struct Hourly {
 pub struct WeatherDisplay {
     pub city: String,
     pub forecasts: Vec<Forecast>,
+    // Set when the forecast API failed and a previously fetched forecast is shown instead.
+    pub stale_since: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -71,9 +197,10 @@ pub struct Forecast {
 }
 
 impl WeatherDisplay {
-    fn new(city: String, weather: WeatherResponse) -> Self {
+    fn new(city: String, weather: WeatherResponse, stale_since: Option<DateTime<Utc>>) -> Self {
         WeatherDisplay {
             city,
+            stale_since: stale_since.map(|t| t.to_rfc2822()),
             forecasts: weather
                 .hourly
                 .time
@@ -93,14 +220,10 @@ impl WeatherDisplay {
 struct IndexTemplate;
 
 #[derive(Template)]
-#[template(path = "stats.html")]
-struct StatsTemplate {
-    pub cities: Vec<City>,
-}
-
-#[derive(Deserialize, Debug, sqlx::FromRow)]
-pub struct City {
-    pub name: String,
+#[template(path = "select_city.html")]
+struct SelectCityTemplate {
+    pub city: String,
+    pub candidates: Vec<GeoCandidate>,
 }
 
 #[derive(Error, Debug)]
@@ -109,80 +232,387 @@ pub enum DbError {
     DatabaseError(#[from] SqlxError),
     #[error("City not found")]
     NotFound,
+    #[error("{0}")]
+    Upstream(#[from] UpstreamError),
+}
+
+impl DbError {
+    fn into_error_page(self) -> (StatusCode, Html<String>) {
+        match self {
+            DbError::NotFound => (StatusCode::NOT_FOUND, Html("City not found".to_string())),
+            DbError::Upstream(e) => (e.status_code(), Html(e.to_string())),
+            e => (StatusCode::INTERNAL_SERVER_ERROR, Html(e.to_string())),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CachedForecast {
+    pub weather: WeatherResponse,
+    pub fetched_at: DateTime<Utc>,
+}
+
+// Last successful forecast per location, served when the forecast API is failing.
+#[derive(Clone, Default)]
+pub struct ForecastCache(Arc<RwLock<HashMap<String, CachedForecast>>>);
+
+impl ForecastCache {
+    fn key(lat_long: &LatLong) -> String {
+        format!("{:.4},{:.4}", lat_long.latitude, lat_long.longitude)
+    }
+
+    fn get(&self, lat_long: &LatLong) -> Option<CachedForecast> {
+        self.0.read().unwrap().get(&Self::key(lat_long)).cloned()
+    }
+
+    fn insert(&self, lat_long: &LatLong, weather: WeatherResponse) {
+        let cached = CachedForecast {
+            weather,
+            fetched_at: Utc::now(),
+        };
+        self.0.write().unwrap().insert(Self::key(lat_long), cached);
+    }
+
+    // Drops the forecasts that are no longer fresh, except those of `keep`, the prefetch
+    // targets, which stay for when the forecast API fails. Returns how many were dropped.
+    fn prune(&self, config: &PrefetchConfig, keep: &[LatLong], now: DateTime<Utc>) -> usize {
+        let keep: HashSet<String> = keep.iter().map(Self::key).collect();
+        let mut forecasts = self.0.write().unwrap();
+        let before = forecasts.len();
+        forecasts
+            .retain(|key, cached| keep.contains(key) || config.is_fresh(cached.fetched_at, now));
+        before - forecasts.len()
+    }
 }
 
 async fn index() -> IndexTemplate {
     IndexTemplate
 }
 
-async fn fetch_lat_long(city: &str) -> Result<LatLong, Box<dyn std::error::Error>> {
-    let endpoint = format!(
-        "https://geocoding-api.open-meteo.com/v1/search?name={}&count=1&language=en&format=json",
-        city
-    );
-    let response = reqwest::get(&endpoint).await?.json::<GeoResponse>().await?;
-    response
-        .results
-        .first()
-        .cloned()
-        .ok_or_else(|| "No results found".into())
+async fn fetch_candidates(
+    geocoding: &Upstream,
+    city: &str,
+) -> Result<Vec<GeoCandidate>, UpstreamError> {
+    let query = [
+        ("name", city.to_string()),
+        ("count", GEOCODING_COUNT.to_string()),
+        ("language", "en".to_string()),
+        ("format", "json".to_string()),
+    ];
+    let response = geocoding
+        .get_json::<GeoResponse>(GEOCODING_URL, &query)
+        .await?;
+    Ok(response.results)
+}
+
+// Cities already in the `cities` table with this name, in any letter case.
+async fn get_cached_candidates(pool: &Pool, city: &str) -> Result<Vec<GeoCandidate>, DbError> {
+    let candidates = sqlx::query_as::<_, GeoCandidate>(
+        "SELECT name, country, admin1, population, lat AS latitude, long AS longitude FROM cities WHERE LOWER(name) = LOWER($1)",
+    )
+    .bind(city)
+    .fetch_all(pool)
+    .await?;
+    Ok(candidates)
 }
 
-async fn get_lat_long(pool: &Pool, name: &str) -> Result<LatLong, DbError> {
+async fn get_cached_lat_long(pool: &Pool, key: &CityKey) -> Result<Option<LatLong>, DbError> {
     let lat_long = sqlx::query_as::<_, LatLong>(
-        // "SELECT lat::FLOAT8 AS latitude, long::FLOAT8 AS longitude FROM cities WHERE name = $1",
-        "SELECT lat AS latitude, long AS longitude FROM cities WHERE name = $1",
+        "SELECT lat AS latitude, long AS longitude FROM cities WHERE name = $1 AND country = $2 AND admin1 = $3",
     )
-    .bind(name)
+    .bind(&key.name)
+    .bind(&key.country)
+    .bind(&key.admin1)
     .fetch_optional(pool)
     .await?;
+    Ok(lat_long)
+}
 
-    if let Some(lat_long) = lat_long {
-        return Ok(lat_long);
+// Rows from before cities had a region are within this many degrees of the candidate
+// they were geocoded from.
+static LEGACY_MATCH_DEGREES: f64 = 0.01;
+
+// Gives a row from before cities had a region the region of the candidate it was geocoded
+// from, so the keyed lookup finds it. A row that has been stored again since is merged into
+// the new one. Returns whether there was such a row.
+async fn backfill_region(pool: &Pool, candidate: &GeoCandidate) -> Result<bool, DbError> {
+    let backfilled = sqlx::query(
+        "UPDATE cities SET country = $2, admin1 = $3, population = $4 \
+         WHERE id = ( \
+             SELECT id FROM cities \
+             WHERE LOWER(name) = LOWER($1) AND country = '' AND admin1 = '' \
+               AND ABS(lat - $5) < $7 AND ABS(long - $6) < $7 \
+             LIMIT 1 \
+         ) AND NOT EXISTS ( \
+             SELECT 1 FROM cities WHERE name = $1 AND country = $2 AND admin1 = $3 \
+         )",
+    )
+    .bind(&candidate.name)
+    .bind(&candidate.country)
+    .bind(&candidate.admin1)
+    .bind(candidate.population)
+    .bind(candidate.latitude)
+    .bind(candidate.longitude)
+    .bind(LEGACY_MATCH_DEGREES)
+    .execute(pool)
+    .await?;
+    if backfilled.rows_affected() > 0 {
+        return Ok(true);
     }
 
-    let lat_long = fetch_lat_long(name).await.map_err(|_| DbError::NotFound)?;
+    let merged = sqlx::query(
+        "WITH legacy AS ( \
+             DELETE FROM cities \
+             WHERE LOWER(name) = LOWER($1) AND country = '' AND admin1 = '' \
+               AND ABS(lat - $4) < $6 AND ABS(long - $5) < $6 \
+               AND EXISTS ( \
+                   SELECT 1 FROM cities WHERE name = $1 AND country = $2 AND admin1 = $3 \
+               ) \
+             RETURNING pinned \
+         ) \
+         UPDATE cities SET pinned = pinned OR EXISTS (SELECT 1 FROM legacy WHERE legacy.pinned) \
+         WHERE name = $1 AND country = $2 AND admin1 = $3 AND EXISTS (SELECT 1 FROM legacy)",
+    )
+    .bind(&candidate.name)
+    .bind(&candidate.country)
+    .bind(&candidate.admin1)
+    .bind(candidate.latitude)
+    .bind(candidate.longitude)
+    .bind(LEGACY_MATCH_DEGREES)
+    .execute(pool)
+    .await?;
+    Ok(merged.rows_affected() > 0)
+}
 
-    println!("Inserting {} into database", name);
-    sqlx::query("INSERT INTO cities (name, lat, long) VALUES ($1, $2, $3)")
-        .bind(name)
-        .bind(lat_long.latitude)
-        .bind(lat_long.longitude)
+// Stores a freshly geocoded candidate, or backfills the row it was stored as before cities
+// had a region.
+async fn store_candidate(pool: &Pool, candidate: &GeoCandidate) -> Result<CityLookup, DbError> {
+    if backfill_region(pool, candidate).await? {
+        println!("Added the region of {} in database", candidate.label());
+    } else {
+        println!("Inserting {} into database", candidate.label());
+        // A concurrent lookup of the same city may have inserted it already.
+        sqlx::query(
+            "INSERT INTO cities (name, country, admin1, population, lat, long) VALUES ($1, $2, $3, $4, $5, $6) \
+             ON CONFLICT (name, country, admin1) DO NOTHING",
+        )
+        .bind(&candidate.name)
+        .bind(&candidate.country)
+        .bind(&candidate.admin1)
+        .bind(candidate.population)
+        .bind(candidate.latitude)
+        .bind(candidate.longitude)
         .execute(pool)
         .await?;
+    }
 
-    Ok(lat_long)
+    Ok(CityLookup {
+        lat_long: candidate.lat_long(),
+        cache_hit: false,
+    })
 }
 
-async fn fetch_weather(lat_long: LatLong) -> Result<WeatherResponse, reqwest::Error> {
-    let endpoint = format!(
-        "https://api.open-meteo.com/v1/forecast?latitude={}&longitude={}&hourly=temperature_2m",
-        lat_long.latitude, lat_long.longitude
-    );
-    let response = reqwest::get(&endpoint)
+// What a plain `/weather?city=X` resolves to.
+enum CityMatch {
+    Found(CityKey, CityLookup),
+    // Several cities have this name; the user has to pick one.
+    Ambiguous(Vec<GeoCandidate>),
+    NotFound,
+}
+
+// A bare name always goes to the geocoding API: only it knows every city of that name, and
+// the cache only ever has the ones picked so far. The cache answers once the user has picked
+// one (`get_lat_long`), and stands in for the API while it fails.
+async fn find_city(state: &AppState, city: &str) -> Result<CityMatch, DbError> {
+    let mut candidates = match fetch_candidates(&state.geocoding, city).await {
+        Ok(candidates) => candidates,
+        Err(e) => {
+            let cached = get_cached_candidates(&state.pool, city).await?;
+            if cached.is_empty() {
+                return Err(e.into());
+            }
+            tracing::warn!("Using cached candidates for {}: {}", city, e);
+            return Ok(CityMatch::Ambiguous(cached));
+        }
+    };
+    if candidates.len() > 1 {
+        return Ok(CityMatch::Ambiguous(candidates));
+    }
+    let Some(candidate) = candidates.pop() else {
+        return Ok(CityMatch::NotFound);
+    };
+    let key = candidate.key();
+    let lookup = match get_cached_lat_long(&state.pool, &key).await? {
+        Some(lat_long) => CityLookup {
+            lat_long,
+            cache_hit: true,
+        },
+        None => store_candidate(&state.pool, &candidate).await?,
+    };
+    Ok(CityMatch::Found(key, lookup))
+}
+
+// Migrates the rows from before cities had a region: each name is geocoded once, and the
+// rows get the region of the candidate at their coordinates. Runs in the background at
+// startup; rows it can't place are left for the lookups to backfill.
+async fn backfill_regions(state: AppState) {
+    let names = match sqlx::query_as::<_, (String,)>(
+        "SELECT DISTINCT name FROM cities WHERE country = '' AND admin1 = ''",
+    )
+    .fetch_all(&state.pool)
+    .await
+    {
+        Ok(names) => names,
+        Err(e) => {
+            tracing::error!("Failed to find cities without a region: {}", e);
+            return;
+        }
+    };
+
+    let mut backfilled = 0;
+    for (name,) in names {
+        let candidates = match fetch_candidates(&state.geocoding, &name).await {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                tracing::warn!("Failed to geocode {} for its region: {}", name, e);
+                continue;
+            }
+        };
+        for candidate in &candidates {
+            match backfill_region(&state.pool, candidate).await {
+                Ok(true) => backfilled += 1,
+                Ok(false) => {}
+                Err(e) => tracing::warn!("Failed to backfill {}: {}", candidate.label(), e),
+            }
+        }
+    }
+    if backfilled > 0 {
+        tracing::info!("Added the region of {} cities", backfilled);
+    }
+}
+
+async fn get_lat_long(state: &AppState, key: &CityKey) -> Result<CityLookup, DbError> {
+    if let Some(lat_long) = get_cached_lat_long(&state.pool, key).await? {
+        return Ok(CityLookup {
+            lat_long,
+            cache_hit: true,
+        });
+    }
+
+    let candidate = fetch_candidates(&state.geocoding, &key.name)
         .await?
-        .json::<WeatherResponse>()
-        .await?;
-    Ok(response)
+        .into_iter()
+        .find(|candidate| key.matches(candidate))
+        .ok_or(DbError::NotFound)?;
+
+    store_candidate(&state.pool, &candidate).await
+}
+
+async fn fetch_weather(
+    forecast: &Upstream,
+    lat_long: &LatLong,
+) -> Result<WeatherResponse, UpstreamError> {
+    let query = [
+        ("latitude", lat_long.latitude.to_string()),
+        ("longitude", lat_long.longitude.to_string()),
+        ("hourly", "temperature_2m".to_string()),
+    ];
+    forecast
+        .get_json::<WeatherResponse>(FORECAST_URL, &query)
+        .await
+}
+
+// Returns the forecast, and when it was fetched if it had to come from the cache.
+async fn get_weather(
+    state: &AppState,
+    lat_long: &LatLong,
+) -> Result<(WeatherResponse, Option<DateTime<Utc>>), DbError> {
+    // Popular cities are kept fresh by the prefetch scheduler.
+    if let Some(cached) = state.forecasts.get(lat_long) {
+        if state
+            .scheduler
+            .config
+            .is_fresh(cached.fetched_at, Utc::now())
+        {
+            return Ok((cached.weather, None));
+        }
+    }
+
+    match fetch_weather(&state.forecast, lat_long).await {
+        Ok(weather) => {
+            state.forecasts.insert(lat_long, weather.clone());
+            Ok((weather, None))
+        }
+        Err(e) => match state.forecasts.get(lat_long) {
+            Some(cached) => {
+                tracing::warn!("Serving cached forecast: {}", e);
+                Ok((cached.weather, Some(cached.fetched_at)))
+            }
+            None => Err(e.into()),
+        },
+    }
 }
 
 async fn weather(
     Query(params): Query<WeatherQuery>,
-    State(pool): State<Pool>,
-) -> Result<impl IntoResponse, Html<String>> {
-    let lat_long = match get_lat_long(&pool, &params.city).await {
-        Ok(lat_long) => lat_long,
-        Err(DbError::NotFound) => return Err(Html("City not found".to_string())),
-        Err(e) => return Err(Html(e.to_string())),
-        // Err(_) => return Err(Html("Internal server error".to_string())),
+    State(state): State<AppState>,
+) -> Result<Response, (StatusCode, Html<String>)> {
+    let pool = &state.pool;
+    let started = Instant::now();
+    let (key, lookup) = match params.key() {
+        Some(key) => {
+            let lookup = get_lat_long(&state, &key).await;
+            (key, lookup)
+        }
+        None => match find_city(&state, &params.city).await {
+            Ok(CityMatch::Found(key, lookup)) => (key, Ok(lookup)),
+            Ok(CityMatch::Ambiguous(candidates)) => {
+                let select = SelectCityTemplate {
+                    city: params.city,
+                    candidates,
+                };
+                return Ok(select.into_response());
+            }
+            Ok(CityMatch::NotFound) => (CityKey::unresolved(&params.city), Err(DbError::NotFound)),
+            Err(e @ DbError::Upstream(_)) => return Err(e.into_error_page()),
+            Err(e) => (CityKey::unresolved(&params.city), Err(e)),
+        },
     };
-    let weather = fetch_weather(lat_long)
-        .await
-        .map_err(|_| Html("Internal server error".to_string()))?;
-    let weather_display = WeatherDisplay::new(params.city, weather);
+    let cache_hit = matches!(
+        lookup,
+        Ok(CityLookup {
+            cache_hit: true,
+            ..
+        })
+    );
+    let weather = match lookup {
+        Ok(lookup) => get_weather(&state, &lookup.lat_long).await,
+        Err(e) => Err(e),
+    };
+    // The selection page above is not recorded; the follow-up query for the chosen city is.
+    stats::record_lookup(pool, &key, cache_hit, started.elapsed()).await;
+
+    let (weather, stale_since) = weather.map_err(DbError::into_error_page)?;
+    let weather_display = WeatherDisplay::new(key.label(), weather, stale_since);
     Ok(weather_display.into_response())
 }
 
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub city: String,
+}
+
+// JSON counterpart of the selection page, for clients that want to pick a candidate themselves.
+async fn search(
+    Query(params): Query<SearchQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<GeoCandidate>>, StatusCode> {
+    let candidates = fetch_candidates(&state.geocoding, &params.city)
+        .await
+        .map_err(|e| e.status_code())?;
+    Ok(Json(candidates))
+}
+
 struct User;
 
 #[async_trait]
@@ -225,23 +655,12 @@ where
     }
 }
 
-async fn get_last_cities(pool: &Pool) -> Result<Vec<City>, DbError> {
-    let cities = sqlx::query_as::<_, City>("SELECT name FROM cities ORDER BY id DESC LIMIT 10")
-        .fetch_all(pool)
-        .await?;
-    Ok(cities)
-}
-
-async fn stats(_user: User, State(pool): State<Pool>) -> Result<StatsTemplate, StatusCode> {
-    let cities = get_last_cities(&pool)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(StatsTemplate { cities })
-}
-
 #[derive(Deserialize, Debug, sqlx::FromRow)]
 pub struct CityLatLong {
     pub name: String,
+    pub country: String,
+    pub admin1: String,
+    pub population: Option<i64>,
     pub lat: f64,
     pub long: f64,
 }
@@ -253,9 +672,11 @@ struct CitiesTemplate {
 }
 
 async fn get_all_cities(pool: &Pool) -> Result<Vec<CityLatLong>, DbError> {
-    let cities = sqlx::query_as::<_, CityLatLong>("SELECT name, lat, long FROM cities")
-        .fetch_all(pool)
-        .await?;
+    let cities = sqlx::query_as::<_, CityLatLong>(
+        "SELECT name, country, admin1, population, lat, long FROM cities",
+    )
+    .fetch_all(pool)
+    .await?;
     Ok(cities)
 }
 
@@ -268,23 +689,110 @@ async fn cities(State(pool): State<Pool>) -> Result<CitiesTemplate, StatusCode>
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-	tracing_subscriber::fmt().with_max_level(tracing::Level::DEBUG).init();
-	// tracing_subscriber::fmt().with_max_level(tracing::Level::WARN).init();
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .init();
+    // tracing_subscriber::fmt().with_max_level(tracing::Level::WARN).init();
 
     dotenv().ok();
     let db_connection_str = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     let pool = Pool::connect(&db_connection_str).await?;
 
+    let client = upstream::client()?;
+    let state = AppState {
+        pool,
+        geocoding: Upstream::new("geocoding", client.clone()),
+        forecast: Upstream::new("forecast", client),
+        forecasts: ForecastCache::default(),
+        scheduler: Scheduler::new(PrefetchConfig::from_env()),
+    };
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let prefetcher = prefetch::spawn(state.clone(), state.scheduler.clone(), shutdown_rx);
+    tokio::spawn(backfill_regions(state.clone()));
+
     let app = Router::new()
         .route("/", get(index))
         .route("/weather", get(weather))
-        .route("/stats", get(stats))
+        .route("/search", get(search))
+        .route("/stats", get(stats::stats))
+        .route("/stats.json", get(stats::stats_json))
         .route("/cities", get(cities))
-		.layer(TraceLayer::new_for_http())
-        .with_state(pool);
+        .route("/cities.geojson", get(map::cities_geojson))
+        .route("/map", get(map::map))
+        .route("/admin/prefetch", get(prefetch::status))
+        .route(
+            "/admin/prefetch/pins",
+            post(prefetch::pin).delete(prefetch::unpin),
+        )
+        .nest_service("/static", ServeDir::new(map::static_dir()))
+        .layer(TraceLayer::new_for_http())
+        .with_state(state);
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
     let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+
+    shutdown_tx.send(true).ok();
+    prefetcher.await?;
     Ok(())
 }
+
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to install Ctrl+C handler");
+    tracing::info!("Shutting down");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lat_long(latitude: f64, longitude: f64) -> LatLong {
+        LatLong {
+            latitude,
+            longitude,
+        }
+    }
+
+    fn weather(lat_long: &LatLong) -> WeatherResponse {
+        WeatherResponse {
+            latitude: lat_long.latitude,
+            longitude: lat_long.longitude,
+            timezone: "GMT".to_string(),
+            hourly: Hourly {
+                time: vec![],
+                temperature_2m: vec![],
+            },
+        }
+    }
+
+    #[test]
+    fn test_prune_keeps_fresh_forecasts_and_targets() {
+        let config = PrefetchConfig {
+            interval_secs: 600,
+            jitter_secs: 60,
+            concurrency: 1,
+            top_n: 10,
+        };
+        let cache = ForecastCache::default();
+        let (target, other) = (lat_long(45.5, -122.7), lat_long(43.7, -70.3));
+        cache.insert(&target, weather(&target));
+        cache.insert(&other, weather(&other));
+
+        let now = Utc::now();
+        assert_eq!(cache.prune(&config, &[], now), 0);
+        let later = now + config.max_age();
+        assert_eq!(
+            cache.prune(&config, std::slice::from_ref(&target), later),
+            1
+        );
+        assert!(cache.get(&target).is_some());
+        assert!(cache.get(&other).is_none());
+        assert_eq!(cache.prune(&config, &[], later), 1);
+        assert!(cache.get(&target).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+use askama_axum::Template;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, str::FromStr};
+
+use crate::{get_all_cities, CityLatLong, DbError, Pool};
+
+// `?bbox=min_lon,min_lat,max_lon,max_lat`, the same order as the GeoJSON `bbox` member.
+// `min_lon > max_lon` describes a box crossing the antimeridian.
+#[derive(Debug, Clone, Copy)]
+pub struct BBox {
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
+}
+
+impl FromStr for BBox {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid bbox value: {}", e))?;
+        let [min_lon, min_lat, max_lon, max_lat] = values[..] else {
+            return Err("bbox must have four values".to_string());
+        };
+        let lon_ok = |lon: f64| (-180.0..=180.0).contains(&lon);
+        let lat_ok = |lat: f64| (-90.0..=90.0).contains(&lat);
+        if !(lon_ok(min_lon) && lon_ok(max_lon) && lat_ok(min_lat) && lat_ok(max_lat)) {
+            return Err("bbox is out of range".to_string());
+        }
+        if min_lat > max_lat {
+            return Err("bbox min_lat is greater than max_lat".to_string());
+        }
+        Ok(BBox {
+            min_lon,
+            min_lat,
+            max_lon,
+            max_lat,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GeoJsonQuery {
+    pub bbox: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FeatureCollection {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub features: Vec<Feature>,
+}
+
+#[derive(Serialize)]
+pub struct Feature {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub geometry: Point,
+    pub properties: CityProperties,
+}
+
+#[derive(Serialize)]
+pub struct Point {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    // GeoJSON positions are [longitude, latitude].
+    pub coordinates: [f64; 2],
+}
+
+#[derive(Serialize)]
+pub struct CityProperties {
+    pub name: String,
+    pub country: String,
+    pub admin1: String,
+    pub population: Option<i64>,
+}
+
+impl From<CityLatLong> for Feature {
+    fn from(city: CityLatLong) -> Self {
+        Feature {
+            kind: "Feature",
+            geometry: Point {
+                kind: "Point",
+                coordinates: [city.long, city.lat],
+            },
+            properties: CityProperties {
+                name: city.name,
+                country: city.country,
+                admin1: city.admin1,
+                population: city.population,
+            },
+        }
+    }
+}
+
+async fn get_cities_in_bbox(pool: &Pool, bbox: BBox) -> Result<Vec<CityLatLong>, DbError> {
+    let lon_condition = if bbox.min_lon <= bbox.max_lon {
+        "long >= $3 AND long <= $4"
+    } else {
+        "(long >= $3 OR long <= $4)"
+    };
+    let query = format!(
+        "SELECT name, country, admin1, population, lat, long FROM cities \
+         WHERE lat >= $1 AND lat <= $2 AND {}",
+        lon_condition
+    );
+    let cities = sqlx::query_as::<_, CityLatLong>(&query)
+        .bind(bbox.min_lat)
+        .bind(bbox.max_lat)
+        .bind(bbox.min_lon)
+        .bind(bbox.max_lon)
+        .fetch_all(pool)
+        .await?;
+    Ok(cities)
+}
+
+pub async fn cities_geojson(
+    Query(params): Query<GeoJsonQuery>,
+    State(pool): State<Pool>,
+) -> Result<Json<FeatureCollection>, (StatusCode, String)> {
+    let cities = match params.bbox {
+        Some(bbox) => {
+            let bbox = bbox
+                .parse::<BBox>()
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+            get_cities_in_bbox(&pool, bbox).await
+        }
+        None => get_all_cities(&pool).await,
+    }
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(FeatureCollection {
+        kind: "FeatureCollection",
+        features: cities.into_iter().map(Feature::from).collect(),
+    }))
+}
+
+#[derive(Template)]
+#[template(path = "map.html")]
+pub struct MapTemplate;
+
+pub async fn map() -> MapTemplate {
+    MapTemplate
+}
+
+// The map script and the vendored Leaflet (`static/leaflet`), served under `/static`.
+// STATIC_DIR points at a copy when the binary runs away from the source tree.
+pub fn static_dir() -> PathBuf {
+    std::env::var("STATIC_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/static")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bbox_parses() {
+        let bbox: BBox = "-10, 35.5,20,60".parse().unwrap();
+        assert_eq!(bbox.min_lon, -10.0);
+        assert_eq!(bbox.min_lat, 35.5);
+        assert_eq!(bbox.max_lon, 20.0);
+        assert_eq!(bbox.max_lat, 60.0);
+
+        // Across the antimeridian.
+        let bbox: BBox = "170,-20,-170,0".parse().unwrap();
+        assert!(bbox.min_lon > bbox.max_lon);
+    }
+
+    #[test]
+    fn test_bbox_needs_four_numbers() {
+        for s in ["1,2,3", "1,2,3,4,5"] {
+            let err = s.parse::<BBox>().unwrap_err();
+            assert_eq!(err, "bbox must have four values", "{}", s);
+        }
+        for s in ["", "1,2,x,4", "1,2,,4"] {
+            let err = s.parse::<BBox>().unwrap_err();
+            assert!(err.starts_with("invalid bbox value"), "{}: {}", s, err);
+        }
+    }
+
+    #[test]
+    fn test_bbox_out_of_range() {
+        for s in ["-181,0,0,10", "0,0,180.5,10", "0,-91,10,10", "0,0,10,90.1"] {
+            assert_eq!(
+                s.parse::<BBox>().unwrap_err(),
+                "bbox is out of range",
+                "{}",
+                s
+            );
+        }
+        assert!("-180,-90,180,90".parse::<BBox>().is_ok());
+    }
+
+    #[test]
+    fn test_bbox_min_lat_above_max_lat() {
+        assert_eq!(
+            "0,50,10,40".parse::<BBox>().unwrap_err(),
+            "bbox min_lat is greater than max_lat"
+        );
+    }
+}
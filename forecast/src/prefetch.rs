@@ -0,0 +1,350 @@
+use axum::{extract::State, http::StatusCode, Json};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{watch, Semaphore},
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::{fetch_weather, AppState, CityKey, DbError, LatLong, Pool, User};
+
+// Popularity of a city is measured over this many days of lookups.
+static POPULARITY_WINDOW_DAYS: i64 = 7;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PrefetchConfig {
+    pub interval_secs: u64,
+    pub jitter_secs: u64,
+    pub concurrency: usize,
+    pub top_n: i64,
+}
+
+impl PrefetchConfig {
+    // PREFETCH_INTERVAL_SECS, PREFETCH_JITTER_SECS, PREFETCH_CONCURRENCY and PREFETCH_TOP_N
+    // override the defaults.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+        PrefetchConfig {
+            interval_secs: var("PREFETCH_INTERVAL_SECS", 600).max(1),
+            jitter_secs: var("PREFETCH_JITTER_SECS", 60),
+            concurrency: var("PREFETCH_CONCURRENCY", 4).max(1),
+            top_n: var("PREFETCH_TOP_N", 10),
+        }
+    }
+
+    // Prefetched forecasts are considered fresh until the next run is due.
+    pub fn max_age(&self) -> chrono::Duration {
+        chrono::Duration::seconds((self.interval_secs + self.jitter_secs) as i64)
+    }
+
+    pub fn is_fresh(&self, fetched_at: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        now - fetched_at < self.max_age()
+    }
+
+    fn next_delay(&self) -> Duration {
+        let jitter = rand::thread_rng().gen_range(0..=self.jitter_secs);
+        Duration::from_secs(self.interval_secs + jitter)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PrefetchStatus {
+    pub running: bool,
+    pub runs: u64,
+    pub last_started_at: Option<DateTime<Utc>>,
+    pub last_finished_at: Option<DateTime<Utc>>,
+    pub next_run_at: Option<DateTime<Utc>>,
+    pub last_refreshed: usize,
+    pub last_failed: usize,
+}
+
+#[derive(Clone)]
+pub struct Scheduler {
+    pub config: PrefetchConfig,
+    status: Arc<Mutex<PrefetchStatus>>,
+}
+
+impl Scheduler {
+    pub fn new(config: PrefetchConfig) -> Self {
+        Scheduler {
+            config,
+            status: Arc::new(Mutex::new(PrefetchStatus::default())),
+        }
+    }
+
+    fn status(&self) -> PrefetchStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut PrefetchStatus)) {
+        f(&mut self.status.lock().unwrap());
+    }
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct PrefetchTarget {
+    pub name: String,
+    pub country: String,
+    pub admin1: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+// Pinned cities first, then the most queried ones, without duplicates.
+async fn get_targets(pool: &Pool, top_n: i64) -> Result<Vec<PrefetchTarget>, DbError> {
+    let mut targets = get_pinned(pool).await?;
+
+    let since = Utc::now() - chrono::Duration::days(POPULARITY_WINDOW_DAYS);
+    let popular = sqlx::query_as::<_, PrefetchTarget>(
+        "SELECT c.name, c.country, c.admin1, c.lat AS latitude, c.long AS longitude \
+         FROM cities c JOIN ( \
+             SELECT name, country, admin1, COUNT(*) AS count FROM lookups \
+             WHERE looked_up_at >= $1 \
+             GROUP BY name, country, admin1 ORDER BY count DESC LIMIT $2 \
+         ) t ON c.name = t.name AND c.country = t.country AND c.admin1 = t.admin1 \
+         ORDER BY t.count DESC",
+    )
+    .bind(since)
+    .bind(top_n)
+    .fetch_all(pool)
+    .await?;
+
+    for target in popular {
+        let duplicate = targets.iter().any(|t| {
+            t.name == target.name && t.country == target.country && t.admin1 == target.admin1
+        });
+        if !duplicate {
+            targets.push(target);
+        }
+    }
+    Ok(targets)
+}
+
+async fn get_pinned(pool: &Pool) -> Result<Vec<PrefetchTarget>, DbError> {
+    let pinned = sqlx::query_as::<_, PrefetchTarget>(
+        "SELECT name, country, admin1, lat AS latitude, long AS longitude FROM cities WHERE pinned",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(pinned)
+}
+
+async fn run_once(state: &AppState, scheduler: &Scheduler) -> Result<(), DbError> {
+    scheduler.update(|s| {
+        s.running = true;
+        s.last_started_at = Some(Utc::now());
+    });
+
+    let targets = get_targets(&state.pool, scheduler.config.top_n).await?;
+    let keep: Vec<LatLong> = targets
+        .iter()
+        .map(|target| LatLong {
+            latitude: target.latitude,
+            longitude: target.longitude,
+        })
+        .collect();
+    let pruned = state.forecasts.prune(&scheduler.config, &keep, Utc::now());
+    if pruned > 0 {
+        tracing::debug!("Dropped {} stale forecasts", pruned);
+    }
+    let semaphore = Arc::new(Semaphore::new(scheduler.config.concurrency));
+    let mut tasks = JoinSet::new();
+    for target in targets {
+        let semaphore = semaphore.clone();
+        let forecast = state.forecast.clone();
+        let forecasts = state.forecasts.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await.ok()?;
+            let lat_long = LatLong {
+                latitude: target.latitude,
+                longitude: target.longitude,
+            };
+            match fetch_weather(&forecast, &lat_long).await {
+                Ok(weather) => {
+                    forecasts.insert(&lat_long, weather);
+                    Some(())
+                }
+                Err(e) => {
+                    tracing::warn!("Prefetch of {} failed: {}", target.name, e);
+                    None
+                }
+            }
+        });
+    }
+
+    let (mut refreshed, mut failed) = (0, 0);
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(Some(())) => refreshed += 1,
+            _ => failed += 1,
+        }
+    }
+    tracing::info!("Prefetched {} forecasts ({} failed)", refreshed, failed);
+
+    scheduler.update(|s| {
+        s.runs += 1;
+        s.last_finished_at = Some(Utc::now());
+        s.last_refreshed = refreshed;
+        s.last_failed = failed;
+    });
+    Ok(())
+}
+
+// Runs until `shutdown` flips to true. A run in progress is abandoned on shutdown;
+// dropping its `JoinSet` aborts the outstanding requests.
+pub fn spawn(
+    state: AppState,
+    scheduler: Scheduler,
+    mut shutdown: watch::Receiver<bool>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                result = run_once(&state, &scheduler) => {
+                    if let Err(e) = result {
+                        tracing::error!("Prefetch failed: {}", e);
+                    }
+                }
+                _ = shutdown.changed() => break,
+            }
+
+            let delay = scheduler.config.next_delay();
+            scheduler.update(|s| {
+                s.running = false;
+                s.next_run_at = chrono::Duration::from_std(delay)
+                    .ok()
+                    .map(|d| Utc::now() + d);
+            });
+
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.changed() => break,
+            }
+        }
+        scheduler.update(|s| {
+            s.running = false;
+            s.next_run_at = None;
+        });
+        tracing::info!("Prefetch scheduler stopped");
+    })
+}
+
+#[derive(Serialize)]
+pub struct SchedulerReport {
+    pub config: PrefetchConfig,
+    pub status: PrefetchStatus,
+    pub pinned: Vec<PrefetchTarget>,
+    pub breakers_open: HashMap<&'static str, bool>,
+}
+
+pub async fn status(
+    _user: User,
+    State(state): State<AppState>,
+) -> Result<Json<SchedulerReport>, StatusCode> {
+    let pinned = get_pinned(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(SchedulerReport {
+        config: state.scheduler.config.clone(),
+        status: state.scheduler.status(),
+        pinned,
+        breakers_open: HashMap::from([
+            ("geocoding", state.geocoding.is_open()),
+            ("forecast", state.forecast.is_open()),
+        ]),
+    }))
+}
+
+async fn set_pinned(pool: &Pool, key: &CityKey, pinned: bool) -> Result<(), DbError> {
+    let result = sqlx::query(
+        "UPDATE cities SET pinned = $1 WHERE name = $2 AND country = $3 AND admin1 = $4",
+    )
+    .bind(pinned)
+    .bind(&key.name)
+    .bind(&key.country)
+    .bind(&key.admin1)
+    .execute(pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(DbError::NotFound);
+    }
+    Ok(())
+}
+
+// Only cities already in the cache can be pinned, since prefetching needs their coordinates.
+pub async fn pin(
+    _user: User,
+    State(pool): State<Pool>,
+    Json(key): Json<CityKey>,
+) -> Result<StatusCode, StatusCode> {
+    match set_pinned(&pool, &key, true).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(DbError::NotFound) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+pub async fn unpin(
+    _user: User,
+    State(pool): State<Pool>,
+    Json(key): Json<CityKey>,
+) -> Result<StatusCode, StatusCode> {
+    match set_pinned(&pool, &key, false).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(DbError::NotFound) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(interval_secs: u64, jitter_secs: u64) -> PrefetchConfig {
+        PrefetchConfig {
+            interval_secs,
+            jitter_secs,
+            concurrency: 1,
+            top_n: 10,
+        }
+    }
+
+    #[test]
+    fn test_max_age_covers_the_next_run() {
+        let config = config(600, 60);
+        assert_eq!(config.max_age(), chrono::Duration::seconds(660));
+        for _ in 0..100 {
+            let delay = config.next_delay();
+            assert!(delay >= Duration::from_secs(600));
+            assert!(delay <= config.max_age().to_std().unwrap());
+        }
+        let no_jitter = PrefetchConfig {
+            jitter_secs: 0,
+            ..config
+        };
+        assert_eq!(no_jitter.next_delay(), Duration::from_secs(600));
+    }
+
+    #[test]
+    fn test_is_fresh() {
+        let config = config(600, 60);
+        let fetched_at = Utc::now();
+        let after = |secs| fetched_at + chrono::Duration::seconds(secs);
+        assert!(config.is_fresh(fetched_at, fetched_at));
+        assert!(config.is_fresh(fetched_at, after(659)));
+        assert!(!config.is_fresh(fetched_at, after(660)));
+        assert!(!config.is_fresh(fetched_at, after(3600)));
+    }
+}
@@ -0,0 +1,190 @@
+use askama_axum::Template;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{CityKey, DbError, Pool, User};
+
+static DEFAULT_WINDOW_DAYS: i64 = 7;
+static MAX_WINDOW_DAYS: i64 = 365;
+static TOP_CITIES_LIMIT: i64 = 10;
+
+// Records one `/weather` query. Failing to record must not fail the query itself.
+pub async fn record_lookup(
+    pool: &Pool,
+    key: &CityKey,
+    cache_hit: bool,
+    latency: std::time::Duration,
+) {
+    let result = sqlx::query(
+        "INSERT INTO lookups (name, country, admin1, cache_hit, latency_ms, looked_up_at) VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(&key.name)
+    .bind(&key.country)
+    .bind(&key.admin1)
+    .bind(cache_hit)
+    .bind(latency.as_millis() as i64)
+    .bind(Utc::now())
+    .execute(pool)
+    .await;
+
+    if let Err(e) = result {
+        tracing::warn!("Failed to record lookup of {}: {}", key.label(), e);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    pub days: Option<i64>,
+}
+
+impl StatsQuery {
+    fn days(&self) -> i64 {
+        self.days
+            .unwrap_or(DEFAULT_WINDOW_DAYS)
+            .clamp(1, MAX_WINDOW_DAYS)
+    }
+}
+
+#[derive(Serialize, Debug, sqlx::FromRow)]
+pub struct TopCity {
+    pub name: String,
+    pub country: String,
+    pub admin1: String,
+    pub count: i64,
+}
+
+#[derive(Serialize, Debug, sqlx::FromRow)]
+pub struct DailyLookups {
+    pub day: String,
+    pub count: i64,
+}
+
+#[derive(sqlx::FromRow)]
+struct Totals {
+    total: i64,
+    cache_hits: Option<i64>,
+    avg_latency_ms: Option<f64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Stats {
+    pub days: i64,
+    pub total: i64,
+    pub cache_hits: i64,
+    pub hit_ratio: f64,
+    pub avg_latency_ms: Option<f64>,
+    pub top_cities: Vec<TopCity>,
+    pub daily: Vec<DailyLookups>,
+}
+
+async fn get_stats(pool: &Pool, days: i64) -> Result<Stats, DbError> {
+    let since = Utc::now() - Duration::days(days);
+
+    let totals = sqlx::query_as::<_, Totals>(
+        "SELECT COUNT(*) AS total, \
+                SUM(CASE WHEN cache_hit THEN 1 ELSE 0 END) AS cache_hits, \
+                CAST(AVG(latency_ms) AS DOUBLE PRECISION) AS avg_latency_ms \
+         FROM lookups WHERE looked_up_at >= $1",
+    )
+    .bind(since)
+    .fetch_one(pool)
+    .await?;
+
+    let top_cities = sqlx::query_as::<_, TopCity>(
+        "SELECT name, country, admin1, COUNT(*) AS count FROM lookups \
+         WHERE looked_up_at >= $1 \
+         GROUP BY name, country, admin1 ORDER BY count DESC LIMIT $2",
+    )
+    .bind(since)
+    .bind(TOP_CITIES_LIMIT)
+    .fetch_all(pool)
+    .await?;
+
+    let daily = sqlx::query_as::<_, DailyLookups>(
+        "SELECT CAST(DATE(looked_up_at) AS TEXT) AS day, COUNT(*) AS count FROM lookups \
+         WHERE looked_up_at >= $1 \
+         GROUP BY day ORDER BY day",
+    )
+    .bind(since)
+    .fetch_all(pool)
+    .await?;
+
+    let cache_hits = totals.cache_hits.unwrap_or(0);
+    let hit_ratio = if totals.total > 0 {
+        cache_hits as f64 / totals.total as f64
+    } else {
+        0.0
+    };
+
+    Ok(Stats {
+        days,
+        total: totals.total,
+        cache_hits,
+        hit_ratio,
+        avg_latency_ms: totals.avg_latency_ms,
+        top_cities,
+        daily,
+    })
+}
+
+#[derive(Template)]
+#[template(path = "stats.html")]
+pub struct StatsTemplate {
+    pub stats: Stats,
+    pub windows: Vec<i64>,
+}
+
+pub async fn stats(
+    _user: User,
+    Query(params): Query<StatsQuery>,
+    State(pool): State<Pool>,
+) -> Result<StatsTemplate, StatusCode> {
+    let stats = get_stats(&pool, params.days())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatsTemplate {
+        stats,
+        windows: vec![1, 7, 30, 90],
+    })
+}
+
+pub async fn stats_json(
+    _user: User,
+    Query(params): Query<StatsQuery>,
+    State(pool): State<Pool>,
+) -> Result<Json<Stats>, StatusCode> {
+    let stats = get_stats(&pool, params.days())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn days(days: Option<i64>) -> i64 {
+        StatsQuery { days }.days()
+    }
+
+    #[test]
+    fn test_days_defaults_to_a_week() {
+        assert_eq!(days(None), DEFAULT_WINDOW_DAYS);
+    }
+
+    #[test]
+    fn test_days_is_clamped() {
+        assert_eq!(days(Some(30)), 30);
+        assert_eq!(days(Some(1)), 1);
+        assert_eq!(days(Some(0)), 1);
+        assert_eq!(days(Some(-5)), 1);
+        assert_eq!(days(Some(MAX_WINDOW_DAYS)), MAX_WINDOW_DAYS);
+        assert_eq!(days(Some(MAX_WINDOW_DAYS + 1)), MAX_WINDOW_DAYS);
+        assert_eq!(days(Some(i64::MAX)), MAX_WINDOW_DAYS);
+    }
+}
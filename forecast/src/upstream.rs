@@ -0,0 +1,298 @@
+use reqwest::{Client, StatusCode};
+use serde::de::DeserializeOwned;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+static CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+static READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+static MAX_ATTEMPTS: u32 = 3;
+static INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+
+// Consecutive failures before the breaker opens, and how long it stays open.
+static FAILURE_THRESHOLD: u32 = 5;
+static OPEN_DURATION: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum UpstreamError {
+    #[error("Upstream request timed out")]
+    Timeout,
+    #[error("Upstream returned {0}")]
+    Status(StatusCode),
+    #[error("Upstream is unavailable (circuit open)")]
+    CircuitOpen,
+    #[error("Upstream request failed: {0}")]
+    Request(reqwest::Error),
+}
+
+impl From<reqwest::Error> for UpstreamError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            UpstreamError::Timeout
+        } else if let Some(status) = e.status() {
+            UpstreamError::Status(status)
+        } else {
+            UpstreamError::Request(e)
+        }
+    }
+}
+
+impl UpstreamError {
+    // Transient failures: worth retrying, and counted against the breaker.
+    fn is_transient(&self) -> bool {
+        match self {
+            UpstreamError::Timeout => true,
+            UpstreamError::Status(status) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            UpstreamError::CircuitOpen => false,
+            UpstreamError::Request(e) => e.is_connect() || e.is_request(),
+        }
+    }
+
+    // Status to answer our own client with.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            UpstreamError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            UpstreamError::CircuitOpen => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+pub fn client() -> Result<Client, reqwest::Error> {
+    Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .read_timeout(READ_TIMEOUT)
+        .build()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // The open period is over and a single trial request is in flight. A trial that never
+    // reports back, e.g. because its request was dropped, gives way to a new one after
+    // another open period.
+    HalfOpen { since: Instant },
+}
+
+#[derive(Debug)]
+struct CircuitBreaker {
+    state: BreakerState,
+}
+
+impl CircuitBreaker {
+    fn new() -> Self {
+        CircuitBreaker {
+            state: BreakerState::Closed { failures: 0 },
+        }
+    }
+
+    fn try_acquire(&mut self) -> Result<(), UpstreamError> {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> Result<(), UpstreamError> {
+        match self.state {
+            BreakerState::Closed { .. } => Ok(()),
+            BreakerState::Open { until } if now < until => Err(UpstreamError::CircuitOpen),
+            BreakerState::HalfOpen { since } if now < since + OPEN_DURATION => {
+                Err(UpstreamError::CircuitOpen)
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => {
+                self.state = BreakerState::HalfOpen { since: now };
+                Ok(())
+            }
+        }
+    }
+
+    fn record_success(&mut self) {
+        self.state = BreakerState::Closed { failures: 0 };
+    }
+
+    fn record_failure(&mut self) {
+        self.record_failure_at(Instant::now())
+    }
+
+    fn record_failure_at(&mut self, now: Instant) {
+        self.state = match self.state {
+            BreakerState::Closed { failures } if failures + 1 < FAILURE_THRESHOLD => {
+                BreakerState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            _ => BreakerState::Open {
+                until: now + OPEN_DURATION,
+            },
+        };
+    }
+
+    fn is_open(&self) -> bool {
+        !matches!(self.state, BreakerState::Closed { .. })
+    }
+}
+
+// One upstream API: a shared client plus a breaker of its own, so an outage of
+// the forecast API does not stop geocoding and vice versa.
+#[derive(Clone)]
+pub struct Upstream {
+    name: &'static str,
+    client: Client,
+    breaker: Arc<Mutex<CircuitBreaker>>,
+}
+
+impl Upstream {
+    pub fn new(name: &'static str, client: Client) -> Self {
+        Upstream {
+            name,
+            client,
+            breaker: Arc::new(Mutex::new(CircuitBreaker::new())),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.breaker.lock().unwrap().is_open()
+    }
+
+    // GET is idempotent, so transient failures are retried with exponential backoff.
+    pub async fn get_json<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, UpstreamError> {
+        self.breaker.lock().unwrap().try_acquire()?;
+
+        let mut attempt = 1;
+        let mut backoff = INITIAL_BACKOFF;
+        let result = loop {
+            match self.try_get_json(url, query).await {
+                Err(e) if e.is_transient() && attempt < MAX_ATTEMPTS => {
+                    tracing::warn!(
+                        "{} request failed (attempt {}/{}): {}; retrying in {:?}",
+                        self.name,
+                        attempt,
+                        MAX_ATTEMPTS,
+                        e,
+                        backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                    backoff *= 2;
+                }
+                result => break result,
+            }
+        };
+
+        let mut breaker = self.breaker.lock().unwrap();
+        match &result {
+            Err(e) if e.is_transient() => {
+                breaker.record_failure();
+                if breaker.is_open() {
+                    tracing::error!("{} circuit breaker is open: {}", self.name, e);
+                }
+            }
+            _ => breaker.record_success(),
+        }
+        result
+    }
+
+    async fn try_get_json<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, UpstreamError> {
+        let response = self
+            .client
+            .get(url)
+            .query(query)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.json::<T>().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_allowed(breaker: &mut CircuitBreaker, now: Instant) -> bool {
+        breaker.try_acquire_at(now).is_ok()
+    }
+
+    fn opened(now: Instant) -> CircuitBreaker {
+        let mut breaker = CircuitBreaker::new();
+        for _ in 0..FAILURE_THRESHOLD {
+            assert!(is_allowed(&mut breaker, now));
+            breaker.record_failure_at(now);
+        }
+        breaker
+    }
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new();
+        for _ in 1..FAILURE_THRESHOLD {
+            breaker.record_failure_at(now);
+        }
+        assert!(!breaker.is_open());
+        // A success starts the count over.
+        breaker.record_success();
+        for _ in 1..FAILURE_THRESHOLD {
+            breaker.record_failure_at(now);
+        }
+        assert!(!breaker.is_open());
+
+        breaker.record_failure_at(now);
+        assert!(breaker.is_open());
+        assert!(!is_allowed(&mut breaker, now));
+        assert!(!is_allowed(&mut breaker, now + OPEN_DURATION / 2));
+    }
+
+    #[test]
+    fn test_half_open_trial_succeeds() {
+        let now = Instant::now();
+        let mut breaker = opened(now);
+
+        let later = now + OPEN_DURATION;
+        assert!(is_allowed(&mut breaker, later));
+        assert!(matches!(breaker.state, BreakerState::HalfOpen { .. }));
+        // Only one trial at a time.
+        assert!(!is_allowed(&mut breaker, later));
+
+        breaker.record_success();
+        assert!(!breaker.is_open());
+        assert!(is_allowed(&mut breaker, later));
+    }
+
+    #[test]
+    fn test_half_open_trial_fails() {
+        let now = Instant::now();
+        let mut breaker = opened(now);
+
+        let later = now + OPEN_DURATION;
+        assert!(is_allowed(&mut breaker, later));
+        breaker.record_failure_at(later);
+        assert!(matches!(breaker.state, BreakerState::Open { .. }));
+        assert!(!is_allowed(&mut breaker, later + OPEN_DURATION / 2));
+        assert!(is_allowed(&mut breaker, later + OPEN_DURATION));
+    }
+
+    #[test]
+    fn test_lost_trial_is_replaced() {
+        let now = Instant::now();
+        let mut breaker = opened(now);
+
+        // The trial's request is dropped and never reports back.
+        let later = now + OPEN_DURATION;
+        assert!(is_allowed(&mut breaker, later));
+        assert!(!is_allowed(&mut breaker, later + OPEN_DURATION / 2));
+        assert!(is_allowed(&mut breaker, later + OPEN_DURATION));
+        assert!(!is_allowed(&mut breaker, later + OPEN_DURATION));
+    }
+}
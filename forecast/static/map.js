@@ -0,0 +1,69 @@
+// The /map page: the cached cities on a Leaflet map, loaded for the visible area only
+// from /cities.geojson?bbox=min_lon,min_lat,max_lon,max_lat.
+(function () {
+	const element = document.getElementById("map");
+	if (!window.L) {
+		element.textContent = "Leaflet is missing: see static/leaflet in the Readme.";
+		return;
+	}
+
+	const map = L.map(element, { worldCopyJump: true }).setView([20, 0], 2);
+	L.tileLayer("https://tile.openstreetmap.org/{z}/{x}/{y}.png", {
+		maxZoom: 19,
+		attribution: '&copy; <a href="https://www.openstreetmap.org/copyright">OpenStreetMap</a> contributors',
+	}).addTo(map);
+
+	// Already loaded cities are kept across moves.
+	const loaded = new Set();
+	const idOf = (f) => [f.properties.name, f.properties.country, f.properties.admin1].join("|");
+	const cities = L.geoJSON(null, {
+		filter: (f) => !loaded.has(idOf(f)),
+		pointToLayer: (f, latlng) => L.circleMarker(latlng, { radius: 5 }),
+		onEachFeature: (f, layer) => {
+			loaded.add(idOf(f));
+			layer.bindPopup(() => popup(f.properties));
+		},
+	}).addTo(map);
+
+	function popup(properties) {
+		const div = document.createElement("div");
+		const name = document.createElement("strong");
+		name.textContent = properties.name;
+		div.append(name);
+		const region = [properties.admin1, properties.country].filter((v) => v).join(", ");
+		if (region) {
+			div.append(document.createElement("br"), region);
+		}
+		if (properties.population != null) {
+			div.append(document.createElement("br"), `Population ${properties.population.toLocaleString()}`);
+		}
+		return div;
+	}
+
+	// Leaflet longitudes run past ±180 on the world copies; the server wants them wrapped,
+	// with min_lon > max_lon across the antimeridian.
+	function bbox() {
+		const bounds = map.getBounds();
+		const wrap = (lon) => L.Util.wrapNum(lon, [-180, 180], true);
+		const clamp = (lat) => Math.max(-90, Math.min(90, lat));
+		let [west, east] = [bounds.getWest(), bounds.getEast()];
+		if (east - west >= 360) {
+			[west, east] = [-180, 180];
+		} else {
+			[west, east] = [wrap(west), wrap(east)];
+		}
+		return [west, clamp(bounds.getSouth()), east, clamp(bounds.getNorth())]
+			.map((v) => v.toFixed(4))
+			.join(",");
+	}
+
+	async function load() {
+		const response = await fetch(`/cities.geojson?bbox=${bbox()}`);
+		if (response.ok) {
+			cities.addData(await response.json());
+		}
+	}
+
+	map.on("moveend", load);
+	load();
+})();